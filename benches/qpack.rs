@@ -0,0 +1,121 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use qpack_rs::{Header, Qpack, StreamId};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+
+const STREAM_ID: StreamId = StreamId::new(4);
+const COOKIE: &str = "KADUSERCOOKIE=DC83629A-1466-4455-ADA8-C50384E85218; KRTBCOOKIE_218=22978-YQOWxQADPCeMBAAC&KRTB&23194-YQOWxQADPCeMBAAC&KRTB&23209-YQOWxQADPCeMBAAC&KRTB&23244-YQOWxQADPCeMBAAC; PUBMDCID=1; KRTBCOOKIE_22=14911-8779088328085767086; KRTBCOOKIE_377=6810-1ac43ec6-1bef-405c-a69f-2282e44b5b8f&KRTB&22918-1ac43ec6-1bef-405c-a69f-2282e44b5b8f&KRTB&23031-1ac43ec6-1bef-405c-a69f-2282e44b5b8f; KRTBCOOKIE_148=19421-uid:CE8832BF05E64A41AF80185CCCFA9808; chkChromeAb67Sec=2; DPSync3=1628812800%3A228_236_197_201_219_221%7C1627689600%3A174%7C1628208000%3A164; SyncRTB3=1628812800%3A54_13_55_7_165_71_3_104_22_220_21_166%7C1628208000%3A223_15_2%7C1628899200%3A35; KRTBCOOKIE_57=22776-6643794442900246332; PugT=1627625159";
+
+fn get_request_headers() -> Vec<Header> {
+    vec![
+        Header::from_str(":authority", "example.com"),
+        Header::from_str(":method", "GET"),
+        Header::from_str(":path", "/"),
+        Header::from_str(":scheme", "https"),
+        Header::from_str("accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.9"),
+        Header::from_str("accept-encoding", "gzip, deflate, br"),
+        Header::from_str("accept-language", "en-US,en;q=0.9"),
+        Header::from_str("sec-ch-ua", "\"Chromium\";v=\"92\", \" Not A;Brand\";v=\"99\", \"Google Chrome\";v=\"92\""),
+        Header::from_str("sec-ch-ua-mobile", "?0"),
+        Header::from_str("sec-fetch-dest", "document"),
+        Header::from_str("sec-fetch-mode", "navigate"),
+        Header::from_str("sec-fetch-site", "none"),
+        Header::from_str("sec-fetch-user", "?1"),
+        Header::from_str("upgrade-insecure-requests", "1"),
+        Header::from_str("user-agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/92.0.4515.107 Safari/537.36")
+    ]
+}
+
+fn get_cookie_headers() -> Vec<Header> {
+    let mut header = Header::from_str("cookie", COOKIE);
+    header.set_huffman((false, true));
+    vec![header]
+}
+
+fn encode(qpack: &Qpack, headers: &[Header]) -> Vec<u8> {
+    let mut encoded = vec![];
+    let commit_func = qpack.encode_headers(&mut encoded, headers.to_vec(), STREAM_ID).unwrap();
+    commit_func().unwrap();
+    encoded
+}
+
+fn encode_request_headers(c: &mut Criterion) {
+    let qpack = Qpack::default();
+    let headers = get_request_headers();
+    c.bench_function("encode_request_headers", |b| b.iter(|| encode(&qpack, black_box(&headers))));
+}
+
+fn decode_request_headers(c: &mut Criterion) {
+    let qpack = Qpack::default();
+    let encoded = encode(&qpack, &get_request_headers());
+    c.bench_function("decode_request_headers", |b| b.iter(|| qpack.decode_headers(black_box(&encoded), STREAM_ID).unwrap()));
+}
+
+// static-only section decoded while another thread keeps taking the dynamic table write lock.
+// decoding does not wait for it as the dynamic table is never locked
+fn decode_static_only_under_contention(c: &mut Criterion) {
+    let qpack = Arc::new(Qpack::new(1, 4096));
+    let encoded = encode(&qpack, &get_request_headers());
+    let mut set_capacity = vec![];
+    qpack.encode_set_dynamic_table_capacity(&mut set_capacity, 4096).unwrap()().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let contender = {
+        let qpack = Arc::clone(&qpack);
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                qpack.decode_encoder_instruction(&set_capacity).unwrap()().unwrap();
+            }
+        })
+    };
+    c.bench_function("decode_static_only_under_contention", |b| b.iter(|| qpack.decode_headers(black_box(&encoded), STREAM_ID).unwrap()));
+    stop.store(true, Ordering::Relaxed);
+    contender.join().unwrap();
+}
+
+fn huffman_encode_cookie(c: &mut Criterion) {
+    let qpack = Qpack::default();
+    let headers = get_cookie_headers();
+    c.bench_function("huffman_encode_cookie", |b| b.iter(|| encode(&qpack, black_box(&headers))));
+}
+
+fn huffman_decode_cookie(c: &mut Criterion) {
+    let qpack = Qpack::default();
+    let encoded = encode(&qpack, &get_cookie_headers());
+    c.bench_function("huffman_decode_cookie", |b| b.iter(|| qpack.decode_headers(black_box(&encoded), STREAM_ID).unwrap()));
+}
+
+fn dynamic_table_lookup_full(c: &mut Criterion) {
+    let table_size = 4096;
+    let qpack = Qpack::new(1, table_size);
+    let mut encoded = vec![];
+    qpack.encode_set_dynamic_table_capacity(&mut encoded, table_size).unwrap()().unwrap();
+    let mut i = 0;
+    loop {
+        let headers = vec![Header::from_string(format!("custom-key-{}", i), format!("custom-value-{}", i))];
+        if !qpack.is_insertable(&headers) {
+            break;
+        }
+        qpack.encode_insert_headers(&mut encoded, headers).unwrap()().unwrap();
+        i += 1;
+    }
+    // hit both of the newest and the oldest entries, and miss
+    let headers = vec![Header::from_string(format!("custom-key-{}", i - 1), format!("custom-value-{}", i - 1)),
+                       Header::from_string(format!("custom-key-{}", 0), "other-value".to_string()),
+                       Header::from_str("missing-key", "missing-value")];
+    c.bench_function("dynamic_table_lookup_full", |b| b.iter(|| {
+        let mut encoded = vec![];
+        // not committed not to add pending sections
+        let _ = qpack.encode_headers(&mut encoded, black_box(headers.clone()), STREAM_ID).unwrap();
+        encoded
+    }));
+}
+
+criterion_group!(benches,
+                 encode_request_headers,
+                 decode_request_headers,
+                 decode_static_only_under_contention,
+                 huffman_encode_cookie,
+                 huffman_decode_cookie,
+                 dynamic_table_lookup_full);
+criterion_main!(benches);
@@ -0,0 +1,100 @@
+use std::error;
+
+use http::{header::{HeaderName, HeaderValue}, HeaderMap, Method, Request, Uri};
+
+use crate::{Header, InvalidHeader};
+
+// Conversions from/to the http crate, enabled by "http" feature.
+// HeaderMap has no pseudo headers, which are taken from/to the method and the URI of Request
+
+// regular headers only. sensitive values are marked not to be inserted into the dynamic table
+pub fn from_header_map(map: &HeaderMap) -> Result<Vec<Header>, Box<dyn error::Error>> {
+    map.iter().map(|(name, value)| {
+        let mut header = Header::from_str(name.as_str(), value.to_str().map_err(|_| InvalidHeader("value is not visible ASCII"))?);
+        header.set_sensitive(value.is_sensitive());
+        Ok(header)
+    }).collect()
+}
+
+// pseudo headers are skipped
+pub fn to_header_map(headers: &[Header]) -> Result<HeaderMap, Box<dyn error::Error>> {
+    let mut map = HeaderMap::new();
+    for header in headers.iter().filter(|header| !header.get_name().value.starts_with(':')) {
+        let name = HeaderName::from_bytes(header.get_name().value.as_bytes())?;
+        let mut value = HeaderValue::from_str(&header.get_value().value)?;
+        value.set_sensitive(header.sensitive);
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+// :method, :scheme, :authority and :path followed by regular headers (RFC 9114 4.3.1)
+pub fn from_request<T>(request: &Request<T>) -> Result<Vec<Header>, Box<dyn error::Error>> {
+    let uri = request.uri();
+    let mut headers = vec![Header::from_str(":method", request.method().as_str())];
+    if let Some(scheme) = uri.scheme_str() {
+        headers.push(Header::from_str(":scheme", scheme));
+    }
+    if let Some(authority) = uri.authority() {
+        headers.push(Header::from_str(":authority", authority.as_str()));
+    }
+    if let Some(path) = uri.path_and_query() {
+        headers.push(Header::from_str(":path", path.as_str()));
+    }
+    headers.append(&mut from_header_map(request.headers())?);
+    Ok(headers)
+}
+
+pub fn to_request(headers: &[Header]) -> Result<Request<()>, Box<dyn error::Error>> {
+    let mut method = None;
+    let mut uri = Uri::builder();
+    for header in headers {
+        let value = header.get_value().value.as_str();
+        match header.get_name().value.as_str() {
+            ":method" => method = Some(Method::from_bytes(value.as_bytes())?),
+            ":scheme" => uri = uri.scheme(value),
+            ":authority" => uri = uri.authority(value),
+            ":path" => uri = uri.path_and_query(value),
+            name if name.starts_with(':') => return Err(InvalidHeader("unknown pseudo header").into()),
+            _ => {},
+        }
+    }
+    let mut request = Request::new(());
+    *request.method_mut() = method.ok_or(InvalidHeader(":method is missing"))?;
+    *request.uri_mut() = uri.build()?;
+    *request.headers_mut() = to_header_map(headers)?;
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header::HeaderValue, Request};
+    use crate::{Qpack, StreamId};
+    use super::{from_request, to_request};
+
+    #[test]
+    fn request_round_trip() {
+        let mut request = Request::get("https://example.com/index.html?q=1")
+            .header("accept", "text/html")
+            .header("cookie", "a=b")
+            .header("cookie", "c=d")
+            .body(()).unwrap();
+        let mut token = HeaderValue::from_static("Bearer secret");
+        token.set_sensitive(true);
+        request.headers_mut().insert("authorization", token);
+
+        let headers = from_request(&request).unwrap();
+        assert_eq!(headers[0].get_name().value, ":method");
+        assert!(headers.iter().any(|header| header.get_name().value == "authorization" && header.sensitive));
+
+        let qpack = Qpack::new(1, 4096);
+        let mut encoded = vec![];
+        qpack.encode_headers(&mut encoded, headers, StreamId::new(4)).unwrap()().unwrap();
+        let decoded = qpack.decode_headers(&encoded, StreamId::new(4)).unwrap().headers;
+        let out = to_request(&decoded).unwrap();
+        assert_eq!(out.method(), request.method());
+        assert_eq!(out.uri(), request.uri());
+        assert_eq!(out.headers(), request.headers());
+        assert!(out.headers()["authorization"].is_sensitive());
+    }
+}
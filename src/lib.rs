@@ -1,52 +1,304 @@
 mod transformer;
 mod table;
 mod types;
+mod stream;
+pub mod settings;
+#[cfg(feature = "http")]
+pub mod http_compat;
 
-use types::{CommitFunc, Header};
-use crate::transformer::decoder::{self, Decoder};
+pub use stream::{DecoderStreamDecoder, EncoderStreamDecoder};
+pub use table::DynamicTableSnapshot;
+pub use types::{CommitFunc, Header, HeaderBytes, HeaderString, InternedHeader, PendingSections, StrHeader, StreamId};
+use crate::types::{NamePool, SeenHeaders};
+use crate::transformer::decoder::{self, Decoder, FieldLine};
 use crate::transformer::encoder::{self, Encoder};
 use crate::table::Table;
+use crate::settings::QpackSettings;
 use core::fmt;
 use std::error;
+use std::collections::HashMap;
+use std::io;
 use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 #[macro_use]
 extern crate lazy_static;
 
+// Lock order. A lock may be taken while holding only the ones above it:
+//   1. table.dynamic_table (RwLock)
+//   2. encoder / decoder (RwLock), never both at once
+//   3. cv_insert_count (Mutex), also taken by DynamicTable on get_insert_count and insertions
+//   4. blocked_stream_cancels (Mutex), taken in the predicate of block_decoding
+// encoder_metrics and last_acknowledgment (Mutex) are taken holding nothing else
+// block_decoding waits on cv_insert_count holding nothing else, so that insertions are never
+// held back by a blocked decoding
 pub struct Qpack {
     encoder: Arc<RwLock<Encoder>>,
     decoder: Arc<RwLock<Decoder>>,
     table: Table,
-    blocked_streams_limit: u16,
+    // updated at runtime as SETTINGS may be renegotiated
+    blocked_streams_limit: AtomicU16,
     cv_insert_count: Arc<(Mutex<usize>, Condvar)>,
+    // insert an entry matching both name and value by Duplicate, otherwise by name reference
+    prefer_duplicate: bool,
+    // reject headers invalid in HTTP on encoding field sections
+    strict: bool,
+    // what to do when a field section refers an entry close to be evicted
+    eviction_risk_policy: EvictionRiskPolicy,
+    // called with (stream id, required insert count) when decoding starts to wait for insertions
+    on_block: Option<Box<dyn Fn(StreamId, usize) + Send + Sync>>,
+    // called with stream id when the blocked decoding resumes
+    on_unblock: Option<Box<dyn Fn(StreamId) + Send + Sync>>,
+    // overrides huffman flags of each header if set
+    huffman_policy: Option<HuffmanPolicy>,
+    // if set, encode_insert_headers inserts only headers seen recently
+    insert_on_repeat: Option<Mutex<SeenHeaders>>,
+    // if set, encode_insert_headers inserts nothing while the blocked streams budget left is this or less
+    insert_throttle_margin: Option<usize>,
+    // if set, decode_headers_interned shares names from this pool
+    name_pool: Option<Mutex<NamePool>>,
+    // called with the acknowledgment lag when insertions make it exceed acknowledgment_lag_threshold
+    on_acknowledgment_lag: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    acknowledgment_lag_threshold: usize,
+    // called with the absolute index of an entry at eviction risk referred under EvictionRiskPolicy::Warn
+    on_eviction_risk: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    // field sections larger than this are neither encoded nor decoded
+    max_header_list_size: Option<usize>,
+    // neither insert nor refer the dynamic table if false
+    allow_dynamic_table: bool,
+    // blocked decoding fails after this long without the insertions. None to wait forever
+    blocked_stream_timeout: Option<Duration>,
+    // streams blocked now, true once cancelled by cancel_blocked_stream. keyed by the stream and
+    // a token of each wait, as decodings on the same stream may block at once
+    blocked_stream_cancels: Mutex<HashMap<(StreamId, u64), bool>>,
+    next_blocked_token: AtomicU64,
+    encoder_config: EncoderConfig,
+    // updated on commit
+    encoder_metrics: Arc<Mutex<EncoderMetrics>>,
+    // when this decoder last committed an Insert Count Increment or Section Acknowledgment
+    last_acknowledgment: Arc<Mutex<Instant>>,
+    // encoder_likely_stalled needs no acknowledgment for this long
+    stall_ack_interval: Duration,
 }
 
 impl Qpack {
     pub fn new(blocked_streams_limit: u16, dynamic_table_max_capacity: usize) -> Self {
+        Qpack::with_config(QpackConfig {
+            blocked_streams_limit,
+            max_table_capacity: dynamic_table_max_capacity,
+            ..QpackConfig::default()
+        })
+    }
+    pub fn with_config(config: QpackConfig) -> Self {
         let cv_insert_count = Arc::new((Mutex::new(0), Condvar::new()));
         Qpack {
             encoder: Arc::new(RwLock::new(Encoder::new())),
             decoder: Arc::new(RwLock::new(Decoder::new())),
-            table: Table::new(dynamic_table_max_capacity, Arc::clone(&cv_insert_count)),
-            blocked_streams_limit,
+            table: Table::new(config.max_table_capacity, Arc::clone(&cv_insert_count)),
+            blocked_streams_limit: AtomicU16::new(config.blocked_streams_limit),
             cv_insert_count,
+            prefer_duplicate: true,
+            strict: false,
+            eviction_risk_policy: EvictionRiskPolicy::Ignore,
+            on_block: None,
+            on_unblock: None,
+            huffman_policy: None,
+            insert_on_repeat: None,
+            insert_throttle_margin: None,
+            name_pool: None,
+            on_acknowledgment_lag: None,
+            acknowledgment_lag_threshold: usize::MAX,
+            on_eviction_risk: None,
+            max_header_list_size: config.max_header_list_size,
+            allow_dynamic_table: config.allow_dynamic_table,
+            blocked_stream_timeout: config.blocked_stream_timeout,
+            blocked_stream_cancels: Mutex::new(HashMap::new()),
+            next_blocked_token: AtomicU64::new(0),
+            encoder_config: config.encoder,
+            encoder_metrics: Arc::new(Mutex::new(EncoderMetrics::default())),
+            last_acknowledgment: Arc::new(Mutex::new(Instant::now())),
+            stall_ack_interval: Duration::from_millis(100),
+        }
+    }
+    // custom static table agreed with the peer out of band. it is referred, not copied,
+    // so connections sharing one table cost nothing extra
+    pub fn set_static_table(&mut self, static_table: &'static [StrHeader<'static>]) {
+        self.table.static_table = static_table;
+    }
+    // from the QPACK parameters in SETTINGS. values too large are capped
+    pub fn with_settings(settings: &QpackSettings) -> Self {
+        Qpack::with_config(QpackConfig {
+            blocked_streams_limit: settings.blocked_streams.min(u16::MAX as u64) as u16,
+            max_table_capacity: settings.max_table_capacity.min(usize::MAX as u64) as usize,
+            ..QpackConfig::default()
+        })
+    }
+    // refer only entries acknowledged by decoder so that decoder never blocks, or any entry if false.
+    // same as set_max_required_insert_count_ahead with Some(0) or None
+    pub fn set_no_block(&mut self, no_block: bool) {
+        self.set_max_required_insert_count_ahead(if no_block { Some(0) } else { None });
+    }
+    pub fn set_max_required_insert_count_ahead(&mut self, ahead: Option<usize>) {
+        self.encoder_config.max_required_insert_count_ahead = ahead;
+    }
+    pub fn set_prefer_duplicate(&mut self, prefer_duplicate: bool) {
+        self.prefer_duplicate = prefer_duplicate;
+    }
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+    pub fn set_eviction_risk_policy(&mut self, policy: EvictionRiskPolicy) {
+        self.eviction_risk_policy = policy;
+    }
+    pub fn set_encoder_config(&mut self, config: EncoderConfig) {
+        self.encoder_config = config;
+    }
+    // how long without acknowledgments before encoder_likely_stalled holds. 100ms by default
+    pub fn set_stall_ack_interval(&mut self, interval: Duration) {
+        self.stall_ack_interval = interval;
+    }
+    // how often references were given up for the constraints, since the start
+    pub fn encoder_metrics(&self) -> EncoderMetrics {
+        *self.encoder_metrics.lock().unwrap()
+    }
+    // Entries evicted by inserting this fraction of capacity are not referred by field sections but
+    // re-inserted by Duplicate (# 2.1.1.1)
+    pub fn set_draining_fraction(&mut self, fraction: f64) {
+        self.encoder.write().unwrap().draining_fraction = fraction;
+    }
+    pub fn draining_idx(&self) -> usize {
+        let fraction = self.encoder.read().unwrap().draining_fraction;
+        self.table.get_draining_index(fraction)
+    }
+    pub fn max_blocked_streams(&self) -> u16 {
+        self.blocked_streams_limit.load(Ordering::Relaxed)
+    }
+    // streams already blocked keep waiting even if the limit goes below them
+    pub fn set_max_blocked_streams(&self, n: u16) {
+        self.blocked_streams_limit.store(n, Ordering::Relaxed);
+    }
+    pub fn set_huffman_policy(&mut self, policy: Option<HuffmanPolicy>) {
+        self.huffman_policy = policy;
+    }
+    fn apply_huffman_policy(&self, headers: &mut [Header]) {
+        let policy = match self.huffman_policy {
+            Some(policy) => policy,
+            None => return,
+        };
+        for header in headers.iter_mut() {
+            let flags = match policy {
+                HuffmanPolicy::None => (false, false),
+                HuffmanPolicy::NamesOnly => (true, false),
+                HuffmanPolicy::ValuesOnly => (false, true),
+                HuffmanPolicy::Both => (true, true),
+                HuffmanPolicy::Auto => {
+                    header.set_huffman_auto();
+                    continue;
+                },
+            };
+            header.set_huffman(flags);
+        }
+    }
+    // Skips headers on the first sighting on encode_insert_headers, and inserts them when seen again
+    // within the last `capacity` distinct headers. 0 inserts everything as before
+    pub fn set_insert_on_repeat(&mut self, capacity: usize) {
+        self.insert_on_repeat = if capacity == 0 {
+            None
+        } else {
+            Some(Mutex::new(SeenHeaders::new(capacity)))
+        };
+    }
+    // Keeps `capacity` names recently decoded by decode_headers_interned to share them. 0 allocates every name
+    pub fn set_name_interning(&mut self, capacity: usize) {
+        self.name_pool = if capacity == 0 {
+            None
+        } else {
+            Some(Mutex::new(NamePool::new(capacity)))
+        };
+    }
+    // names allocated by the pool so far
+    pub fn interned_name_allocations(&self) -> usize {
+        self.name_pool.as_ref().map_or(0, |pool| pool.lock().unwrap().allocations)
+    }
+    // Holds back insertions while streams which may block the decoder leave `margin` or less of
+    // blocked_streams_limit, as new entries referred by more streams would exceed it. None to always insert
+    pub fn set_insert_throttle(&mut self, margin: Option<usize>) {
+        self.insert_throttle_margin = margin;
+    }
+    // streams whose sections refer entries not acknowledged yet, and so may block the decoder
+    pub fn blocking_streams(&self) -> usize {
+        let known_received_count = self.table.get_known_received_count();
+        self.encoder.read().unwrap().blocking_streams(known_received_count)
+    }
+    fn is_insert_throttled(&self) -> bool {
+        match self.insert_throttle_margin {
+            Some(margin) => (self.max_blocked_streams() as usize).saturating_sub(self.blocking_streams()) <= margin,
+            None => false,
+        }
+    }
+    pub fn set_on_block(&mut self, on_block: impl Fn(StreamId, usize) + Send + Sync + 'static) {
+        self.on_block = Some(Box::new(on_block));
+    }
+    pub fn set_on_unblock(&mut self, on_unblock: impl Fn(StreamId) + Send + Sync + 'static) {
+        self.on_unblock = Some(Box::new(on_unblock));
+    }
+    pub fn set_on_eviction_risk(&mut self, on_eviction_risk: impl Fn(usize) + Send + Sync + 'static) {
+        self.on_eviction_risk = Some(Box::new(on_eviction_risk));
+    }
+    pub fn set_on_acknowledgment_lag(&mut self, threshold: usize, on_lag: impl Fn(usize) + Send + Sync + 'static) {
+        self.on_acknowledgment_lag = Some(Box::new(on_lag));
+        self.acknowledgment_lag_threshold = threshold;
+    }
+    // number of insertions the decoder has not acknowledged yet
+    pub fn acknowledgment_lag(&self) -> usize {
+        self.table.get_insert_count().saturating_sub(self.table.get_known_received_count())
+    }
+    fn check_acknowledgment_lag(&self, num_inserts: usize) {
+        if let Some(on_lag) = &self.on_acknowledgment_lag {
+            let lag = self.acknowledgment_lag() + num_inserts;
+            if self.acknowledgment_lag_threshold < lag {
+                on_lag(lag);
+            }
         }
     }
-    pub fn is_insertable(&self, headers: &Vec<Header>) -> bool {
+    pub fn is_insertable(&self, headers: &[Header]) -> bool {
         self.table.is_insertable(headers)
     }
-    pub fn encode_insert_headers(&self, encoded: &mut Vec<u8>, headers: Vec<Header>)
+    // The commit fails with TableChanged if another insertion or eviction is committed in between,
+    // as relative indices in encoded are against the table at the time of encoding
+    pub fn encode_insert_headers(&self, encoded: &mut Vec<u8>, mut headers: Vec<Header>)
             -> Result<CommitFunc, Box<dyn error::Error>> {
+        self.apply_huffman_policy(&mut headers);
+        if let Some(seen_headers) = &self.insert_on_repeat {
+            let mut seen_headers = seen_headers.lock().unwrap();
+            headers.retain(|header| seen_headers.see(header));
+        }
+        if !self.allow_dynamic_table || self.is_insert_throttled() {
+            headers.clear();
+        }
+        let mut metrics = EncoderMetrics::default();
+        if !self.encoder_config.insert_sensitive {
+            let len = headers.len();
+            headers.retain(|header| !header.sensitive);
+            metrics.sensitive_not_inserted = len - headers.len();
+        }
         let mut commit_funcs = vec![];
+        let draining_idx = self.draining_idx();
         // INFO: Perforamnce of bulk lookup or lookup each would be depends on lookup algorithm
+        // relative indices below are valid only while no entry is inserted or evicted. checked again on commit
+        let eviction_count = self.table.get_eviction_count();
         let find_index_results = self.table.find_headers(&headers);
+        let insert_count = self.table.get_insert_count();
         for (i, header)  in headers.into_iter().enumerate() {
             let (both_match, on_static, mut idx) = find_index_results[i];
+            // draining entry is refreshed by Duplicate so that field sections can keep referring it
+            let draining = !on_static && idx < draining_idx;
             if idx != usize::MAX && !on_static {
-                // absolute to relative (against 0) conversion
-                idx = self.table.get_insert_count() - 1 - idx
+                // absolute to relative conversion, against Insert Count including preceding instructions
+                idx = insert_count + i - 1 - idx
             }
 
-            if both_match && !on_static {
+            if both_match && !on_static && (self.prefer_duplicate || draining) {
                 Encoder::encode_duplicate(encoded, idx)?;
                 commit_funcs.push(self.table.duplicate(idx)?);
             } else if idx != usize::MAX {
@@ -58,14 +310,22 @@ impl Qpack {
                 commit_funcs.push(self.table.insert_both_literal(header)?);
             }
         }
+        self.check_acknowledgment_lag(commit_funcs.len());
 
         let encoder = Arc::clone(&self.encoder);
         let dynamic_table = Arc::clone(&self.table.dynamic_table);
+        let encoder_metrics = Arc::clone(&self.encoder_metrics);
         Ok(Box::new(move || -> Result<(), Box<dyn error::Error>> {
             let count = commit_funcs.len();
             let mut locked_table = dynamic_table.write().unwrap();
+            if 0 < count && (locked_table.eviction_count != eviction_count || locked_table.get_insert_count() != insert_count) {
+                // another encoder changed the table after find_headers. encoded is stale
+                return Err(TableChanged.into());
+            }
             commit_funcs.into_iter().try_for_each(|f| f(&mut locked_table))?;
             encoder.write().unwrap().known_sending_count += count;
+            drop(locked_table);
+            encoder_metrics.lock().unwrap().add(&metrics);
             Ok(())
         }))
     }
@@ -77,18 +337,54 @@ impl Qpack {
             dynamic_table.write().unwrap().set_capacity(capacity)
         }))
     }
-    pub fn encode_section_ackowledgment(&self, encoded: &mut Vec<u8>, stream_id: u16)
+    // wire size of encoder instructions, without emitting them
+    pub fn set_capacity_instruction_len(capacity: usize) -> usize {
+        Encoder::set_dynamic_table_capacity_len(capacity)
+    }
+    pub fn duplicate_instruction_len(idx: usize) -> usize {
+        Encoder::duplicate_len(idx)
+    }
+    // Required Insert Count as encoded in the prefix of field sections and its inverse (# 4.5.1.1),
+    // for a table of max_entries (the maximum capacity / 32)
+    pub fn encoded_insert_count(required_insert_count: usize, max_entries: usize) -> u64 {
+        Encoder::encoded_insert_count(required_insert_count as u64, max_entries as u64)
+    }
+    pub fn decoded_insert_count(encoded_insert_count: u64, max_entries: usize, total_number_of_inserts: usize)
+            -> Result<usize, Box<dyn error::Error>> {
+        match Decoder::decoded_insert_count(encoded_insert_count, max_entries as u64, total_number_of_inserts as u64) {
+            Some(required_insert_count) => Ok(required_insert_count as usize),
+            None => Err(DecompressionFailed::new(DecompressReason::InvalidRequiredInsertCount, 0).into()),
+        }
+    }
+    // Dynamic table capacity which holds all the headers with a third of headroom, so that the set
+    // stays out of the last quarter of the table which is considered at eviction risk.
+    pub fn recommended_capacity(headers: &[Header]) -> usize {
+        let total: usize = headers.iter().map(|header| header.size()).sum();
+        total + total / 3
+    }
+    pub fn encode_section_ackowledgment(&self, encoded: &mut Vec<u8>, stream_id: StreamId)
             -> Result<CommitFunc, Box<dyn error::Error>> {
         Decoder::encode_section_ackowledgment(encoded, stream_id)?;
         let decoder = Arc::clone(&self.decoder);
         let dynamic_table = Arc::clone(&self.table.dynamic_table);
+        let last_acknowledgment = Arc::clone(&self.last_acknowledgment);
         Ok(Box::new(move || -> Result<(), Box<dyn error::Error>> {
-            let section = decoder.write().unwrap().ack_section(stream_id);
+            let section = decoder.write().unwrap().ack_section(stream_id)?;
             dynamic_table.write().unwrap().ack_section(section, vec![]);
+            *last_acknowledgment.lock().unwrap() = Instant::now();
             Ok(())
         }))
     }
-    pub fn encode_stream_cancellation(&self, encoded: &mut Vec<u8>, stream_id: u16)
+    // Encoder side cleanup of a stream aborted locally. Releases the references of the sections sent on
+    // the stream, as the decoder will not acknowledge them. Nothing happens if the stream has no section
+    pub fn abort_stream(&self, stream_id: StreamId) {
+        // same lock order as the commit funcs of decoder instructions
+        let mut dynamic_table = self.table.dynamic_table.write().unwrap();
+        if let Ok(indices) = self.encoder.write().unwrap().cancel_section(stream_id) {
+            dynamic_table.cancel_section(indices);
+        }
+    }
+    pub fn encode_stream_cancellation(&self, encoded: &mut Vec<u8>, stream_id: StreamId)
             -> Result<CommitFunc, Box<dyn error::Error>> {
         Decoder::encode_stream_cancellation(encoded, stream_id)?;
         let decoder = Arc::clone(&self.decoder);
@@ -97,177 +393,634 @@ impl Qpack {
             Ok(())
         }))
     }
-    // TODO: check whether to update state
+    // Emits nothing and commits nothing if every insertion is acknowledged already,
+    // as an increment of 0 is an error (# 4.4.3)
     pub fn encode_insert_count_increment(&self, encoded: &mut Vec<u8>)
             -> Result<CommitFunc, Box<dyn error::Error>> {
         let dynamic_table_read = self.table.dynamic_table.read().unwrap();
-        let increment = dynamic_table_read.list.len() - dynamic_table_read.known_received_count;
+        let increment = dynamic_table_read.get_insert_count() - dynamic_table_read.known_received_count;
+        drop(dynamic_table_read);
+        if increment == 0 {
+            return Ok(Box::new(|| Ok(())));
+        }
         Decoder::encode_insert_count_increment(encoded, increment)?;
         let dynamic_table = Arc::clone(&self.table.dynamic_table);
+        let last_acknowledgment = Arc::clone(&self.last_acknowledgment);
         Ok(Box::new(move || -> Result<(), Box<dyn error::Error>> {
             dynamic_table.write().unwrap().known_received_count += increment;
+            *last_acknowledgment.lock().unwrap() = Instant::now();
             Ok(())
         }))
     }
-
-    fn get_prefix_meta_data(&self, find_index_results: &Vec<(bool, bool, usize)>) -> (usize, bool, u32) {
-        // if same distribusion, then post base.
-        // currently just range
-        let mut min_max = (usize::MAX, usize::MIN);
-        for i in 0..find_index_results.len() {
-            let result = &find_index_results[i];
-            if result.1 || result.2 == usize::MAX {
-                continue;
-            }
-            if result.2 < min_max.0 {
-                min_max.0 = result.2;
-            }
-            if min_max.1 < result.2 {
-                min_max.1 = result.2;
-            }
-        }
-        if min_max == (usize::MAX, usize::MIN) {
-            return (0, false, 0);
+    // Acknowledges every insertion applied so far if encoder_likely_stalled, so that the encoder
+    // can evict entries. Emits nothing and commits nothing otherwise
+    pub fn acknowledge_all_inserts(&self, encoded: &mut Vec<u8>) -> Result<CommitFunc, Box<dyn error::Error>> {
+        if !self.encoder_likely_stalled() {
+            return Ok(Box::new(|| Ok(())));
         }
-        let entry_len = self.table.get_dynamic_table_entry_len();
-        let required_insert_count = min_max.1 + 1 + self.table.get_eviction_count();
+        self.encode_insert_count_increment(encoded)
+    }
+    // Decoder side guess that the encoder cannot insert any more. The table is almost full,
+    // which can be evicted only after insertions are acknowledged, and nothing has been
+    // acknowledged for stall_ack_interval
+    pub fn encoder_likely_stalled(&self) -> bool {
+        self.table.get_known_received_count() < self.table.get_insert_count()
+            && self.table.get_free_capacity() <= self.table.get_capacity() / 4
+            && self.stall_ack_interval <= self.last_acknowledgment.lock().unwrap().elapsed()
+    }
 
-        // WARN: if min_max uses abs_index, entry_len to be insert_count
-        let post_base = ((min_max.0 + min_max.1) / 2) < entry_len / 2;
+    // Base is either the required insert count (S=0, delta 0) so that every reference is pre-base,
+    // or the smallest referred index (S=1) so that every reference is post-base.
+    // Both lengths of delta base and dynamic table indices are computed, and post-base is chosen
+    // only when it is strictly shorter.
+    fn get_prefix_meta_data(&self, headers: &[Header], find_index_results: &[(bool, bool, usize)]) -> (usize, bool, u32) {
+        let refs: Vec<(bool, usize)> = headers.iter().zip(find_index_results)
+            .filter(|(_, (_, on_static, idx))| !on_static && *idx != usize::MAX)
+            .map(|(header, (both_match, _, idx))| (*both_match && !header.sensitive, *idx))
+            .collect();
+        let (min, max) = match (refs.iter().map(|r| r.1).min(), refs.iter().map(|r| r.1).max()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return (0, false, 0),
+        };
+        let required_insert_count = max + 1;
+
+        let pre_base_len = Encoder::delta_base_len(0) + refs.iter()
+            .map(|(indexed, idx)| Encoder::field_line_index_len(required_insert_count - idx - 1, *indexed, false))
+            .sum::<usize>();
+        let post_base_len = Encoder::delta_base_len(required_insert_count - min - 1) + refs.iter()
+            .map(|(indexed, idx)| Encoder::field_line_index_len(idx - min, *indexed, true))
+            .sum::<usize>();
+        let post_base = post_base_len < pre_base_len;
         (
             required_insert_count,
             post_base,
-            if post_base {min_max.0} else {required_insert_count} as u32
+            if post_base {min} else {required_insert_count} as u32
         )
     }
 
-    pub fn encode_headers(&self, encoded: &mut Vec<u8>, headers: Vec<Header>, stream_id: u16)
-            -> Result<CommitFunc, Box<dyn error::Error>> {
-        let find_index_results = self.table.find_headers(&headers);
-        let (required_insert_count, post_base, base) = self.get_prefix_meta_data(&find_index_results);
-        Encoder::prefix(encoded,
-                        &self.table,
-                        required_insert_count as u32,
-                        post_base,
-                        base);
-
-        let mut dynamic_table_indices = vec![];
-        for (i, header) in headers.into_iter().enumerate() {
-            let (both_match, on_static, idx) = find_index_results[i];
-            if !on_static && idx != usize::MAX {
-                dynamic_table_indices.push(idx);
+    // entries at this absolute index or later must not be referred. None if no limit
+    fn referable_insert_count(&self) -> Option<usize> {
+        if !self.allow_dynamic_table {
+            return Some(0);
+        }
+        self.encoder_config.max_required_insert_count_ahead.map(|ahead| self.table.get_known_received_count().saturating_add(ahead))
+    }
+    // references given up are counted in metrics, except for draining entries
+    fn find_headers_to_refer(&self, headers: &[Header], referable_insert_count: Option<usize>, metrics: &mut EncoderMetrics)
+            -> Vec<(bool, bool, usize)> {
+        let mut find_index_results = self.table.find_headers(headers);
+        // draining entries would block insertions until acknowledged. fall back to static table or literal
+        let draining_idx = self.draining_idx();
+        for (i, header) in headers.iter().enumerate() {
+            let (_, on_static, idx) = find_index_results[i];
+            if !on_static && idx < draining_idx {
+                find_index_results[i] = self.table.find_static_header(header);
+            }
+        }
+        if let Some(referable_insert_count) = referable_insert_count {
+            // entries not acknowledged yet may block decoder. fall back to static table or literal
+            for (i, header) in headers.iter().enumerate() {
+                let (_, on_static, idx) = find_index_results[i];
+                if !on_static && idx != usize::MAX && referable_insert_count <= idx {
+                    find_index_results[i] = self.table.find_static_header(header);
+                    metrics.unacknowledged_fallbacks += 1;
+                }
             }
+        }
+        if let Some(max_references) = self.encoder_config.max_dynamic_references {
+            let mut references = 0;
+            for (i, header) in headers.iter().enumerate() {
+                let (_, on_static, idx) = find_index_results[i];
+                if on_static || idx == usize::MAX {
+                    continue;
+                }
+                if references < max_references {
+                    references += 1;
+                } else {
+                    find_index_results[i] = self.table.find_static_header(header);
+                    metrics.reference_cap_fallbacks += 1;
+                }
+            }
+        }
+        find_index_results
+    }
 
+    // Entries before base are referred pre-base, and the others post-base.
+    // Referring an entry at required_insert_count or later is invalid either way
+    fn encode_field_line(encoded: &mut Vec<u8>, header: Header, find_index_result: (bool, bool, usize), required_insert_count: usize, base: usize)
+            -> Result<Representation, Box<dyn error::Error>> {
+        let (both_match, on_static, idx) = find_index_result;
+        if !on_static && idx != usize::MAX && required_insert_count <= idx {
+            return Err(InvalidReference { idx, required_insert_count }.into());
+        }
+        let post_base = base <= idx;
+        Ok(
             if both_match && !header.sensitive {
                 if on_static {
                     Encoder::encode_indexed(encoded, idx as u32, true);
+                    Representation::Indexed { from_static: true }
+                } else if post_base {
+                    Encoder::encode_indexed_post_base(encoded, (idx - base) as u32);
+                    Representation::IndexedPostBase
                 } else {
-                    if post_base {
-                        Encoder::encode_indexed_post_base(encoded, idx as u32 - base);
-                    } else {
-                        Encoder::encode_indexed(encoded, base - idx as u32 - 1, false);
-                    }
+                    Encoder::encode_indexed(encoded, (base - idx - 1) as u32, false);
+                    Representation::Indexed { from_static: false }
                 }
             } else if idx != usize::MAX {
                 if on_static {
                     Encoder::encode_refer_name(encoded, idx as u32, header, true)?;
+                    Representation::ReferName { from_static: true }
+                } else if post_base {
+                    Encoder::encode_refer_name_post_base(encoded, (idx - base) as u32, header)?;
+                    Representation::ReferNamePostBase
                 } else {
-                    if post_base {
-                        Encoder::encode_refer_name_post_base(encoded, idx as u32 - base, header)?;
-                    } else {
-                        Encoder::encode_refer_name(encoded, base - idx as u32 - 1, header, false)?;
-                    }
+                    Encoder::encode_refer_name(encoded, (base - idx - 1) as u32, header, false)?;
+                    Representation::ReferName { from_static: false }
                 }
             } else { // not found
                 Encoder::encode_both_literal(encoded, header)?;
+                Representation::BothLiteral
+            }
+        )
+    }
+
+    // Encodes a field line without prefix. Dynamic table is referred relative to the Base
+    // which encode_headers would choose for a section consisting of this header only.
+    // Referred entries are not tracked as no section is sent.
+    pub fn encode_single_header(&self, header: &Header) -> Result<(Vec<u8>, Representation), Box<dyn error::Error>> {
+        let mut header = header.clone();
+        self.apply_huffman_policy(std::slice::from_mut(&mut header));
+        let find_index_results = self.find_headers_to_refer(std::slice::from_ref(&header), self.referable_insert_count(),
+                                                            &mut EncoderMetrics::default());
+        let (required_insert_count, _, base) = self.get_prefix_meta_data(std::slice::from_ref(&header), &find_index_results);
+        let mut encoded = vec![];
+        let representation = Qpack::encode_field_line(&mut encoded, header, find_index_results[0], required_insert_count, base as usize)?;
+        Ok((encoded, representation))
+    }
+
+    // size of the field section as in SETTINGS_MAX_FIELD_SECTION_SIZE, 32 bytes overhead per header (RFC 9114 4.2.2)
+    fn check_header_list_size(&self, headers: &[Header]) -> Result<(), Box<dyn error::Error>> {
+        if let Some(limit) = self.max_header_list_size {
+            let size = headers.iter().map(|header| header.size()).sum();
+            if limit < size {
+                return Err(TooLarge { size, limit }.into());
+            }
+        }
+        Ok(())
+    }
+    pub fn encode_headers(&self, encoded: &mut Vec<u8>, headers: Vec<Header>, stream_id: StreamId)
+            -> Result<CommitFunc, Box<dyn error::Error>> {
+        self.encode_section(encoded, headers, stream_id, None, self.referable_insert_count())
+    }
+    // Same as encode_headers, but refers only entries acknowledged by the decoder regardless of
+    // max_required_insert_count_ahead, so that decoding the section never blocks
+    pub fn encode_headers_safe(&self, encoded: &mut Vec<u8>, headers: Vec<Header>, stream_id: StreamId)
+            -> Result<CommitFunc, Box<dyn error::Error>> {
+        let known_received_count = self.table.get_known_received_count();
+        let referable_insert_count = self.referable_insert_count()
+            .map_or(known_received_count, |count| count.min(known_received_count));
+        self.encode_section(encoded, headers, stream_id, None, Some(referable_insert_count))
+    }
+    // Same as encode_headers, but against the given Base instead of the shortest one. To refer entries
+    // inserted for this request post-base (# B.2), take the Insert Count before encode_insert_headers
+    // as base and commit the insertions first
+    pub fn encode_headers_with_base(&self, encoded: &mut Vec<u8>, headers: Vec<Header>, stream_id: StreamId, base: usize)
+            -> Result<CommitFunc, Box<dyn error::Error>> {
+        let insert_count = self.table.get_insert_count();
+        if insert_count < base {
+            return Err(InvalidBase { base, insert_count }.into());
+        }
+        self.encode_section(encoded, headers, stream_id, Some(base), self.referable_insert_count())
+    }
+    // state of the dynamic table, e.g. to decide whether to emit Insert Count Increment or hold insertions
+    pub fn insert_count(&self) -> usize {
+        self.table.get_insert_count()
+    }
+    pub fn known_received_count(&self) -> usize {
+        self.table.get_known_received_count()
+    }
+    // sum of entry sizes (# 3.2.1)
+    pub fn dynamic_table_size(&self) -> usize {
+        self.table.dynamic_table.read().unwrap().current_size
+    }
+    pub fn dynamic_table_capacity(&self) -> usize {
+        self.table.dynamic_table.read().unwrap().capacity
+    }
+    fn encode_section(&self, encoded: &mut Vec<u8>, mut headers: Vec<Header>, stream_id: StreamId, base: Option<usize>,
+                      referable_insert_count: Option<usize>)
+            -> Result<CommitFunc, Box<dyn error::Error>> {
+        self.apply_huffman_policy(&mut headers);
+        if self.strict && headers.iter().any(|header| header.get_name().value.is_empty()) {
+            return Err(InvalidHeader("empty name").into());
+        }
+        self.check_header_list_size(&headers)?;
+        // indices below are valid only while no entry is evicted. checked again on commit
+        let eviction_count = self.table.get_eviction_count();
+        let mut metrics = EncoderMetrics::default();
+        let find_index_results = self.find_headers_to_refer(&headers, referable_insert_count, &mut metrics);
+        if self.eviction_risk_policy != EvictionRiskPolicy::Ignore {
+            for (_, on_static, idx) in find_index_results.iter() {
+                if *on_static || *idx == usize::MAX || !self.table.is_at_eviction_risk(*idx) {
+                    continue;
+                }
+                if self.eviction_risk_policy == EvictionRiskPolicy::Error {
+                    return Err(EvictionRisk(*idx).into());
+                }
+                if let Some(on_eviction_risk) = &self.on_eviction_risk {
+                    on_eviction_risk(*idx);
+                }
+            }
+        }
+        let (required_insert_count, post_base, base) = match (self.get_prefix_meta_data(&headers, &find_index_results), base) {
+            ((required_insert_count, _, _), Some(base)) if 0 < required_insert_count =>
+                (required_insert_count, base < required_insert_count, base as u32),
+            (meta_data, _) => meta_data,
+        };
+        Encoder::prefix(encoded,
+                        &self.table,
+                        required_insert_count as u32,
+                        post_base,
+                        base);
+
+        let mut dynamic_table_indices = vec![];
+        for (i, header) in headers.into_iter().enumerate() {
+            let (_, on_static, idx) = find_index_results[i];
+            if !on_static && idx != usize::MAX {
+                dynamic_table_indices.push(idx);
             }
+            Qpack::encode_field_line(encoded, header, find_index_results[i], required_insert_count, base as usize)?;
         }
         let encoder = Arc::clone(&self.encoder);
         let dynamic_table = Arc::clone(&self.table.dynamic_table);
+        let encoder_metrics = Arc::clone(&self.encoder_metrics);
         Ok(Box::new(move || -> Result<(), Box<dyn error::Error>> {
-            if 0 < dynamic_table_indices.len() {
+            if !dynamic_table_indices.is_empty() {
                 let mut write_lock = dynamic_table.write().unwrap();
+                if write_lock.eviction_count != eviction_count {
+                    // another encoder evicted entries after find_headers. encoded is stale
+                    return Err(TableChanged.into());
+                }
                 dynamic_table_indices.iter().try_for_each(|idx| write_lock.ref_entry_at(*idx))?;
                 encoder.write().unwrap().add_section(stream_id, required_insert_count, dynamic_table_indices);
             }
+            encoder_metrics.lock().unwrap().add(&metrics);
+            Ok(())
+        }))
+    }
+
+    // Encodes a field section which fits in limit bytes, or fails with TooLarge without any state change
+    pub fn encode_headers_within(&self, headers: Vec<Header>, stream_id: StreamId, limit: usize)
+            -> Result<(Vec<u8>, CommitFunc), Box<dyn error::Error>> {
+        let mut encoded = vec![];
+        let commit_func = self.encode_headers(&mut encoded, headers, stream_id)?;
+        if limit < encoded.len() {
+            return Err(TooLarge { size: encoded.len(), limit }.into());
+        }
+        Ok((encoded, commit_func))
+    }
+    // Size of the field section encode_headers would emit divided by the sum of name and value
+    // lengths. Nothing is committed, so the table state is left untouched.
+    // None if there is nothing to compress, i.e. no header or only empty names and values
+    pub fn compression_ratio(&self, headers: &[Header]) -> Result<Option<f64>, Box<dyn error::Error>> {
+        let uncompressed_len: usize = headers.iter()
+            .map(|header| header.get_name().value.len() + header.get_value().value.len())
+            .sum();
+        if uncompressed_len == 0 {
+            return Ok(None);
+        }
+        let mut encoded = vec![];
+        let _ = self.encode_headers(&mut encoded, headers.to_vec(), StreamId::new(0))?;
+        Ok(Some(encoded.len() as f64 / uncompressed_len as f64))
+    }
+
+    // Encodes headers one by one, inserting the ones not found in the tables, and passes the bytes
+    // to emit as soon as they are built. A field line referring to an entry inserted here is emitted
+    // right after its encoder instruction, so the caller must write EncoderInstruction chunks to the
+    // encoder stream no later than the field section. The Prefix depends on the Required Insert Count
+    // and is emitted last, but has to be placed before all the FieldLine chunks of the section.
+    // Insertion never evicts an entry, headers not fitting in the free space are not inserted.
+    pub fn encode_headers_streaming(&self, mut headers: Vec<Header>, stream_id: StreamId, mut emit: impl FnMut(EncodedChunk, &[u8]))
+            -> Result<CommitFunc, Box<dyn error::Error>> {
+        self.apply_huffman_policy(&mut headers);
+        if self.strict && headers.iter().any(|header| header.get_name().value.is_empty()) {
+            return Err(InvalidHeader("empty name").into());
+        }
+        self.check_header_list_size(&headers)?;
+        let eviction_count = self.table.get_eviction_count();
+        // Base is the Insert Count before this section, entries inserted here are referred post-base
+        let base = self.table.get_insert_count();
+        let referable_insert_count = self.referable_insert_count();
+        let find_index_results = self.find_headers_to_refer(&headers, referable_insert_count, &mut EncoderMetrics::default());
+        let mut free_capacity = self.table.get_free_capacity();
+        let referable_insert_count = referable_insert_count.unwrap_or(usize::MAX);
+        let mut inserted: Vec<Header> = vec![];
+        let mut dynamic_table_indices = vec![];
+        let mut required_insert_count = 0;
+
+        for (i, header) in headers.into_iter().enumerate() {
+            let (both_match, on_static, idx) = find_index_results[i];
+            let inserted_idx = inserted.iter().position(|entry| entry.get_name() == header.get_name()
+                                                               && entry.get_value() == header.get_value());
+            let mut encoded = vec![];
+            let find_index_result = if let Some(pos) = inserted_idx {
+                (true, false, base + pos)
+            } else if !both_match && !header.sensitive && base + inserted.len() < referable_insert_count
+                      && header.size() <= free_capacity {
+                let insert_count = base + inserted.len();
+                if idx != usize::MAX {
+                    let name_idx = if on_static { idx } else { insert_count - 1 - idx };
+                    Encoder::encode_insert_refer_name(&mut encoded, on_static, name_idx, header.get_value())?;
+                } else {
+                    Encoder::encode_insert_both_literal(&mut encoded, &header)?;
+                }
+                emit(EncodedChunk::EncoderInstruction, &encoded);
+                encoded.clear();
+                free_capacity -= header.size();
+                inserted.push(header.clone());
+                (true, false, insert_count)
+            } else {
+                find_index_results[i]
+            };
+
+            let (_, on_static, abs_idx) = find_index_result;
+            if !on_static && abs_idx != usize::MAX {
+                dynamic_table_indices.push(abs_idx);
+                required_insert_count = required_insert_count.max(abs_idx + 1);
+            }
+            Qpack::encode_field_line(&mut encoded, header, find_index_result, required_insert_count, base)?;
+            emit(EncodedChunk::FieldLine, &encoded);
+        }
+
+        let mut encoded = vec![];
+        if required_insert_count == 0 {
+            Encoder::prefix(&mut encoded, &self.table, 0, false, 0);
+        } else {
+            Encoder::prefix(&mut encoded, &self.table, required_insert_count as u32,
+                            base < required_insert_count, base as u32);
+        }
+        emit(EncodedChunk::Prefix, &encoded);
+        self.check_acknowledgment_lag(inserted.len());
+
+        let encoder = Arc::clone(&self.encoder);
+        let dynamic_table = Arc::clone(&self.table.dynamic_table);
+        Ok(Box::new(move || -> Result<(), Box<dyn error::Error>> {
+            let mut write_lock = dynamic_table.write().unwrap();
+            if write_lock.eviction_count != eviction_count || write_lock.get_insert_count() != base {
+                return Err(TableChanged.into());
+            }
+            let count = inserted.len();
+            inserted.into_iter().try_for_each(|header| write_lock.insert_header(header))?;
+            let mut encoder = encoder.write().unwrap();
+            encoder.known_sending_count += count;
+            if !dynamic_table_indices.is_empty() {
+                dynamic_table_indices.iter().try_for_each(|idx| write_lock.ref_entry_at(*idx))?;
+                encoder.add_section(stream_id, required_insert_count, dynamic_table_indices);
+            }
             Ok(())
         }))
     }
 
-    fn block_decoding(&self, required_insert_count: usize) -> Result<(), Box<dyn error::Error>> {
-        if self.blocked_streams_limit < self.decoder.read().unwrap().current_blocked_streams + 1 {
-            return Err(DecompressionFailed.into());
+    fn block_decoding(&self, stream_id: StreamId, required_insert_count: usize) -> Result<(), Box<dyn error::Error>> {
+        if self.max_blocked_streams() < self.decoder.read().unwrap().current_blocked_streams + 1 {
+            return Err(DecompressionFailed::new(DecompressReason::BlockedStreamsLimitExceeded, 0).into());
+        }
+        let key = (stream_id, self.next_blocked_token.fetch_add(1, Ordering::Relaxed));
+        self.blocked_stream_cancels.lock().unwrap().insert(key, false);
+        let blocked_stream = BlockedStreamGuard::new(&self.decoder);
+        if let Some(on_block) = &self.on_block {
+            on_block(stream_id, required_insert_count);
         }
-        self.decoder.write().unwrap().current_blocked_streams += 1;
 
         let (mux, cv) = &*self.cv_insert_count;
 
         let locked_insert_count = mux.lock().unwrap();
-        let _ = cv.wait_while(locked_insert_count, |locked_insert_count| *locked_insert_count < required_insert_count).unwrap();
-        self.decoder.write().unwrap().current_blocked_streams -= 1;
+        let still_blocked = |locked_insert_count: &mut usize| *locked_insert_count < required_insert_count
+            && !self.blocked_stream_cancels.lock().unwrap().get(&key).copied().unwrap_or(true);
+        let timed_out = match self.blocked_stream_timeout {
+            Some(timeout) => cv.wait_timeout_while(locked_insert_count, timeout, still_blocked).unwrap().1.timed_out(),
+            None => {
+                drop(cv.wait_while(locked_insert_count, still_blocked).unwrap());
+                false
+            },
+        };
+        // blocked stream is no longer counted on drop of the guard
+        if self.blocked_stream_cancels.lock().unwrap().remove(&key) == Some(true) {
+            return Err(DecompressionFailed::new(DecompressReason::BlockedStreamCancelled, 0).into());
+        }
+        if timed_out {
+            return Err(DecompressionFailed::new(DecompressReason::BlockedStreamTimeout, 0).into());
+        }
+        drop(blocked_stream);
+        if let Some(on_unblock) = &self.on_unblock {
+            on_unblock(stream_id);
+        }
         Ok(())
     }
-    pub fn decode_headers(&self, wire: &Vec<u8>, stream_id: u16) -> Result<(Vec<Header>, bool), Box<dyn error::Error>> {
+    // Wakes the decodings blocked on the stream to fail with BlockedStreamCancelled, e.g. when the
+    // request stream is reset. Returns false if the stream is not blocked
+    pub fn cancel_blocked_stream(&self, stream_id: StreamId) -> bool {
+        let mut cancels = self.blocked_stream_cancels.lock().unwrap();
+        let mut found = false;
+        for (_, cancelled) in cancels.iter_mut().filter(|((id, _), _)| *id == stream_id) {
+            *cancelled = true;
+            found = true;
+        }
+        drop(cancels);
+        if !found {
+            return false;
+        }
+        // under the lock so that the waiting one never misses the notification between its check and wait
+        let (mux, cv) = &*self.cv_insert_count;
+        let _locked_insert_count = mux.lock().unwrap();
+        cv.notify_all();
+        true
+    }
+    // Decodes a field section from a request stream. Encoder instructions passed here by mistake are
+    // not detected, e.g. Set Dynamic Table Capacity (0b001xxxxx) is read as the Required Insert Count.
+    // Use decode_headers_from to have the stream type checked in strict mode.
+    pub fn decode_headers(&self, wire: &[u8], stream_id: StreamId) -> Result<DecodeResult, Box<dyn error::Error>> {
+        let (headers, action, _) = self.decode_section(wire, stream_id)?;
+        Ok(DecodeResult { headers, action })
+    }
+    // Same as decode_headers, but with the Required Insert Count in the prefix
+    pub fn decode_headers_detailed(&self, wire: &[u8], stream_id: StreamId) -> Result<DecodedSection, Box<dyn error::Error>> {
+        let (headers, action, required_insert_count) = self.decode_section(wire, stream_id)?;
+        Ok(DecodedSection { headers, required_insert_count, referenced_dynamic: action.requires_ack() })
+    }
+    // Decodes a field section of len bytes at the head of wire, e.g. the payload of a HEADERS frame
+    // in a buffer holding the following frames too. Returns the number of bytes consumed as well
+    pub fn decode_headers_with_len(&self, wire: &[u8], len: usize, stream_id: StreamId)
+            -> Result<(DecodeResult, usize), Box<dyn error::Error>> {
+        if wire.len() < len {
+            return Err(DecompressionFailed::new(DecompressReason::Truncated, wire.len()).into());
+        }
+        Ok((self.decode_headers(&wire[..len], stream_id)?, len))
+    }
+    // Same as decode_headers, but returns names and values as raw bytes, huffman decoded but not
+    // validated as UTF-8. For proxies forwarding the bytes as they are
+    pub fn decode_headers_bytes(&self, wire: &[u8], stream_id: StreamId) -> Result<Vec<HeaderBytes>, Box<dyn error::Error>> {
+        Ok(self.decode_section(wire, stream_id)?.0)
+    }
+    // For intermediaries forwarding a field section unchanged. The section is decoded only to be
+    // validated and acknowledged later, so that the prefix is kept exactly as received
+    pub fn validate_and_forward<'a>(&self, wire: &'a [u8], stream_id: StreamId) -> Result<&'a [u8], Box<dyn error::Error>> {
+        self.decode_headers_bytes(wire, stream_id)?;
+        Ok(wire)
+    }
+    // Same as decode_headers, but names are shared through the pool set by set_name_interning,
+    // for servers keeping many headers of the same names
+    pub fn decode_headers_interned(&self, wire: &[u8], stream_id: StreamId)
+            -> Result<(Vec<InternedHeader>, DecoderAction), Box<dyn error::Error>> {
+        let (headers, action, _) = self.decode_section::<Header>(wire, stream_id)?;
+        let mut pool = self.name_pool.as_ref().map(|pool| pool.lock().unwrap());
+        let headers = headers.into_iter().map(|header| {
+            let name = match pool.as_mut() {
+                Some(pool) => pool.intern(&header.get_name().value),
+                None => Arc::from(header.get_name().value.as_str()),
+            };
+            InternedHeader { name, sensitive: header.sensitive, value: header.move_value().value }
+        }).collect();
+        Ok((headers, action))
+    }
+    // returns Required Insert Count as well
+    fn decode_section<F: FieldLine>(&self, wire: &[u8], stream_id: StreamId) -> Result<(Vec<F>, DecoderAction, usize), Box<dyn error::Error>> {
         let mut idx = 0;
         let (len, required_insert_count, base) = Decoder::prefix(wire, idx, &self.table)?;
         idx += len;
         let required_insert_count = required_insert_count as usize;
+        if required_insert_count == 0 {
+            // static table and literals only. dynamic table is not locked at all,
+            // as any reference to it fails before looking it up
+            let (headers, _) = self.decode_field_lines(&self.table, wire, idx, base, required_insert_count)?;
+            return Ok((headers, DecoderAction::None, required_insert_count));
+        }
 
         // blocked if dynamic_table.insert_count < requred_insert_count
         // OPTIMIZE: blocked just before referencing dynamic_table is better?
-        let insert_count = self.table.get_insert_count();
-        if insert_count < required_insert_count {
-            self.block_decoding(required_insert_count)?;
+        let blocked = self.table.get_insert_count() < required_insert_count;
+        if blocked {
+            self.block_decoding(stream_id, required_insert_count)?;
         }
 
+        let (headers, ref_dynamic) = self.decode_field_lines(&self.table, wire, idx, base, required_insert_count)?;
+        // ?
+        // TODO: move to commit func?
+        self.decoder.write().unwrap().add_section(stream_id, required_insert_count);
+        let action = match (ref_dynamic, blocked) {
+            (false, _) => DecoderAction::None,
+            (true, false) => DecoderAction::SendAck,
+            (true, true) => DecoderAction::AlreadyUnblocked,
+        };
+        Ok((headers, action, required_insert_count))
+    }
+    // Same as decode_headers, but fails in strict mode if wire is not from a request or push stream.
+    // Encoder instructions can't be told from a field section by their bytes (Set Capacity 0b001xxxxx
+    // reads as a prefix, then as a literal field line), so the check relies on stream_type.
+    pub fn decode_headers_from(&self, wire: &[u8], stream_id: StreamId, stream_type: StreamType)
+            -> Result<DecodeResult, Box<dyn error::Error>> {
+        if self.strict && !matches!(stream_type, StreamType::Request | StreamType::Push) {
+            return Err(DecompressionFailed::new(DecompressReason::NotFieldSection, 0).into());
+        }
+        self.decode_headers(wire, stream_id)
+    }
+    // NOT FOR PRODUCTION, debug builds only. Decodes field lines against the given base instead of the one
+    // in the prefix, to see which headers the section would result in when diagnosing a desync.
+    // Blocks on the stream as decode_headers, but the section is not registered for acknowledgment.
+    #[cfg(any(test, debug_assertions))]
+    pub fn decode_headers_with_base_override(&self, wire: &[u8], stream_id: StreamId, base: usize)
+            -> Result<Vec<Header>, Box<dyn error::Error>> {
+        let (len, required_insert_count, _) = Decoder::prefix(wire, 0, &self.table)?;
+        let required_insert_count = required_insert_count as usize;
+        if self.table.get_insert_count() < required_insert_count {
+            self.block_decoding(stream_id, required_insert_count)?;
+        }
+        Ok(self.decode_field_lines(&self.table, wire, len, base, required_insert_count)?.0)
+    }
+    // Decodes a field section against the snapshot instead of the live table, e.g. to analyze captured
+    // traffic offline. Never blocks, and neither the snapshot nor the decoder state is changed.
+    // A section needing more insertions than the snapshot has fails with InvalidRequiredInsertCount
+    pub fn decode_headers_against(&self, wire: &[u8], snapshot: &DynamicTableSnapshot) -> Result<Vec<Header>, Box<dyn error::Error>> {
+        let (len, required_insert_count, base) = Decoder::prefix(wire, 0, &snapshot.table)?;
+        let required_insert_count = required_insert_count as usize;
+        if snapshot.get_insert_count() < required_insert_count {
+            // snapshot taken before the insertions, it would block forever
+            return Err(DecompressionFailed::new(DecompressReason::InvalidRequiredInsertCount, 0).into());
+        }
+        Ok(self.decode_field_lines(&snapshot.table, wire, len, base, required_insert_count)?.0)
+    }
+    pub fn snapshot_dynamic_table(&self) -> DynamicTableSnapshot {
+        DynamicTableSnapshot { table: self.table.snapshot() }
+    }
+    fn decode_field_lines<F: FieldLine>(&self, table: &Table, wire: &[u8], mut idx: usize, base: usize, required_insert_count: usize)
+            -> Result<(Vec<F>, bool), Box<dyn error::Error>> {
         let mut headers = vec![];
         let wire_len = wire.len();
         let mut ref_dynamic = false;
+        let mut size = 0;
         while idx < wire_len {
-            let ret = if wire[idx] & FieldType::INDEXED == FieldType::INDEXED {
-                Decoder::decode_indexed(wire, &mut idx, base, required_insert_count, &self.table)?
+            let ret: (F, bool) = if wire[idx] & FieldType::INDEXED == FieldType::INDEXED {
+                Decoder::decode_indexed(wire, &mut idx, base, required_insert_count, table)?
             } else if wire[idx] & FieldType::REFER_NAME == FieldType::REFER_NAME {
-                Decoder::decode_refer_name(wire, &mut idx, base, required_insert_count, &self.table)?
+                Decoder::decode_refer_name(wire, &mut idx, base, required_insert_count, table)?
             } else if wire[idx] & FieldType::BOTH_LITERAL == FieldType::BOTH_LITERAL {
                 Decoder::decode_both_literal(wire, &mut idx)?
             } else if wire[idx] & FieldType::INDEXED_POST_BASE == FieldType::INDEXED_POST_BASE {
-                Decoder::decode_indexed_post_base(wire, &mut idx, base, required_insert_count, &self.table)?
+                Decoder::decode_indexed_post_base(wire, &mut idx, base, required_insert_count, table)?
             } else if wire[idx] & 0b11110000 == FieldType::REFER_NAME_POST_BASE {
-                Decoder::decode_refer_name_post_base(wire, &mut idx, base, required_insert_count, &self.table)?
+                Decoder::decode_refer_name_post_base(wire, &mut idx, base, required_insert_count, table)?
             } else {
-                return Err(DecompressionFailed.into());
+                return Err(DecompressionFailed::new(DecompressReason::UnknownFieldType, idx).into());
             };
+            // checked as decoded, not to allocate more for a hostile encoder
+            size += ret.0.size();
+            if let Some(limit) = self.max_header_list_size.filter(|limit| *limit < size) {
+                return Err(TooLarge { size, limit }.into());
+            }
             headers.push(ret.0);
             ref_dynamic |= ret.1;
         }
-        // ?
-        // TODO: move to commit func?
-        if required_insert_count != 0 {
-            self.decoder.write().unwrap().add_section(stream_id, required_insert_count);
-        }
         Ok((headers, ref_dynamic))
     }
-    pub fn decode_encoder_instruction(&self, wire: &Vec<u8>)
+    // Trailers are a field section sent after the content, and must not contain pseudo headers
+    pub fn encode_trailers(&self, encoded: &mut Vec<u8>, trailers: Vec<Header>, stream_id: StreamId)
+            -> Result<CommitFunc, Box<dyn error::Error>> {
+        if trailers.iter().any(|header| header.get_name().value.starts_with(':')) {
+            return Err(InvalidHeader("pseudo header in trailers").into());
+        }
+        self.encode_headers(encoded, trailers, stream_id)
+    }
+    pub fn decode_trailers(&self, wire: &[u8], stream_id: StreamId) -> Result<DecodeResult, Box<dyn error::Error>> {
+        let out = self.decode_headers(wire, stream_id)?;
+        if out.headers.iter().any(|header| header.get_name().value.starts_with(':')) {
+            return Err(InvalidHeader("pseudo header in trailers").into());
+        }
+        Ok(out)
+    }
+    pub fn decode_encoder_instruction(&self, wire: &[u8])
             -> Result<CommitFunc, Box<dyn error::Error>> {
         let mut idx = 0;
         let wire_len = wire.len();
         let mut commit_funcs = vec![];
+        let truncated = |e| stream_error_if_truncated(e, || EncoderStreamError("truncated instruction").into());
 
         while idx < wire_len {
             idx += if wire[idx] & encoder::Instruction::INSERT_REFER_NAME == encoder::Instruction::INSERT_REFER_NAME {
-                let (output, input) = Decoder::decode_insert_refer_name(wire, idx)?;
+                let (output, input) = Decoder::decode_insert_refer_name(wire, idx).map_err(truncated)?;
                 commit_funcs.push(self.table.insert_refer_name(input.0, input.1, input.2)?);
                 output
             } else if wire[idx] & encoder::Instruction::INSERT_BOTH_LITERAL == encoder::Instruction::INSERT_BOTH_LITERAL {
-                let (output, input) = Decoder::decode_insert_both_literal(wire, idx)?;
+                let (output, input) = Decoder::decode_insert_both_literal(wire, idx).map_err(truncated)?;
                 commit_funcs.push(self.table.insert_both_literal(input)?);
                 output
             } else if wire[idx] & encoder::Instruction::SET_DYNAMIC_TABLE_CAPACITY == encoder::Instruction::SET_DYNAMIC_TABLE_CAPACITY {
-                let (output, input) = Decoder::decode_dynamic_table_capacity(wire, idx)?;
+                let (output, input) = Decoder::decode_dynamic_table_capacity(wire, idx).map_err(truncated)?;
+                if self.table.get_max_capacity() < input {
+                    return Err(EncoderStreamError(CAPACITY_EXCEEDS_MAX).into());
+                }
                 commit_funcs.push(self.table.set_dynamic_table_capacity(input)?);
                 output
             } else { // if wire[idx] & encoder::Instruction::DUPLICATE == encoder::Instruction::DUPLICATE
-                let (output, input) = Decoder::decode_duplicate(wire, idx)?;
+                let (output, input) = Decoder::decode_duplicate(wire, idx).map_err(truncated)?;
                 commit_funcs.push(self.table.duplicate(input)?);
                 output
             };
@@ -280,15 +1033,54 @@ impl Qpack {
         }))
     }
 
-    pub fn decode_decoder_instruction(&self, wire: &Vec<u8>)
+    // Checks all the instructions in wire without building commit funcs
+    pub fn validate_encoder_instruction(&self, wire: &[u8]) -> Result<(), Box<dyn error::Error>> {
+        let mut idx = 0;
+        // entries inserted by preceding instructions in wire can be referred. eviction is not considered
+        let mut num_entries = self.table.get_dynamic_table_entry_len();
+        while idx < wire.len() {
+            let len = match Decoder::encoder_instruction_len(wire, idx) {
+                Some(len) => len,
+                None => return Err(EncoderStreamError("truncated instruction").into()),
+            };
+            if wire[idx] & encoder::Instruction::INSERT_REFER_NAME == encoder::Instruction::INSERT_REFER_NAME {
+                let (_, (name_idx, _, on_static)) = Decoder::decode_insert_refer_name(wire, idx)?;
+                if (on_static && self.table.get_header_from_static(name_idx).is_err()) || (!on_static && num_entries <= name_idx) {
+                    return Err(EncoderStreamError("invalid name index").into());
+                }
+                num_entries += 1;
+            } else if wire[idx] & encoder::Instruction::INSERT_BOTH_LITERAL == encoder::Instruction::INSERT_BOTH_LITERAL {
+                Decoder::decode_insert_both_literal(wire, idx)?;
+                num_entries += 1;
+            } else if wire[idx] & encoder::Instruction::SET_DYNAMIC_TABLE_CAPACITY == encoder::Instruction::SET_DYNAMIC_TABLE_CAPACITY {
+                let (_, capacity) = Decoder::decode_dynamic_table_capacity(wire, idx)?;
+                if self.table.get_max_capacity() < capacity {
+                    return Err(EncoderStreamError(CAPACITY_EXCEEDS_MAX).into());
+                }
+            } else {
+                let (_, dup_idx) = Decoder::decode_duplicate(wire, idx)?;
+                if num_entries <= dup_idx {
+                    return Err(EncoderStreamError("invalid duplicate index").into());
+                }
+                num_entries += 1;
+            }
+            idx += len;
+        }
+        Ok(())
+    }
+
+    pub fn decode_decoder_instruction(&self, wire: &[u8])
             -> Result<CommitFunc, Box<dyn error::Error>> {
         let mut idx = 0;
         let wire_len = wire.len();
         let mut commit_funcs = vec![];
+        let truncated = |e| stream_error_if_truncated(e, || DecoderStreamError.into());
+        // increments in wire are applied on commit at once, so validated altogether
+        let mut total_increment = 0;
 
         while idx < wire_len {
             idx += if wire[idx] & decoder::Instruction::SECTION_ACKNOWLEDGMENT == decoder::Instruction::SECTION_ACKNOWLEDGMENT {
-                let (len, stream_id) = Encoder::decode_section_ackowledgment(wire, idx)?;
+                let (len, stream_id) = Encoder::decode_section_ackowledgment(wire, idx).map_err(truncated)?;
                 if !self.encoder.read().unwrap().has_section(stream_id) {
                     // $4.4.1 section has already been acked
                     return Err(DecoderStreamError.into());
@@ -296,12 +1088,17 @@ impl Qpack {
                 commit_funcs.push(self.table.section_ackowledgment(Arc::clone(&self.encoder), stream_id)?);
                 len
             } else if wire[idx] & decoder::Instruction::STREAM_CANCELLATION == decoder::Instruction::STREAM_CANCELLATION {
-                let (len, stream_id) = Encoder::decode_stream_cancellation(wire, idx)?;
+                let (len, stream_id) = Encoder::decode_stream_cancellation(wire, idx).map_err(truncated)?;
+                if !self.encoder.read().unwrap().has_section(stream_id) {
+                    return Err(DecoderStreamError.into());
+                }
                 commit_funcs.push(self.table.stream_cancellation(Arc::clone(&self.encoder), stream_id)?);
                 len
             } else { // wire[idx] & Instruction::INSERT_COUNT_INCREMENT == Instruction::INSERT_COUNT_INCREMENT
-                let (len, increment) = Encoder::decode_insert_count_increment(wire, idx)?;
-                if increment == 0 || self.encoder.read().unwrap().known_sending_count < self.table.dynamic_table.read().unwrap().known_received_count + increment {
+                let (len, increment) = Encoder::decode_insert_count_increment(wire, idx).map_err(truncated)?;
+                let known_received_count = self.table.get_known_received_count();
+                total_increment += increment;
+                if increment == 0 || self.encoder.read().unwrap().known_sending_count < known_received_count + total_increment {
                     // 4.4.3 invalid value
                     return Err(DecoderStreamError.into());
                 }
@@ -319,21 +1116,263 @@ impl Qpack {
     pub fn dump_dynamic_table(&self) {
         self.table.dump_dynamic_table();
     }
+    // dump only count entries from absolute index start_abs, for tables too large to print whole
+    pub fn dump_dynamic_table_range(&self, start_abs: usize, count: usize, w: &mut dyn io::Write) -> io::Result<()> {
+        self.table.dump_dynamic_table_range(start_abs, count, w)
+    }
+    // Encoder instructions reproducing the current dynamic table on a fresh decoder, e.g. on reconnection.
+    // Fails with ReplayAfterEviction once any entry is evicted, as the Insert Count of the fresh decoder
+    // would fall behind by the evicted entries and every later reference would be off.
+    // The commit func changes nothing as the encoder already has the table.
+    pub fn encoder_stream_replay(&self) -> Result<(Vec<u8>, CommitFunc), Box<dyn error::Error>> {
+        let eviction_count = self.table.get_eviction_count();
+        if 0 < eviction_count {
+            return Err(ReplayAfterEviction(eviction_count).into());
+        }
+        let mut encoded = vec![];
+        Encoder::encode_set_dynamic_table_capacity(&mut encoded, self.table.get_capacity())?;
+        for header in self.table.get_dynamic_table_headers() {
+            Encoder::encode_insert_both_literal(&mut encoded, &header)?;
+        }
+        Ok((encoded, Box::new(|| Ok(()))))
+    }
+    // partitions decoded headers into (pseudo headers, regular headers)
+    pub fn split_pseudo(headers: Vec<Header>) -> Result<(Vec<Header>, Vec<Header>), Box<dyn error::Error>> {
+        let mut pseudo = vec![];
+        let mut regular = vec![];
+        for header in headers {
+            if header.get_name().value.starts_with(':') {
+                if !regular.is_empty() {
+                    return Err(InvalidHeader("pseudo header after regular header").into());
+                }
+                pseudo.push(header);
+            } else {
+                regular.push(header);
+            }
+        }
+        Ok((pseudo, regular))
+    }
+    // Section Acknowledgment acknowledges the oldest outstanding section of the stream (# 4.4.1).
+    // Fails with DecoderStreamError if the section of required_insert_count is not the one
+    pub fn validate_section_acknowledgment_order(&self, stream_id: StreamId, required_insert_count: usize)
+            -> Result<(), Box<dyn error::Error>> {
+        let encoder = self.encoder.read().unwrap();
+        match encoder.pending_sections.get(&stream_id).and_then(|sections| sections.front()) {
+            Some((oldest, _)) if *oldest == required_insert_count => Ok(()),
+            _ => Err(DecoderStreamError.into()),
+        }
+    }
+    pub fn pending_sections_snapshot(&self) -> PendingSections {
+        PendingSections {
+            encoder: self.encoder.read().unwrap().pending_sections.clone(),
+            decoder: self.decoder.read().unwrap().pending_sections.clone(),
+        }
+    }
+    // Compares dynamic table of encoder and decoder which should hold the same entries after every
+    // encoder instruction is committed. Returns the first difference, None if in sync
+    pub fn find_desync(&self, other: &Qpack) -> Option<String> {
+        self.table.find_desync(&other.table)
+    }
+    #[cfg(test)]
+    pub fn assert_synced(&self, other: &Qpack) {
+        if let Some(desync) = self.find_desync(other) {
+            panic!("dynamic tables are out of sync. {}", desync);
+        }
+    }
 }
 
-struct FieldType;
-impl FieldType {
-    // 4.5.2
-    // 0   1   2   3   4   5   6   7
-    // +---+---+---+---+---+---+---+---+
-    // | 1 | T |      Index (6+)       |
-    // +---+---+-----------------------+
-    pub const INDEXED: u8 = 0b10000000;
-    // 4.5.3
-    // 0   1   2   3   4   5   6   7
-    // +---+---+---+---+---+---+---+---+
-    // | 0 | 0 | 0 | 1 |  Index (4+)   |
-    // +---+---+---+---+---------------+
+impl Default for Qpack {
+    // 16 blocked streams and 4096 bytes of dynamic table as common HTTP/3 deployments advertise
+    // in SETTINGS_QPACK_BLOCKED_STREAMS and SETTINGS_QPACK_MAX_TABLE_CAPACITY
+    fn default() -> Self {
+        Qpack::with_config(QpackConfig::default())
+    }
+}
+
+// parameters given on construction, mostly from SETTINGS
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct QpackConfig {
+    // SETTINGS_QPACK_BLOCKED_STREAMS
+    pub blocked_streams_limit: u16,
+    // SETTINGS_QPACK_MAX_TABLE_CAPACITY
+    pub max_table_capacity: usize,
+    // SETTINGS_MAX_FIELD_SECTION_SIZE. larger field sections fail to encode or decode with TooLarge
+    pub max_header_list_size: Option<usize>,
+    // false to encode by static table and literals only
+    pub allow_dynamic_table: bool,
+    // blocked decoding fails with BlockedStreamTimeout after this long, so that a stalled encoder stream
+    // does not hold the thread forever
+    pub blocked_stream_timeout: Option<Duration>,
+    pub encoder: EncoderConfig,
+}
+impl Default for QpackConfig {
+    // same as Qpack::default
+    fn default() -> Self {
+        Self {
+            blocked_streams_limit: 16,
+            max_table_capacity: 4096,
+            max_header_list_size: None,
+            allow_dynamic_table: true,
+            blocked_stream_timeout: None,
+            encoder: EncoderConfig::default(),
+        }
+    }
+}
+
+// constraints on what encoder refers and inserts, in exchange for compression
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EncoderConfig {
+    // refer only entries keeping Required Insert Count within this many entries beyond Known Received Count.
+    // Some(0) refers only acknowledged entries so that decoder never blocks. None to refer any entry
+    pub max_required_insert_count_ahead: Option<usize>,
+    // false to keep sensitive headers out of the dynamic table even by encode_insert_headers
+    pub insert_sensitive: bool,
+    // field lines referring the dynamic table per section. the rest fall back to static table or literal
+    pub max_dynamic_references: Option<usize>,
+}
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            max_required_insert_count_ahead: None,
+            insert_sensitive: true,
+            max_dynamic_references: None,
+        }
+    }
+}
+impl EncoderConfig {
+    // decoder never blocks on field sections encoded by encode_headers, and a lost acknowledgment
+    // or eviction affects only a few field lines
+    pub fn conservative() -> Self {
+        Self {
+            max_required_insert_count_ahead: Some(0),
+            insert_sensitive: false,
+            max_dynamic_references: Some(8),
+        }
+    }
+}
+
+// counts of committed encodings which gave up the dynamic table for the constraints
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct EncoderMetrics {
+    // field lines not referring an entry as it was not acknowledged yet
+    pub unacknowledged_fallbacks: usize,
+    // field lines beyond max_dynamic_references
+    pub reference_cap_fallbacks: usize,
+    // sensitive headers dropped by encode_insert_headers
+    pub sensitive_not_inserted: usize,
+}
+impl EncoderMetrics {
+    fn add(&mut self, other: &EncoderMetrics) {
+        self.unacknowledged_fallbacks += other.unacknowledged_fallbacks;
+        self.reference_cap_fallbacks += other.reference_cap_fallbacks;
+        self.sensitive_not_inserted += other.sensitive_not_inserted;
+    }
+}
+
+// Entries in the oldest quarter of the dynamic table may be evicted before
+// the section referring them is acknowledged. Frequent warnings mean the table is too small.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EvictionRiskPolicy {
+    Ignore,
+    // passes the entry to the callback set by set_on_eviction_risk
+    Warn,
+    Error,
+}
+
+// which strings of literals are huffman encoded, regardless of flags of each header
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HuffmanPolicy {
+    None,
+    NamesOnly,
+    ValuesOnly,
+    Both,
+    // huffman encode only if it is shorter than raw
+    Auto,
+}
+
+// kind of bytes passed by encode_headers_streaming
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EncodedChunk {
+    // to be written to the encoder stream
+    EncoderInstruction,
+    // to be written to the request stream after the Prefix
+    FieldLine,
+    // beginning of the field section, emitted after all the field lines
+    Prefix,
+}
+
+// follow-up required to the caller after decoding a field section
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DecoderAction {
+    // static table and literals only. nothing to send
+    None,
+    // dynamic table is referred. send Section Acknowledgment (# 4.4.1)
+    SendAck,
+    // blocked until the referred entries were inserted, and on_unblock has been called.
+    // Section Acknowledgment is required as well
+    AlreadyUnblocked,
+}
+impl DecoderAction {
+    pub fn requires_ack(&self) -> bool {
+        *self != DecoderAction::None
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DecodeResult {
+    pub headers: Vec<Header>,
+    pub action: DecoderAction,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DecodedSection {
+    pub headers: Vec<Header>,
+    pub required_insert_count: usize,
+    // Section Acknowledgment is to be sent if true
+    pub referenced_dynamic: bool,
+}
+
+// counts a blocked stream while alive, so that the count is restored on any exit path
+struct BlockedStreamGuard<'a> {
+    decoder: &'a RwLock<Decoder>,
+}
+impl<'a> BlockedStreamGuard<'a> {
+    fn new(decoder: &'a RwLock<Decoder>) -> Self {
+        decoder.write().unwrap().current_blocked_streams += 1;
+        Self { decoder }
+    }
+}
+impl Drop for BlockedStreamGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut decoder) = self.decoder.write() {
+            decoder.current_blocked_streams -= 1;
+        }
+    }
+}
+
+// field line representation chosen by the encoder
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Representation {
+    Indexed { from_static: bool },
+    IndexedPostBase,
+    ReferName { from_static: bool },
+    ReferNamePostBase,
+    BothLiteral,
+}
+
+struct FieldType;
+impl FieldType {
+    // 4.5.2
+    // 0   1   2   3   4   5   6   7
+    // +---+---+---+---+---+---+---+---+
+    // | 1 | T |      Index (6+)       |
+    // +---+---+-----------------------+
+    pub const INDEXED: u8 = 0b10000000;
+    // 4.5.3
+    // 0   1   2   3   4   5   6   7
+    // +---+---+---+---+---+---+---+---+
+    // | 0 | 0 | 0 | 1 |  Index (4+)   |
+    // +---+---+---+---+---------------+
     pub const INDEXED_POST_BASE: u8 = 0b00010000;
     // 4.5.4
     // 0   1   2   3   4   5   6   7
@@ -369,24 +1408,170 @@ impl FieldType {
     pub const BOTH_LITERAL: u8 = 0b00100000;
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DecompressReason {
+    // Required Insert Count in the prefix cannot be reconstructed
+    InvalidRequiredInsertCount,
+    // Base in the prefix goes below 0
+    InvalidBase,
+    InvalidStaticIndex,
+    // refers entry not in the dynamic table or beyond Required Insert Count
+    InvalidDynamicIndex,
+    // wire ends in the middle of an integer or a string
+    Truncated,
+    // prefix integer does not fit in 64 bits
+    IntegerOverflow,
+    InvalidHuffman,
+    // string literal is not valid UTF-8
+    InvalidUtf8,
+    UnknownFieldType,
+    BlockedStreamsLimitExceeded,
+    // insertions required did not arrive within blocked_stream_timeout
+    BlockedStreamTimeout,
+    // cancelled by cancel_blocked_stream while blocked
+    BlockedStreamCancelled,
+    // bytes read from a stream other than a request or push stream
+    NotFieldSection,
+}
+
+// stream which bytes are read from
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StreamType {
+    Request,
+    // server push stream, carries field sections as a request stream does
+    Push,
+    Encoder,
+    Decoder,
+}
+
 #[derive(Debug)]
-struct DecompressionFailed; // TODO: represent 0x0200
-impl error::Error for DecompressionFailed {}
+pub struct DecompressionFailed {
+    pub reason: DecompressReason,
+    // offset in the wire of the failed prefix field, field line or string literal
+    pub offset: usize,
+    source: Option<Box<dyn error::Error>>,
+}
+impl DecompressionFailed {
+    pub fn new(reason: DecompressReason, offset: usize) -> Self {
+        Self { reason, offset, source: None }
+    }
+    pub fn with_source(reason: DecompressReason, offset: usize, source: Box<dyn error::Error>) -> Self {
+        Self { reason, offset, source: Some(source) }
+    }
+    // set offset to the error raised by where the wire is not visible (e.g. table lookup)
+    fn at(err: Box<dyn error::Error>, offset: usize) -> Box<dyn error::Error> {
+        match err.downcast::<DecompressionFailed>() {
+            Ok(mut failed) => {
+                failed.offset = offset;
+                failed
+            },
+            Err(err) => err,
+        }
+    }
+}
+impl error::Error for DecompressionFailed {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source.as_deref()
+    }
+}
 impl fmt::Display for DecompressionFailed {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "Decompression Failed")
+		write!(f, "Decompression Failed: {:?} at {}", self.reason, self.offset)
+	}
+}
+#[derive(Debug)]
+pub struct InvalidHeader(pub &'static str);
+impl error::Error for InvalidHeader {}
+impl fmt::Display for InvalidHeader {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Invalid Header: {}", self.0)
+	}
+}
+#[derive(Debug)]
+pub struct EvictionRisk(pub usize); // absolute index of the entry
+impl error::Error for EvictionRisk {}
+impl fmt::Display for EvictionRisk {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Eviction Risk: entry Abs:{} may be evicted before acknowledgment", self.0)
+	}
+}
+#[derive(Debug)]
+pub struct TableChanged; // encoded field section refers to evicted entries. encode again
+impl error::Error for TableChanged {}
+impl fmt::Display for TableChanged {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Dynamic table changed between encoding and commit")
+	}
+}
+#[derive(Debug)]
+pub struct ReplayAfterEviction(pub usize); // number of entries evicted so far
+impl error::Error for ReplayAfterEviction {}
+impl fmt::Display for ReplayAfterEviction {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Dynamic table cannot be replayed after evicting {} entries", self.0)
+	}
+}
+#[derive(Debug)]
+pub struct TooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+impl error::Error for TooLarge {}
+impl fmt::Display for TooLarge {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Too Large: field section is {} bytes, exceeding the limit {}", self.size, self.limit)
+	}
+}
+#[derive(Debug)]
+pub struct InvalidBase {
+    pub base: usize,
+    pub insert_count: usize,
+}
+impl error::Error for InvalidBase {}
+impl fmt::Display for InvalidBase {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Invalid Base: {} is beyond Insert Count {}", self.base, self.insert_count)
+	}
+}
+// encoder side bug, referring an entry the decoder cannot resolve by the prefix
+#[derive(Debug)]
+pub struct InvalidReference {
+    pub idx: usize,
+    pub required_insert_count: usize,
+}
+impl error::Error for InvalidReference {}
+impl fmt::Display for InvalidReference {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Invalid Reference: absolute index {} is not below Required Insert Count {}", self.idx, self.required_insert_count)
+	}
+}
+#[derive(Debug)]
+pub struct InvalidSettings(pub &'static str); // TODO: represent H3_SETTINGS_ERROR 0x0109
+impl error::Error for InvalidSettings {}
+impl fmt::Display for InvalidSettings {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Invalid Settings: {}", self.0)
 	}
 }
+const CAPACITY_EXCEEDS_MAX: &str = "capacity exceeds SETTINGS_QPACK_MAX_TABLE_CAPACITY";
 #[derive(Debug)]
-struct EncoderStreamError; // TODO: represent 0x0201
+pub struct EncoderStreamError(pub &'static str);
 impl error::Error for EncoderStreamError {}
 impl fmt::Display for EncoderStreamError {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "Encoder Stream Error")
+		write!(f, "Encoder Stream Error: {}", self.0)
 	}
 }
+// instruction ending in the middle is an error of the stream, not of a field section
+fn stream_error_if_truncated(err: Box<dyn error::Error>, stream_error: impl FnOnce() -> Box<dyn error::Error>)
+        -> Box<dyn error::Error> {
+    match err.downcast_ref::<DecompressionFailed>() {
+        Some(failed) if failed.reason == DecompressReason::Truncated => stream_error(),
+        _ => err,
+    }
+}
 #[derive(Debug)]
-struct DecoderStreamError; // TODO: represent 0x0202
+pub(crate) struct DecoderStreamError;
 impl error::Error for DecoderStreamError {}
 impl fmt::Display for DecoderStreamError {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -394,13 +1579,52 @@ impl fmt::Display for DecoderStreamError {
 	}
 }
 
+// connection errors of QPACK (# 6). the connection is closed with the code
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum QpackError {
+    DecompressionFailed,
+    EncoderStreamError,
+    DecoderStreamError,
+}
+impl QpackError {
+    pub fn code(&self) -> u64 {
+        match self {
+            QpackError::DecompressionFailed => 0x0200,
+            QpackError::EncoderStreamError => 0x0201,
+            QpackError::DecoderStreamError => 0x0202,
+        }
+    }
+    // kind of the error returned by Qpack methods. None for errors not closing the connection,
+    // e.g. TableChanged or TooLarge
+    pub fn of(err: &(dyn error::Error + 'static)) -> Option<QpackError> {
+        if err.is::<DecompressionFailed>() {
+            Some(QpackError::DecompressionFailed)
+        } else if err.is::<EncoderStreamError>() {
+            Some(QpackError::EncoderStreamError)
+        } else if err.is::<DecoderStreamError>() {
+            Some(QpackError::DecoderStreamError)
+        } else {
+            None
+        }
+    }
+}
+impl error::Error for QpackError {}
+impl fmt::Display for QpackError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?} (0x{:04x})", self, self.code())
+	}
+}
+
 #[cfg(test)]
 mod tests {
     use core::time;
-    use std::{error, sync::Arc, thread};
-    use crate::{Header, Qpack, types::HeaderString};
+    use std::{error, sync::{Arc, Mutex}, thread};
+    use crate::transformer::decoder::Decoder;
+    use crate::transformer::encoder::Encoder;
+    use crate::transformer::huffman::HUFFMAN_TRANSFORMER;
+    use crate::{DecodeResult, DecodedSection, DecoderAction, DecompressReason, DecompressionFailed, DecoderStreamDecoder, EncodedChunk, EncoderConfig, EncoderMetrics, EncoderStreamDecoder, EncoderStreamError, EvictionRisk, EvictionRiskPolicy, Header, HeaderBytes, HuffmanPolicy, InvalidBase, InvalidHeader, InvalidReference, Qpack, QpackConfig, QpackError, ReplayAfterEviction, Representation, StreamType, TableChanged, TooLarge, types::{CommitFunc, HeaderString, StrHeader, StreamId}};
 
-    static STREAM_ID: u16 = 4;
+    static STREAM_ID: StreamId = StreamId::new(4);
     fn get_request_headers(remove_value: bool) -> Vec<Header> {
         let mut headers = vec![
             Header::from_str(":authority", "example.com"),
@@ -450,22 +1674,15 @@ mod tests {
         headers
     }
 
-    fn commit(func: Result<Box<dyn FnOnce() -> Result<(), Box<dyn error::Error>>>,
-                            Box<dyn error::Error>>) {
+    fn commit(func: Result<CommitFunc, Box<dyn error::Error>>) {
         match func {
             Ok(ok) => {
-                match ok() {
-                    Ok(_) => (),
-                    Err(e) => {
-                        println!("{:?}", e);
-                        assert!(false);
-                        ()
-                    },
+                if let Err(e) = ok() {
+                    panic!("{:?}", e);
                 }
             },
             Err(e) => {
-                println!("{:?}", e);
-                assert!(false);
+                panic!("{:?}", e);
             },
         }
     }
@@ -478,24 +1695,22 @@ mod tests {
         commit(commit_func);
     }
     fn insert_headers(client: &Qpack, server: &Qpack, headers: Vec<Header>) {
-        if !client.is_insertable(&headers) {
-            assert!(false);
-        }
+        assert!(client.is_insertable(&headers));
         let mut encoded = vec![];
         let commit_func = client.encode_insert_headers(&mut encoded, headers);
         commit(commit_func);
         let commit_func = server.decode_encoder_instruction(&encoded);
         commit(commit_func);
     }
-    fn send_headers(client: &Qpack, server: &Qpack, headers: Vec<Header>, stream_id: u16) -> bool {
+    fn send_headers(client: &Qpack, server: &Qpack, headers: Vec<Header>, stream_id: StreamId) -> bool {
         let mut encoded = vec![];
         let commit_func = client.encode_headers(&mut encoded, headers.clone(), stream_id);
         commit(commit_func);
         let out = server.decode_headers(&encoded, stream_id).unwrap();
-        assert_eq!(headers, out.0);
-        out.1
+        assert_eq!(headers, out.headers);
+        out.action.requires_ack()
     }
-    fn section_ackowledgment(client: &Qpack, server: &Qpack, stream_id: u16) {
+    fn section_ackowledgment(client: &Qpack, server: &Qpack, stream_id: StreamId) {
         let mut encoded = vec![];
         let commit_func = server.encode_section_ackowledgment(&mut encoded, stream_id);
         commit(commit_func);
@@ -530,6 +1745,30 @@ mod tests {
         assert!(!refer_dynamic_table);
     }
 
+    #[test]
+    fn static_only_section_without_table_lock() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let request_headers = get_request_headers(false);
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, request_headers.clone(), STREAM_ID);
+        commit(commit_func);
+        assert_eq!(encoded[..2], [0x00, 0x00]);
+
+        // decodes while another thread holds the write lock of the dynamic table
+        let qpack_decoder = Arc::new(qpack_decoder);
+        let decoder = Arc::clone(&qpack_decoder);
+        let locked = qpack_decoder.table.dynamic_table.write().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let th = thread::spawn(move || {
+            let _ = tx.send(decoder.decode_headers(&encoded, STREAM_ID).unwrap());
+        });
+        let out = rx.recv_timeout(time::Duration::from_secs(1)).unwrap();
+        drop(locked);
+        let _ = th.join();
+        assert_eq!(out, DecodeResult { headers: request_headers, action: DecoderAction::None });
+        assert!(qpack_decoder.pending_sections_snapshot().decoder.is_empty());
+    }
+
     #[test]
     fn simple_get_huffman() {
         let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
@@ -575,6 +1814,112 @@ mod tests {
 
     }
 
+    #[test]
+    fn nul_in_value() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let mut headers = vec![Header::from_str("custom-key", "before\0after"),
+                               Header::from_str(":path", "\0"),
+                               Header::from_str("custom\0key", "\0\0")];
+        assert!(!send_headers(&qpack_encoder, &qpack_decoder, headers.clone(), STREAM_ID));
+        headers.iter_mut().for_each(|header| header.set_huffman((true, true)));
+        assert!(!send_headers(&qpack_encoder, &qpack_decoder, headers.clone(), STREAM_ID));
+        insert_send_ack(&qpack_encoder, &qpack_decoder, headers, false);
+        qpack_encoder.assert_synced(&qpack_decoder);
+    }
+
+    #[test]
+    fn refer_name_huffman_value_flag() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "custom-value")]);
+        // names come from static and dynamic table, values are on the wire
+        let mut headers = vec![Header::from_str(":authority", "www.example.com"),
+                               Header::from_str("custom-key", "other-value")];
+        for huffman in [true, false] {
+            headers.iter_mut().for_each(|header| header.set_huffman((false, huffman)));
+            let mut encoded = vec![];
+            let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+            commit(commit_func);
+            let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+            assert_eq!(out.headers, headers);
+            for header in out.headers {
+                assert_eq!(header.get_name().huffman, Some(false));
+                assert_eq!(header.get_value().huffman, Some(huffman));
+            }
+        }
+    }
+
+    #[test]
+    fn strict_empty_name() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        qpack_encoder.set_strict(true);
+        let headers = vec![Header::from_str(":path", "/"), Header::from_str("", "value")];
+        let mut encoded = vec![];
+        let err = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID).err().unwrap();
+        assert!(err.downcast_ref::<InvalidHeader>().is_some());
+        assert!(encoded.is_empty());
+        // encoder instructions are not restricted
+        insert_headers(&qpack_encoder, &qpack_decoder, headers);
+        qpack_encoder.assert_synced(&qpack_decoder);
+    }
+
+    #[test]
+    fn split_pseudo() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = get_request_headers(false);
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        let (pseudo, regular) = Qpack::split_pseudo(out.headers).unwrap();
+        assert_eq!(pseudo, headers[..4].to_vec());
+        assert_eq!(regular, headers[4..].to_vec());
+
+        let headers = vec![Header::from_str("accept", "*/*"), Header::from_str(":path", "/")];
+        let err = Qpack::split_pseudo(headers).unwrap_err();
+        assert!(err.downcast_ref::<InvalidHeader>().is_some());
+    }
+
+    #[test]
+    fn huffman_auto_per_string() {
+        let cookie = "KADUSERCOOKIE=DC83629A-1466-4455-ADA8-C50384E85218; PUBMDCID=1; chkChromeAb67Sec=2; PugT=1627625159";
+        let token = "Q+XZ+JK+VY+XZ+J=";
+        assert!(HUFFMAN_TRANSFORMER.encoded_len(cookie) < cookie.len());
+        assert!(token.len() < HUFFMAN_TRANSFORMER.encoded_len(token));
+
+        for (value, huffman) in [(cookie, true), (token, false)] {
+            let mut header = Header::from_str("x-custom", value);
+            header.set_huffman_auto();
+            let mut encoded = vec![];
+            Encoder::encode_insert_both_literal(&mut encoded, &header).unwrap();
+            let name_len = 1 + HUFFMAN_TRANSFORMER.encoded_len("x-custom");
+            assert!(encoded[0] & 0b00100000 > 0); // H bit of name
+            assert_eq!(encoded[name_len] & 0b10000000 > 0, huffman); // H bit of value
+            let value_len = if huffman { HUFFMAN_TRANSFORMER.encoded_len(value) } else { value.len() };
+            assert_eq!(encoded.len(), name_len + 1 + value_len);
+        }
+    }
+    #[test]
+    fn huffman_policy() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let mut header = Header::from_str("custom-key", "\0\0\0");
+        header.set_huffman((true, true));
+        for (policy, expected) in [(HuffmanPolicy::None, (false, false)),
+                                   (HuffmanPolicy::NamesOnly, (true, false)),
+                                   (HuffmanPolicy::ValuesOnly, (false, true)),
+                                   (HuffmanPolicy::Both, (true, true)),
+                                   (HuffmanPolicy::Auto, (true, false))] {
+            qpack_encoder.set_huffman_policy(Some(policy));
+            let mut encoded = vec![];
+            let commit_func = qpack_encoder.encode_headers(&mut encoded, vec![header.clone()], STREAM_ID);
+            commit(commit_func);
+            // H bit of name in literal with literal name
+            assert_eq!(encoded[2] & 0b00001000 > 0, expected.0);
+            let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+            assert_eq!(out.headers, vec![header.clone()]);
+            assert_eq!((out.headers[0].get_name().huffman, out.headers[0].get_value().huffman), (Some(expected.0), Some(expected.1)));
+        }
+    }
+
     #[test]
     fn insert_simple_headers() {
         let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 4096);
@@ -596,6 +1941,64 @@ mod tests {
         assert!(refer_dynamic_table);
     }
 
+    #[test]
+    fn refer_name_post_base_huffman_sensitive() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 4096);
+        // section referring the oldest entries mostly, which is shorter post-base
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("x-request-id", "0")]);
+        let fillers: Vec<Header> = (0..70)
+            .map(|i| Header::from_string(format!("key{}", i), format!("value{}", i)))
+            .collect();
+        insert_headers(&qpack_encoder, &qpack_decoder, fillers.clone());
+        let mut header = Header::new("x-request-id".to_string(), "5f4b7d2c-d3a1-4e3b".to_string(), true);
+        header.set_huffman((false, true));
+        let headers = vec![header, fillers[0].clone(), fillers[1].clone(), fillers[69].clone()];
+
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        assert_eq!(encoded[1] & 0b10000000, 0b10000000); // S bit
+        assert_eq!(encoded[2] & 0b11110000, 0b00000000);
+        assert_eq!(encoded[2] & 0b00001000, 0b00001000); // N bit
+        assert_eq!(encoded[3] & 0b10000000, 0b10000000); // H bit
+
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert!(out.action.requires_ack());
+        assert_eq!(out.headers, headers);
+        assert!(out.headers[0].sensitive);
+    }
+
+    #[test]
+    fn post_base_chosen_only_when_shorter() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 4096);
+        let entries: Vec<Header> = (0..71)
+            .map(|i| Header::from_string(format!("key{}", i), format!("value{}", i)))
+            .collect();
+        insert_headers(&qpack_encoder, &qpack_decoder, entries.clone());
+        let referred = [0, 1, 2, 70];
+        let headers: Vec<Header> = referred.iter().map(|i| entries[*i].clone()).collect();
+
+        // Required Insert Count 71, Base 71 (delta 0) or Base 0 (delta 70)
+        let pre_base_len = Encoder::delta_base_len(0)
+            + referred.iter().map(|i| Encoder::field_line_index_len(70 - i, true, false)).sum::<usize>();
+        let post_base_len = Encoder::delta_base_len(70)
+            + referred.iter().map(|i| Encoder::field_line_index_len(*i, true, true)).sum::<usize>();
+        assert_eq!((pre_base_len, post_base_len), (8, 6));
+
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        assert_eq!(encoded.len(), 1 + post_base_len);
+        assert_eq!(encoded[1], 0b10000000 | 70); // S bit
+        assert_eq!(qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap().headers, headers);
+
+        // pre-base is kept when post-base is not shorter
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, vec![entries[0].clone(), entries[70].clone()], STREAM_ID);
+        commit(commit_func);
+        assert_eq!(encoded[1], 0);
+    }
+
     fn insert_send_recv_many_prep(num: usize) -> Vec<Header> {
         let mut headers = vec![];
         headers.push(Header::from_str("", ""));
@@ -606,8 +2009,8 @@ mod tests {
             let mut base_value = header.get_value().value.clone();
 
             for j in 0..26 {
-                base_name.push(('a' as u8 + j) as char);
-                base_value.push(('a' as u8 + j) as char);
+                base_name.push((b'a' + j) as char);
+                base_value.push((b'a' + j) as char);
                 headers.push(Header::from_str(&base_name, &base_value));
                 base_name.pop();
                 base_value.pop();
@@ -635,7 +2038,7 @@ mod tests {
         let mut headers = insert_send_recv_many_prep(num);
 
         let mut batch_size = 1;
-        while 0 != headers.len() {
+        while !headers.is_empty() {
             let boundary = if batch_size <= headers.len() {batch_size} else {headers.len()};
             let request_headers = headers[..boundary].to_vec();
             headers = headers[boundary..].to_vec();
@@ -658,14 +2061,1081 @@ mod tests {
         let headers = get_request_headers(true);
         insert_headers(&client, &server, headers);
         let headers = get_request_headers(false);
-        let refer_dynamic_table = send_headers(&client, &server, headers);
         let refer_dynamic_table = send_headers(&client, &server, headers, STREAM_ID);
         assert!(refer_dynamic_table);
     }
 
+    #[test]
+    fn encoder_stream_decoder_byte_by_byte() {
+        let qpack_encoder = Qpack::new(1, 1024);
+        let qpack_decoder = Qpack::new(1, 1024);
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_set_dynamic_table_capacity(&mut encoded, 220);
+        commit(commit_func);
+        let headers = vec![Header::from_str(":authority", "www.example.com"),
+                           Header::from_str(":path", "/sample/path")];
+        let commit_func = qpack_encoder.encode_insert_headers(&mut encoded, headers);
+        commit(commit_func);
+
+        let mut stream = EncoderStreamDecoder::new(&qpack_decoder);
+        let mut num_instructions = 0;
+        for byte in encoded.iter() {
+            let commit_funcs = stream.push(&[*byte]).unwrap();
+            num_instructions += commit_funcs.len();
+            commit_funcs.into_iter().for_each(|f| commit(Ok(f)));
+        }
+        assert_eq!(num_instructions, 3);
+        assert_eq!(stream.pending_len(), 0);
+        assert_eq!(qpack_decoder.table.get_insert_count(), 2);
+        qpack_encoder.assert_synced(&qpack_decoder);
+    }
+
+    #[test]
+    fn encoder_stream_decoder_every_split() {
+        let mut qpack_encoder = Qpack::new(1, 1024);
+        qpack_encoder.set_prefer_duplicate(true);
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_set_dynamic_table_capacity(&mut encoded, 1024));
+        // both literal and static name reference, then dynamic name reference and duplicate
+        commit(qpack_encoder.encode_insert_headers(&mut encoded, vec![Header::from_str("custom-key", "custom-value"),
+                                                                      Header::from_str(":authority", "www.example.com")]));
+        commit(qpack_encoder.encode_insert_headers(&mut encoded, vec![Header::from_str("custom-key", "custom-value2"),
+                                                                      Header::from_str("custom-key", "custom-value")]));
+        assert_eq!(encoded[encoded.len() - 1], 0x02);
+
+        for offset in 0..=encoded.len() {
+            let qpack_decoder = Qpack::new(1, 1024);
+            let mut stream = EncoderStreamDecoder::new(&qpack_decoder);
+            for chunk in [&encoded[..offset], &encoded[offset..]] {
+                stream.push(chunk).unwrap().into_iter().for_each(|f| commit(Ok(f)));
+            }
+            assert_eq!(stream.pending_len(), 0);
+            qpack_encoder.assert_synced(&qpack_decoder);
+            assert_eq!(qpack_decoder.snapshot_dynamic_table().get_headers(),
+                       vec![Header::from_str("custom-key", "custom-value"),
+                            Header::from_str(":authority", "www.example.com"),
+                            Header::from_str("custom-key", "custom-value2"),
+                            Header::from_str("custom-key", "custom-value")]);
+        }
+    }
+
+    #[test]
+    fn decoder_stream_decoder_split() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = vec![Header::from_str(":authority", "www.example.com"),
+                           Header::from_str(":path", "/sample/path")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, STREAM_ID));
+        let mut decoder_stream = vec![];
+        let commit_func = qpack_decoder.encode_section_ackowledgment(&mut decoder_stream, STREAM_ID);
+        commit(commit_func);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "custom-value")]);
+        let commit_func = qpack_decoder.encode_insert_count_increment(&mut decoder_stream);
+        commit(commit_func);
+        assert_eq!(decoder_stream, vec![0x84, 0x01]);
+
+        let mut stream = DecoderStreamDecoder::new(&qpack_encoder);
+        for chunk in [&decoder_stream[..0], &decoder_stream[..1], &decoder_stream[1..]] {
+            let commit_funcs = stream.push(chunk).unwrap();
+            assert_eq!(commit_funcs.len(), chunk.len());
+            commit_funcs.into_iter().for_each(|f| commit(Ok(f)));
+        }
+        assert_eq!(stream.pending_len(), 0);
+        assert!(!qpack_encoder.encoder.read().unwrap().has_section(STREAM_ID));
+        assert_eq!(qpack_encoder.table.dynamic_table.read().unwrap().known_received_count, 3);
+
+        // Section Acknowledgment with 2 bytes stream id waits for the second byte
+        let mut encoded = vec![];
+        let _ = qpack_decoder.encode_section_ackowledgment(&mut encoded, StreamId::new(200));
+        assert_eq!(stream.push(&encoded[..1]).unwrap().len(), 0);
+        assert_eq!(stream.pending_len(), 1);
+    }
+
+    #[test]
+    fn no_block_unacknowledged_entry() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        qpack_encoder.set_no_block(true);
+        let headers = vec![Header::from_str("custom-key", "custom-value"),
+                           Header::from_str(":authority", "www.example.com")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        // Required Insert Count 0, literal with literal name, literal with static name reference
+        assert_eq!(&encoded[..4], &[0x00, 0x00, 0x27, 0x03]);
+        assert_eq!(encoded[27], 0x50);
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, headers);
+        assert_eq!(out.action, DecoderAction::None);
+
+        // refers dynamic table once acknowledged
+        let mut encoded = vec![];
+        let commit_func = qpack_decoder.encode_insert_count_increment(&mut encoded);
+        commit(commit_func);
+        let commit_func = qpack_encoder.decode_decoder_instruction(&encoded);
+        commit(commit_func);
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, STREAM_ID));
+    }
+
+    #[test]
+    fn max_required_insert_count_ahead() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        qpack_encoder.set_max_required_insert_count_ahead(Some(0));
+        let acked = Header::from_str("custom-key", "custom-value");
+        let unacked = Header::from_str("custom-key2", "custom-value2");
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![acked.clone()]);
+        let mut encoded = vec![];
+        let commit_func = qpack_decoder.encode_insert_count_increment(&mut encoded);
+        commit(commit_func);
+        let commit_func = qpack_encoder.decode_decoder_instruction(&encoded);
+        commit(commit_func);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![unacked.clone()]);
+
+        let headers = vec![acked, unacked];
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers.clone(), STREAM_ID));
+        // only the acknowledged entry is referred
+        assert_eq!(qpack_encoder.pending_sections_snapshot().encoder[&STREAM_ID], vec![(1, vec![0])]);
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+
+        qpack_encoder.set_max_required_insert_count_ahead(Some(1));
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, STREAM_ID));
+        assert_eq!(qpack_encoder.pending_sections_snapshot().encoder[&STREAM_ID], vec![(2, vec![0, 1])]);
+    }
+
+    #[test]
+    fn pending_sections_snapshot() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(2, 1024);
+        let headers1 = vec![Header::from_str("custom-key", "custom-value")];
+        let headers2 = vec![Header::from_str("custom-key2", "custom-value2")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers1.clone());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers1, StreamId::new(4)));
+        insert_headers(&qpack_encoder, &qpack_decoder, headers2.clone());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers2, StreamId::new(8)));
+
+        let snapshot = qpack_encoder.pending_sections_snapshot();
+        assert_eq!(snapshot.encoder.len(), 2);
+        assert_eq!(snapshot.encoder[&StreamId::new(4)], vec![(1, vec![0])]);
+        assert_eq!(snapshot.encoder[&StreamId::new(8)], vec![(2, vec![1])]);
+        let snapshot = qpack_decoder.pending_sections_snapshot();
+        assert_eq!(snapshot.decoder.len(), 2);
+        assert_eq!(snapshot.decoder[&StreamId::new(4)], vec![1]);
+        assert_eq!(snapshot.decoder[&StreamId::new(8)], vec![2]);
+
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, StreamId::new(4));
+        assert!(!qpack_encoder.pending_sections_snapshot().encoder.contains_key(&StreamId::new(4)));
+        assert!(!qpack_decoder.pending_sections_snapshot().decoder.contains_key(&StreamId::new(4)));
+    }
+
+    #[test]
+    fn acknowledgment_beyond_sections() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, STREAM_ID));
+        // the second one finds no section when committed
+        let err = qpack_encoder.decode_decoder_instruction(&[0x84, 0x84]).unwrap()().unwrap_err();
+        assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::DecoderStreamError));
+        // nor the decoder acknowledges more than decoded
+        let mut encoded = vec![];
+        commit(qpack_decoder.encode_section_ackowledgment(&mut encoded, STREAM_ID));
+        let err = qpack_decoder.encode_section_ackowledgment(&mut encoded, STREAM_ID).unwrap()().unwrap_err();
+        assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::DecoderStreamError));
+    }
+
+    #[test]
+    fn truncated_instructions() {
+        let qpack = Qpack::new(1, 1024);
+        // each ends in the middle of the integer of Insert With Name Reference, Insert With Literal Name,
+        // Set Dynamic Table Capacity and Duplicate
+        for wire in [[0xff], [0x7f], [0x3f], [0x1f]] {
+            let err = qpack.decode_encoder_instruction(&wire).err().unwrap();
+            assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::EncoderStreamError), "{:?}", wire);
+        }
+        // Section Acknowledgment, Stream Cancellation and Insert Count Increment
+        for wire in [[0xff], [0x7f], [0x3f]] {
+            let err = qpack.decode_decoder_instruction(&wire).err().unwrap();
+            assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::DecoderStreamError), "{:?}", wire);
+        }
+    }
+
+    #[test]
+    fn section_acknowledgment_order() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(2, 1024);
+        let headers1 = vec![Header::from_str("custom-key", "custom-value")];
+        let headers2 = vec![Header::from_str("custom-key2", "custom-value2")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers1.clone());
+        insert_headers(&qpack_encoder, &qpack_decoder, headers2.clone());
+        // headers and trailers on the same stream
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers1, STREAM_ID));
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers2, STREAM_ID));
+        assert_eq!(qpack_encoder.pending_sections_snapshot().encoder[&STREAM_ID], vec![(1, vec![0]), (2, vec![1])]);
+        assert_eq!(qpack_decoder.pending_sections_snapshot().decoder[&STREAM_ID], vec![1, 2]);
+
+        // the second section acknowledged first
+        let err = qpack_encoder.validate_section_acknowledgment_order(STREAM_ID, 2).unwrap_err();
+        assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::DecoderStreamError));
+        qpack_encoder.validate_section_acknowledgment_order(STREAM_ID, 1).unwrap();
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+        assert_eq!(qpack_encoder.pending_sections_snapshot().encoder[&STREAM_ID], vec![(2, vec![1])]);
+        qpack_encoder.validate_section_acknowledgment_order(STREAM_ID, 2).unwrap();
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+        assert!(qpack_encoder.validate_section_acknowledgment_order(STREAM_ID, 2).is_err());
+        assert!(qpack_encoder.pending_sections_snapshot().encoder.is_empty());
+        assert!(qpack_decoder.pending_sections_snapshot().decoder.is_empty());
+    }
+
+    #[test]
+    fn compression_ratio() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 4096);
+        let request_headers = get_request_headers(false);
+        let cold_ratio = qpack_encoder.compression_ratio(&request_headers).unwrap().unwrap();
+        insert_headers(&qpack_encoder, &qpack_decoder, request_headers.clone());
+        let primed_ratio = qpack_encoder.compression_ratio(&request_headers).unwrap().unwrap();
+        assert!(primed_ratio < 1.0);
+        assert!(primed_ratio < cold_ratio);
+        // estimation must not register a section
+        assert!(qpack_encoder.pending_sections_snapshot().encoder.is_empty());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, request_headers, STREAM_ID));
+        // nothing to compress
+        assert_eq!(qpack_encoder.compression_ratio(&[]).unwrap(), None);
+        assert_eq!(qpack_encoder.compression_ratio(&[Header::from_str("", "")]).unwrap(), None);
+    }
+
+    #[test]
+    fn recommended_capacity() {
+        let request_headers = get_request_headers(false);
+        let capacity = Qpack::recommended_capacity(&request_headers);
+        assert!(request_headers.iter().map(|header| header.size()).sum::<usize>() < capacity);
+
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, capacity);
+        insert_headers(&qpack_encoder, &qpack_decoder, request_headers.clone());
+        assert_eq!(qpack_encoder.table.get_dynamic_table_entry_len(), request_headers.len());
+        assert_eq!(qpack_encoder.table.get_eviction_count(), 0);
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, request_headers, STREAM_ID));
+    }
+
+    #[test]
+    fn encode_headers_streaming() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let mut headers = get_request_headers(false);
+        insert_headers(&qpack_encoder, &qpack_decoder, headers[..2].to_vec());
+        headers.push(Header::from_str("custom-key", "custom-value"));
+        headers.push(Header::from_str("custom-key", "custom-value"));
+        headers.push(Header::new("authorization".to_string(), "secret".to_string(), true));
+
+        let mut encoder_stream = vec![];
+        let mut prefix = vec![];
+        let mut field_lines = vec![];
+        let commit_func = qpack_encoder.encode_headers_streaming(headers.clone(), STREAM_ID, |chunk, bytes| {
+            match chunk {
+                EncodedChunk::EncoderInstruction => encoder_stream.extend_from_slice(bytes),
+                EncodedChunk::FieldLine => field_lines.extend_from_slice(bytes),
+                EncodedChunk::Prefix => {
+                    assert!(prefix.is_empty());
+                    prefix.extend_from_slice(bytes);
+                },
+            }
+        });
+        commit(commit_func);
+        assert!(!encoder_stream.is_empty());
+        let commit_func = qpack_decoder.decode_encoder_instruction(&encoder_stream);
+        commit(commit_func);
+        qpack_encoder.assert_synced(&qpack_decoder);
+
+        prefix.extend_from_slice(&field_lines);
+        let out = qpack_decoder.decode_headers(&prefix, STREAM_ID).unwrap();
+        assert!(out.action.requires_ack());
+        assert_eq!(out.headers, headers);
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+
+        // every header is now in the table, nothing more to insert
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers_streaming(headers.clone(), STREAM_ID, |chunk, bytes| {
+            assert_ne!(chunk, EncodedChunk::EncoderInstruction);
+            if chunk == EncodedChunk::Prefix {
+                encoded.splice(0..0, bytes.iter().cloned());
+            } else {
+                encoded.extend_from_slice(bytes);
+            }
+        });
+        commit(commit_func);
+        let mut expected = vec![];
+        let _ = qpack_encoder.encode_headers(&mut expected, headers.clone(), STREAM_ID);
+        assert_eq!(qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap(),
+                   qpack_decoder.decode_headers(&expected, STREAM_ID).unwrap());
+    }
+
+    #[test]
+    fn insert_on_repeat() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        qpack_encoder.set_insert_on_repeat(8);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_insert_headers(&mut encoded, headers.clone());
+        commit(commit_func);
+        assert!(encoded.is_empty());
+        assert_eq!(qpack_encoder.table.get_insert_count(), 0);
+        assert!(!send_headers(&qpack_encoder, &qpack_decoder, headers.clone(), STREAM_ID));
+
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        assert_eq!(qpack_encoder.table.get_insert_count(), 1);
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, STREAM_ID));
+    }
+
+    #[test]
+    fn insert_throttle() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(2, 1024);
+        qpack_encoder.set_insert_throttle(Some(1));
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, STREAM_ID));
+        assert_eq!(qpack_encoder.blocking_streams(), 1);
+
+        // only 1 of 2 blocked streams is left
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_insert_headers(&mut encoded, vec![Header::from_str("custom-key2", "custom-value2")]);
+        commit(commit_func);
+        assert!(encoded.is_empty());
+        assert_eq!(qpack_encoder.table.get_insert_count(), 1);
+
+        // budget is back once acknowledged
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+        assert_eq!(qpack_encoder.blocking_streams(), 0);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key2", "custom-value2")]);
+        assert_eq!(qpack_encoder.table.get_insert_count(), 2);
+    }
+
+    #[test]
+    fn trailers() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let trailers = vec![Header::from_str("grpc-status", "0"), Header::from_str("grpc-message", "OK")];
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_trailers(&mut encoded, trailers.clone(), STREAM_ID);
+        commit(commit_func);
+        let out = qpack_decoder.decode_trailers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, trailers);
+
+        let mut encoded = vec![];
+        let invalid = vec![Header::from_str("grpc-status", "0"), Header::from_str(":status", "200")];
+        let err = qpack_encoder.encode_trailers(&mut encoded, invalid.clone(), STREAM_ID).err().unwrap();
+        assert!(err.downcast_ref::<InvalidHeader>().is_some());
+        assert!(encoded.is_empty());
+
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, invalid, STREAM_ID);
+        commit(commit_func);
+        let err = qpack_decoder.decode_trailers(&encoded, STREAM_ID).unwrap_err();
+        assert!(err.downcast_ref::<InvalidHeader>().is_some());
+    }
+
+    #[test]
+    fn decode_headers_with_base_override() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = vec![Header::from_str("custom-key", "custom-value"), Header::from_str("custom-key2", "custom-value2")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+
+        let (_, _, base) = Decoder::prefix(&encoded, 0, &qpack_decoder.table).unwrap();
+        let out = qpack_decoder.decode_headers_with_base_override(&encoded, STREAM_ID, base).unwrap();
+        assert_eq!(out, qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap().headers);
+        // a wrong base refers other entries, or none
+        assert_ne!(qpack_decoder.decode_headers_with_base_override(&encoded, STREAM_ID, base + 1).ok(), Some(out));
+    }
+
+    #[test]
+    fn decode_headers_against_snapshot() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 256);
+        let headers = vec![Header::from_str("custom-key", "custom-value"), Header::from_str("custom-key2", "custom-value2")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        let snapshot = qpack_decoder.snapshot_dynamic_table();
+        let mut captured = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut captured, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        let live = qpack_decoder.decode_headers(&captured, STREAM_ID).unwrap().headers;
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+
+        // live table goes on evicting the entries captured section refers
+        for i in 0..4 {
+            insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_string(format!("other-key{}", i), "other-value".to_string())]);
+        }
+        assert!(qpack_decoder.decode_headers_with_base_override(&captured, STREAM_ID, 2).is_err());
+        let pending_sections = qpack_decoder.pending_sections_snapshot();
+        assert_eq!(qpack_decoder.decode_headers_against(&captured, &snapshot).unwrap(), live);
+        assert_eq!(qpack_decoder.pending_sections_snapshot(), pending_sections);
+        assert_eq!(snapshot.get_insert_count(), 2);
+        assert_eq!(snapshot.get_headers(), headers);
+
+        // refers entries inserted after the snapshot
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, vec![Header::from_str("other-key3", "other-value")], STREAM_ID);
+        commit(commit_func);
+        let err = qpack_decoder.decode_headers_against(&encoded, &snapshot).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::InvalidRequiredInsertCount);
+    }
+
+    #[test]
+    fn header_wire_size() {
+        let header = Header::from_str("accept-encoding", "gzip, deflate, br");
+        assert_eq!(header.wire_size(false), 15 + 17);
+        assert!(header.wire_size(true) < header.wire_size(false));
+        // huffman never changes the size accounted in the dynamic table
+        assert_eq!(header.size(), header.wire_size(false) + 32);
+
+        let mut huffman_header = header.clone();
+        huffman_header.set_huffman((true, true));
+        assert_eq!(huffman_header.size(), header.size());
+        let mut encoded = vec![];
+        Encoder::encode_insert_both_literal(&mut encoded, &huffman_header).unwrap();
+        // 1 byte length prefix for each string
+        assert_eq!(encoded.len(), header.wire_size(true) + 2);
+    }
+
+    #[test]
+    fn decode_headers_from_encoder_stream() {
+        let (qpack_encoder, mut qpack_decoder) = gen_client_server_instances(1, 4096);
+        let mut encoded = vec![];
+        let _ = qpack_encoder.encode_set_dynamic_table_capacity(&mut encoded, 220);
+        assert_eq!(encoded[0] & 0b11100000, 0b00100000);
+
+        qpack_decoder.set_strict(true);
+        let err = qpack_decoder.decode_headers_from(&encoded, STREAM_ID, StreamType::Encoder).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::NotFieldSection);
+        assert!(qpack_decoder.pending_sections_snapshot().decoder.is_empty());
+
+        let headers = get_request_headers(false);
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        let out = qpack_decoder.decode_headers_from(&encoded, STREAM_ID, StreamType::Request).unwrap();
+        assert_eq!(out.headers, headers);
+    }
+
+    #[test]
+    fn acknowledgment_lag() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 4096);
+        let lags = Arc::new(Mutex::new(vec![]));
+        let lags_clone = Arc::clone(&lags);
+        qpack_encoder.set_on_acknowledgment_lag(3, move |lag| lags_clone.lock().unwrap().push(lag));
+
+        for i in 0..5 {
+            insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", &format!("value{}", i))]);
+        }
+        assert_eq!(qpack_encoder.acknowledgment_lag(), 5);
+        assert_eq!(*lags.lock().unwrap(), vec![4, 5]);
+
+        let mut encoded = vec![];
+        let commit_func = qpack_decoder.encode_insert_count_increment(&mut encoded);
+        commit(commit_func);
+        let commit_func = qpack_encoder.decode_decoder_instruction(&encoded);
+        commit(commit_func);
+        assert_eq!(qpack_encoder.acknowledgment_lag(), 0);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "value5")]);
+        assert_eq!(lags.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn encode_headers_within() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = get_request_headers(false);
+        let mut expected = vec![];
+        let _ = qpack_encoder.encode_headers(&mut expected, headers.clone(), STREAM_ID);
+
+        let err = qpack_encoder.encode_headers_within(headers.clone(), STREAM_ID, expected.len() - 1).err().unwrap();
+        let too_large = err.downcast_ref::<TooLarge>().unwrap();
+        assert_eq!(too_large.size, expected.len());
+        assert_eq!(too_large.limit, expected.len() - 1);
+
+        let (encoded, commit_func) = qpack_encoder.encode_headers_within(headers.clone(), STREAM_ID, expected.len()).unwrap();
+        commit_func().unwrap();
+        assert_eq!(encoded, expected);
+        assert_eq!(qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap().headers, headers);
+    }
+
+    #[test]
+    fn encoder_stream_replay() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        insert_headers(&qpack_encoder, &qpack_decoder, get_request_headers(false)[4..10].to_vec());
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("accept", "*/*")]);
+
+        let (encoded, commit_func) = qpack_encoder.encoder_stream_replay().unwrap();
+        commit(Ok(commit_func));
+        let fresh_decoder = Qpack::new(1, 1024);
+        assert!(fresh_decoder.validate_encoder_instruction(&encoded).is_ok());
+        let commit_func = fresh_decoder.decode_encoder_instruction(&encoded);
+        commit(commit_func);
+        qpack_encoder.assert_synced(&fresh_decoder);
+        qpack_decoder.assert_synced(&fresh_decoder);
+    }
+
+    #[test]
+    fn decode_headers_bytes() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 4096);
+        let mut headers = get_request_headers(false);
+        insert_headers(&qpack_encoder, &qpack_decoder, headers[..6].to_vec());
+        headers.iter_mut().skip(10).for_each(|header| header.set_huffman((true, true)));
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        let out = qpack_decoder.decode_headers_bytes(&encoded, STREAM_ID).unwrap();
+        let expected: Vec<HeaderBytes> = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap().headers.iter()
+            .map(|header| (header.get_name().value.as_bytes().to_vec(), header.get_value().value.as_bytes().to_vec()))
+            .collect();
+        assert_eq!(out, expected);
+
+        // literal with literal name, value is not UTF-8
+        let encoded = vec![0x00, 0x00, 0x23, b'a', b'b', b'c', 0x02, 0xff, 0xfe];
+        assert!(qpack_decoder.decode_headers(&encoded, STREAM_ID).is_err());
+        let out = qpack_decoder.decode_headers_bytes(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out, vec![(b"abc".to_vec(), vec![0xff, 0xfe])]);
+    }
+
+    #[test]
+    fn with_config() {
+        let qpack = Qpack::with_config(QpackConfig::default());
+        let default = Qpack::default();
+        assert_eq!(qpack.max_blocked_streams(), default.max_blocked_streams());
+        assert_eq!(qpack.table.get_max_capacity(), default.table.get_max_capacity());
+        let qpack = Qpack::with_config(QpackConfig { blocked_streams_limit: 1, max_table_capacity: 1024, ..QpackConfig::default() });
+        let new = Qpack::new(1, 1024);
+        assert_eq!((qpack.max_blocked_streams(), qpack.table.get_max_capacity()), (new.max_blocked_streams(), new.table.get_max_capacity()));
+        assert_eq!((qpack.max_header_list_size, qpack.allow_dynamic_table), (new.max_header_list_size, new.allow_dynamic_table));
+        assert_eq!((new.max_header_list_size, new.allow_dynamic_table), (None, true));
+
+        // static table and literals only
+        let qpack_encoder = Qpack::with_config(QpackConfig { allow_dynamic_table: false, ..QpackConfig::default() });
+        let qpack_decoder = Qpack::default();
+        set_table_capacity(&qpack_encoder, &qpack_decoder, 4096);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut encoded, headers.clone()));
+        assert!(encoded.is_empty());
+        let mut chunks = vec![];
+        commit(qpack_encoder.encode_headers_streaming(headers.clone(), STREAM_ID, |chunk, _| chunks.push(chunk)));
+        assert!(!chunks.contains(&EncodedChunk::EncoderInstruction));
+        assert!(!send_headers(&qpack_encoder, &qpack_decoder, headers.clone(), STREAM_ID));
+
+        let qpack = Qpack::with_config(QpackConfig { max_header_list_size: Some(headers[0].size()), ..QpackConfig::default() });
+        let mut encoded = vec![];
+        commit(qpack.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+        let mut headers = headers;
+        headers.push(Header::from_str(":method", "GET"));
+        let err = qpack.encode_headers(&mut encoded, headers, STREAM_ID).err().unwrap();
+        assert_eq!(err.downcast_ref::<TooLarge>().unwrap().limit, 22 + 32);
+    }
+
+    #[test]
+    fn decode_max_header_list_size() {
+        let qpack_encoder = Qpack::default();
+        let qpack_decoder = Qpack::with_config(QpackConfig { max_header_list_size: Some(4096), ..QpackConfig::default() });
+        let headers: Vec<Header> = (0..100).map(|i| Header::from_string(format!("x-large-{}", i), "v".repeat(1000))).collect();
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+        let err = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap_err();
+        let too_large = err.downcast_ref::<TooLarge>().unwrap();
+        assert_eq!(too_large.limit, 4096);
+        // stopped at the first header beyond the limit
+        assert_eq!(too_large.size, headers[..4].iter().map(|header| header.size()).sum::<usize>());
+        assert!(qpack_decoder.decode_headers_bytes(&encoded, STREAM_ID).is_err());
+        assert!(qpack_decoder.pending_sections_snapshot().decoder.is_empty());
+
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers[..3].to_vec(), STREAM_ID));
+        assert_eq!(qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap().headers, headers[..3]);
+    }
+
+    #[test]
+    fn name_interning() {
+        let (qpack_encoder, mut qpack_decoder) = gen_client_server_instances(1, 1024);
+        qpack_decoder.set_name_interning(4);
+        let mut sections = vec![];
+        for i in 0..100 {
+            let headers = vec![Header::from_str(":method", "GET"),
+                               Header::from_string("x-request-id".to_string(), i.to_string()),
+                               Header::from_string("x-trace-id".to_string(), i.to_string())];
+            let mut encoded = vec![];
+            commit(qpack_encoder.encode_headers(&mut encoded, headers, STREAM_ID));
+            let (decoded, _) = qpack_decoder.decode_headers_interned(&encoded, STREAM_ID).unwrap();
+            assert_eq!(&*decoded[1].name, "x-request-id");
+            assert_eq!(decoded[1].value, i.to_string());
+            sections.push(decoded);
+        }
+        // 3 names for 300 headers
+        assert_eq!(qpack_decoder.interned_name_allocations(), 3);
+        assert!(Arc::ptr_eq(&sections[0][2].name, &sections[99][2].name));
+
+        // least recently used name is evicted
+        qpack_decoder.set_name_interning(2);
+        for _ in 0..2 {
+            let mut encoded = vec![];
+            commit(qpack_encoder.encode_headers(&mut encoded, sections[0].iter()
+                .map(|header| Header::from_str(&header.name, &header.value)).collect(), STREAM_ID));
+            qpack_decoder.decode_headers_interned(&encoded, STREAM_ID).unwrap();
+        }
+        assert_eq!(qpack_decoder.interned_name_allocations(), 6);
+    }
+
+    #[test]
+    fn empty_instructions() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, STREAM_ID));
+
+        // nothing to flush this time
+        let pending_sections = qpack_decoder.pending_sections_snapshot();
+        commit(qpack_decoder.decode_encoder_instruction(&[]));
+        assert_eq!(qpack_decoder.table.get_insert_count(), 1);
+        assert_eq!(qpack_decoder.table.get_capacity(), 1024);
+        assert_eq!(qpack_decoder.pending_sections_snapshot(), pending_sections);
+
+        let pending_sections = qpack_encoder.pending_sections_snapshot();
+        commit(qpack_encoder.decode_decoder_instruction(&[]));
+        assert_eq!(qpack_encoder.table.get_known_received_count(), 0);
+        assert_eq!(qpack_encoder.pending_sections_snapshot(), pending_sections);
+        assert_eq!(qpack_encoder.table.find_desync(&qpack_decoder.table), None);
+    }
+
+    #[test]
+    fn qpack_error_code() {
+        assert_eq!(QpackError::DecompressionFailed.code(), 0x0200);
+        assert_eq!(QpackError::EncoderStreamError.code(), 0x0201);
+        assert_eq!(QpackError::DecoderStreamError.code(), 0x0202);
+
+        let qpack = Qpack::new(1, 1024);
+        let err = qpack.decode_headers(&[0xff], STREAM_ID).unwrap_err();
+        assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::DecompressionFailed));
+        // capacity beyond the maximum
+        let mut wire = vec![];
+        let _ = Qpack::new(1, 4096).encode_set_dynamic_table_capacity(&mut wire, 4096).unwrap();
+        let err = qpack.decode_encoder_instruction(&wire).err().unwrap();
+        assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::EncoderStreamError));
+        // Section Acknowledgment and Stream Cancellation of a stream without section
+        let err = qpack.decode_decoder_instruction(&[0x84]).err().unwrap();
+        assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::DecoderStreamError));
+        let err = qpack.decode_decoder_instruction(&[0x44]).err().unwrap();
+        assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::DecoderStreamError));
+        assert_eq!(QpackError::of(&TableChanged), None);
+    }
+
+    #[test]
+    fn header_normalize() {
+        let mut header = Header::from_str("Content-Type", "  text/html  ");
+        header.normalize();
+        assert_eq!(header, Header::from_str("content-type", "text/html"));
+
+        // only around the value
+        let mut header = Header::from_str("ACCEPT", "\ttext/html, text/plain \t");
+        header.normalize();
+        assert_eq!(header, Header::from_str("accept", "text/html, text/plain"));
+    }
+
+    #[test]
+    fn huffman_non_ascii_round_trip() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        // U+0080 is 0xc2 0x80 in UTF-8, which must not be decoded into 2 chars
+        let mut header = Header::from_str("x-custom", "a\u{80}b");
+        header.set_huffman((true, true));
+        assert!(!send_headers(&qpack_encoder, &qpack_decoder, vec![header], STREAM_ID));
+
+        // literal with literal name, huffman encoded value of byte 0x80 which is not UTF-8
+        let mut value = vec![];
+        HUFFMAN_TRANSFORMER.encode_bytes(&mut value, &[b'a', 0x80]).unwrap();
+        let mut encoded = vec![0x00, 0x00, 0x23, b'a', b'b', b'c', 0x80 | value.len() as u8];
+        encoded.extend_from_slice(&value);
+        let err = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::InvalidUtf8);
+        let out = qpack_decoder.decode_headers_bytes(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out, vec![(b"abc".to_vec(), vec![b'a', 0x80])]);
+    }
+
+    #[test]
+    fn insert_refer_static_name_with_default_value() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let header = Header::from_str(":scheme", "ws");
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_insert_headers(&mut encoded, vec![header.clone()]);
+        commit(commit_func);
+        // Insert with Name Reference to static :scheme
+        assert_eq!(encoded[0] & 0b11000000, 0b11000000);
+        assert!(encoded[0] & 0b00111111 == 22 || encoded[0] & 0b00111111 == 23);
+        let commit_func = qpack_decoder.decode_encoder_instruction(&encoded);
+        commit(commit_func);
+        assert_eq!(qpack_encoder.table.get_dynamic_table_headers(), vec![header.clone()]);
+        assert_eq!(qpack_decoder.table.get_dynamic_table_headers(), vec![header.clone()]);
+
+        // name of :scheme https, not the entry itself
+        let mut encoded = vec![];
+        Encoder::encode_insert_refer_name(&mut encoded, true, 23, header.get_value()).unwrap();
+        let commit_func = qpack_decoder.decode_encoder_instruction(&encoded);
+        commit(commit_func);
+        assert_eq!(qpack_decoder.table.get_dynamic_table_headers(), vec![header.clone(), header]);
+    }
+
+    #[test]
+    fn acknowledge_all_inserts() {
+        let (qpack_encoder, mut qpack_decoder) = gen_client_server_instances(1, 256);
+        qpack_decoder.set_stall_ack_interval(time::Duration::ZERO);
+        for i in 0..4 {
+            insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", &format!("custom-value{}", i))]);
+        }
+        assert!(qpack_decoder.encoder_likely_stalled());
+
+        let mut encoded = vec![];
+        let commit_func = qpack_decoder.acknowledge_all_inserts(&mut encoded);
+        commit(commit_func);
+        assert_eq!(encoded, vec![0x04]);
+        assert!(!qpack_decoder.encoder_likely_stalled());
+        let commit_func = qpack_encoder.decode_decoder_instruction(&encoded);
+        commit(commit_func);
+        assert_eq!(qpack_encoder.table.get_known_received_count(), 4);
+        assert!(qpack_encoder.is_insertable(&[Header::from_str("custom-key", "custom-value4")]));
+
+        // nothing to acknowledge
+        let mut encoded = vec![];
+        let commit_func = qpack_decoder.acknowledge_all_inserts(&mut encoded);
+        commit(commit_func);
+        assert!(encoded.is_empty());
+    }
+
+    #[test]
+    fn insert_count_increment_twice() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "custom-value")]);
+        let mut encoded = vec![];
+        commit(qpack_decoder.encode_insert_count_increment(&mut encoded));
+        assert_eq!(encoded, vec![0x01]);
+        commit(qpack_encoder.decode_decoder_instruction(&encoded));
+
+        // no insertion since the last increment
+        let mut encoded = vec![];
+        commit(qpack_decoder.encode_insert_count_increment(&mut encoded));
+        assert!(encoded.is_empty());
+        assert_eq!(qpack_decoder.known_received_count(), 1);
+        assert_eq!(qpack_encoder.known_received_count(), 1);
+    }
+
+    #[test]
+    fn large_stream_id_ackowledgment() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let stream_id = StreamId::new(1 << 40);
+        assert!(u64::from(stream_id) > u32::MAX as u64);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, stream_id));
+        assert!(qpack_encoder.pending_sections_snapshot().encoder.contains_key(&stream_id));
+
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, stream_id);
+        assert!(qpack_encoder.pending_sections_snapshot().encoder.is_empty());
+        assert!(qpack_decoder.pending_sections_snapshot().decoder.is_empty());
+        assert_eq!(qpack_encoder.table.get_known_received_count(), 1);
+    }
+
+    #[test]
+    fn large_stream_id_cancellation() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let stream_id = StreamId::new(1 << 40);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, stream_id));
+
+        let mut encoded = vec![];
+        let commit_func = qpack_decoder.encode_stream_cancellation(&mut encoded, stream_id);
+        commit(commit_func);
+        assert_eq!(Encoder::decode_stream_cancellation(&encoded, 0).unwrap(), (encoded.len(), stream_id));
+        let commit_func = qpack_encoder.decode_decoder_instruction(&encoded);
+        commit(commit_func);
+        assert!(qpack_encoder.pending_sections_snapshot().encoder.is_empty());
+        assert_eq!(qpack_encoder.table.get_known_received_count(), 0);
+    }
+
+    #[test]
+    fn stream_id_at_api_boundary() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let raw: u64 = 12;
+        let stream_id = StreamId::from(raw);
+        assert_eq!(u64::from(stream_id), raw);
+        assert_eq!(stream_id.to_string(), "12");
+
+        // the table index of the inserted entry cannot be passed where a stream id is expected
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        let idx: usize = qpack_encoder.table.find_headers(&headers[..1])[0].2;
+        assert_eq!(idx, 0);
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, raw.into()));
+        let snapshot = qpack_encoder.pending_sections_snapshot();
+        assert_eq!(snapshot.encoder[&stream_id], vec![(1, vec![idx])]);
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, stream_id);
+        assert!(qpack_encoder.pending_sections_snapshot().encoder.is_empty());
+    }
+
+    #[test]
+    fn dump_dynamic_table_range() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers: Vec<Header> = (0..10)
+            .map(|i| Header::from_string(format!("key{}", i), format!("value{}", i)))
+            .collect();
+        insert_headers(&qpack_encoder, &qpack_decoder, headers);
+
+        let mut out = vec![];
+        qpack_decoder.dump_dynamic_table_range(4, 3, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "\tAbs:6, Refs:0, (key6=value6)\n\
+                    \tAbs:5, Refs:0, (key5=value5)\n\
+                    \tAbs:4, Refs:0, (key4=value4)\n");
+
+        // window is clipped to the entries in the table
+        let mut out = vec![];
+        qpack_decoder.dump_dynamic_table_range(8, 5, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn abort_stream() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(2, 1024);
+        let headers = vec![Header::from_str("custom-key0", "custom-value0"), Header::from_str("custom-key1", "custom-value1")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        send_headers(&qpack_encoder, &qpack_decoder, headers.clone(), StreamId::new(4));
+        send_headers(&qpack_encoder, &qpack_decoder, headers[..1].to_vec(), StreamId::new(8));
+        let refs = || {
+            let mut out = vec![];
+            qpack_encoder.dump_dynamic_table_range(0, 2, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(refs(), "\tAbs:1, Refs:1, (custom-key1=custom-value1)\n\
+                            \tAbs:0, Refs:2, (custom-key0=custom-value0)\n");
+
+        qpack_encoder.abort_stream(StreamId::new(4));
+        assert_eq!(refs(), "\tAbs:1, Refs:0, (custom-key1=custom-value1)\n\
+                            \tAbs:0, Refs:1, (custom-key0=custom-value0)\n");
+        assert!(!qpack_encoder.pending_sections_snapshot().encoder.contains_key(&StreamId::new(4)));
+        assert!(qpack_encoder.pending_sections_snapshot().encoder.contains_key(&StreamId::new(8)));
+        // nothing left to abort
+        qpack_encoder.abort_stream(StreamId::new(4));
+        qpack_encoder.abort_stream(StreamId::new(12));
+        assert_eq!(qpack_encoder.pending_sections_snapshot().encoder.len(), 1);
+    }
+
+    #[test]
+    fn insert_duplicate_or_refer_name() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_insert_headers(&mut encoded, headers.clone());
+        commit(commit_func);
+        assert_eq!(encoded, vec![0x00]); // Duplicate relative index 0
+        let commit_func = qpack_decoder.decode_encoder_instruction(&encoded);
+        commit(commit_func);
+
+        qpack_encoder.set_prefer_duplicate(false);
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_insert_headers(&mut encoded, headers);
+        commit(commit_func);
+        // Insert with dynamic name reference to relative index 0
+        assert_eq!(encoded, vec![0x80, 0x0c, 0x63, 0x75, 0x73, 0x74, 0x6f, 0x6d,
+                                 0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65]);
+        let commit_func = qpack_decoder.decode_encoder_instruction(&encoded);
+        commit(commit_func);
+        assert_eq!(qpack_decoder.table.get_insert_count(), 3);
+        qpack_encoder.assert_synced(&qpack_decoder);
+    }
+
+    #[test]
+    fn eviction_risk() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 200);
+        let headers = vec![Header::from_str("a", "b"), // 34 bytes
+                           Header::from_str("custom-key", "custom-value"), // 54 bytes
+                           Header::from_str("custom-key1", "custom-value1"), // 56 bytes
+                           Header::from_str("custom-key2", "custom-value2")]; // 56 bytes
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+
+        // the oldest entry fills less than a quarter of the full table
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers[..1].to_vec(), STREAM_ID));
+        let warnings = Arc::new(Mutex::new(vec![]));
+        let warnings_clone = Arc::clone(&warnings);
+        qpack_encoder.set_on_eviction_risk(move |idx| warnings_clone.lock().unwrap().push(idx));
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers[..1].to_vec(), STREAM_ID));
+        // ignored by default
+        assert!(warnings.lock().unwrap().is_empty());
+        qpack_encoder.set_eviction_risk_policy(EvictionRiskPolicy::Warn);
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers[..1].to_vec(), STREAM_ID));
+        assert_eq!(*warnings.lock().unwrap(), vec![0]);
+        qpack_encoder.set_eviction_risk_policy(EvictionRiskPolicy::Error);
+        let mut encoded = vec![];
+        let err = qpack_encoder.encode_headers(&mut encoded, headers[..1].to_vec(), STREAM_ID).err().unwrap();
+        assert_eq!(err.downcast_ref::<EvictionRisk>().unwrap().0, 0);
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers[1..].to_vec(), STREAM_ID));
+    }
+
+    #[test]
+    fn dynamic_table_state() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        for qpack in [&qpack_encoder, &qpack_decoder] {
+            assert_eq!((qpack.insert_count(), qpack.known_received_count()), (0, 0));
+            assert_eq!((qpack.dynamic_table_size(), qpack.dynamic_table_capacity()), (0, 1024));
+        }
+        let headers = vec![Header::from_str("custom-key", "custom-value"), // 54 bytes
+                           Header::from_str("custom-key2", "custom-value2")]; // 56 bytes
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        for qpack in [&qpack_encoder, &qpack_decoder] {
+            assert_eq!((qpack.insert_count(), qpack.known_received_count()), (2, 0));
+            assert_eq!(qpack.dynamic_table_size(), 110);
+        }
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers[..1].to_vec(), STREAM_ID));
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+        assert_eq!(qpack_encoder.known_received_count(), 1);
+        let mut encoded = vec![];
+        commit(qpack_decoder.encode_insert_count_increment(&mut encoded));
+        commit(qpack_encoder.decode_decoder_instruction(&encoded));
+        assert_eq!(qpack_encoder.known_received_count(), 2);
+        set_table_capacity(&qpack_encoder, &qpack_decoder, 64);
+        assert_eq!((qpack_encoder.dynamic_table_size(), qpack_encoder.dynamic_table_capacity()), (56, 64));
+    }
+
+    #[test]
+    fn validate_and_forward() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = vec![Header::from_str("custom-key", "custom-value"), Header::from_str(":path", "/")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers[..1].to_vec());
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers, STREAM_ID));
+
+        let forwarded = qpack_decoder.validate_and_forward(&encoded, STREAM_ID).unwrap();
+        assert_eq!(forwarded, &encoded[..]);
+        assert_eq!(qpack_decoder.pending_sections_snapshot().decoder[&STREAM_ID], vec![1]);
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+        assert!(qpack_decoder.pending_sections_snapshot().decoder.is_empty());
+
+        // invalid one is not forwarded
+        encoded.push(0xff);
+        assert!(qpack_decoder.validate_and_forward(&encoded, STREAM_ID).is_err());
+    }
+
+    #[test]
+    fn encode_headers_safe() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let acked = Header::from_str("custom-key0", "custom-value0");
+        insert_send_ack(&qpack_encoder, &qpack_decoder, vec![acked.clone()], false);
+
+        // inserted, but the instruction has not reached the decoder yet
+        let unacked = Header::from_str("custom-key1", "custom-value1");
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut encoded, vec![unacked.clone()]));
+
+        let headers = vec![acked, unacked, Header::from_str(":path", "/")];
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers_safe(&mut encoded, headers.clone(), STREAM_ID));
+        assert_eq!(encoded[..3], [0x02, 0x00, 0x80]);
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, headers);
+        assert_eq!(out.action, DecoderAction::SendAck);
+        assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+    }
+
+    #[test]
+    fn conservative_encoder_never_blocks() {
+        let qpack_encoder = Qpack::with_config(QpackConfig { encoder: EncoderConfig::conservative(), ..QpackConfig::default() });
+        // any section blocking the decoder fails with BlockedStreamsLimitExceeded
+        let qpack_decoder = Qpack::new(0, 4096);
+        set_table_capacity(&qpack_encoder, &qpack_decoder, 4096);
+        let mut headers: Vec<Header> = (0..10).map(|i| Header::from_string(format!("custom-key{}", i), "custom-value".to_string())).collect();
+        let mut token = Header::from_str("authorization", "secret");
+        token.set_sensitive(true);
+        headers.push(token);
+
+        let mut instructions = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut instructions, headers.clone()));
+        assert_eq!(qpack_encoder.insert_count(), 10);
+        // the insertions have not reached the decoder yet
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, headers);
+        assert_eq!(out.action, DecoderAction::None);
+        assert_eq!(qpack_encoder.encoder_metrics(), EncoderMetrics {
+            unacknowledged_fallbacks: 10,
+            reference_cap_fallbacks: 0,
+            sensitive_not_inserted: 1,
+        });
+
+        commit(qpack_decoder.decode_encoder_instruction(&instructions));
+        let mut encoded = vec![];
+        commit(qpack_decoder.encode_insert_count_increment(&mut encoded));
+        commit(qpack_encoder.decode_decoder_instruction(&encoded));
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, headers);
+        assert_eq!(out.action, DecoderAction::SendAck);
+        // 8 field lines at most refer the dynamic table
+        assert_eq!(qpack_encoder.pending_sections_snapshot().encoder[&STREAM_ID][0].1.len(), 8);
+        assert_eq!(qpack_encoder.encoder_metrics().reference_cap_fallbacks, 2);
+        assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+    }
+
+    #[test]
+    fn draining_entries_not_referred() {
+        let (mut qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 200);
+        let headers = vec![Header::from_str("custom-key0", "custom-value0"), // 56 bytes
+                           Header::from_str("custom-key1", "custom-value1"),
+                           Header::from_str("custom-key2", "custom-value2")];
+        insert_send_ack(&qpack_encoder, &qpack_decoder, headers.clone(), false);
+        assert_eq!(qpack_encoder.draining_idx(), 0);
+
+        // 32 bytes left, the oldest entry is evicted by inserting 88 bytes or less
+        qpack_encoder.set_draining_fraction(0.44);
+        assert_eq!(qpack_encoder.draining_idx(), 1);
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+        // draining one is encoded as literal, the others are indexed
+        assert_eq!(encoded[..5], [0x04, 0x00, 0x27, 0x04, 0x63]);
+        assert_eq!(encoded[encoded.len() - 2..], [0x81, 0x80]);
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, headers);
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+
+        // and inserted again by Duplicate
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut encoded, headers[..1].to_vec()));
+        assert_eq!(encoded, vec![0x02]);
+        commit(qpack_decoder.decode_encoder_instruction(&encoded));
+        assert_eq!(qpack_encoder.draining_idx(), 2);
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers[..1].to_vec(), STREAM_ID));
+    }
+
+    #[test]
+    fn assert_synced() {
+        let (qpack_client, qpack_server) = gen_client_server_instances(1, 4096);
+        qpack_client.assert_synced(&qpack_server);
+        insert_send_ack(&qpack_client, &qpack_server, get_request_headers(false), false);
+        qpack_client.assert_synced(&qpack_server);
+        insert_send_ack(&qpack_server, &qpack_client, get_response_headers(false), false);
+        qpack_client.assert_synced(&qpack_server);
+    }
+
+    #[test]
+    #[should_panic(expected = "dynamic tables are out of sync")]
+    fn assert_synced_detects_desync() {
+        let (qpack_client, qpack_server) = gen_client_server_instances(1, 4096);
+        let mut encoded = vec![];
+        let commit_func = qpack_client.encode_insert_headers(&mut encoded, vec![Header::from_str("custom-key", "custom-value")]);
+        commit(commit_func);
+        // server receives different entry from the one client inserted
+        let mut encoded = vec![];
+        let _ = qpack_server.encode_insert_headers(&mut encoded, vec![Header::from_str("custom-key", "other-value")]);
+        let commit_func = qpack_server.decode_encoder_instruction(&encoded);
+        commit(commit_func);
+        qpack_client.assert_synced(&qpack_server);
+    }
+
     #[test]
     fn request_response() {
-        let (qpack_client, qpack_server) = gen_client_server_instances(1, 1024);
+        // large enough not to evict the entries of a request inserted at once, which are not acknowledged yet
+        let (qpack_client, qpack_server) = gen_client_server_instances(1, 2048);
         println!("Client -> Server");
         let request_headers = get_request_headers(false);
         insert_send_ack(&qpack_client, &qpack_server, request_headers, false);
@@ -693,10 +3163,40 @@ mod tests {
 								0x69, 0x6e, 0x64, 0x65, 0x78,
 								0x2e, 0x68, 0x74, 0x6d, 0x6c];
 		let out = qpack.decode_headers(&wire, STREAM_ID).unwrap();
-		assert_eq!(out.0, vec![Header::from_str(":path", "/index.html")]);
-		assert_eq!(out.1, false);
+		assert_eq!(out.headers, vec![Header::from_str(":path", "/index.html")]);
+		assert_eq!(out.action, DecoderAction::None);
 	}
 
+    #[test]
+    fn rfc_appendix_b1_default() {
+        let qpack = Qpack::default();
+        let headers = vec![Header::from_str(":path", "/index.html")];
+        let mut encoded = vec![];
+        let commit_func = qpack.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        assert_eq!(encoded,
+                    vec![0x00, 0x00, 0x51, 0x0b, 0x2f,
+                         0x69, 0x6e, 0x64, 0x65, 0x78,
+                         0x2e, 0x68, 0x74, 0x6d, 0x6c]);
+        let out = qpack.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, headers);
+        assert_eq!(out.action, DecoderAction::None);
+    }
+
+    #[test]
+    fn static_name_reference() {
+        let qpack = Qpack::new(1, 1024);
+        let headers = vec![Header::from_str("content-encoding", "zstd")];
+        let mut encoded = vec![];
+        let commit_func = qpack.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        // literal with name reference to static index 42 (content-encoding: br)
+        assert_eq!(encoded, vec![0x00, 0x00, 0x5f, 0x1b, 0x04, 0x7a, 0x73, 0x74, 0x64]);
+        let out = qpack.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, headers);
+        assert_eq!(out.action, DecoderAction::None);
+    }
+
 	#[test]
 	fn encode_indexed_simple() {
 		let qpack = Qpack::new(1, 1024);
@@ -712,10 +3212,81 @@ mod tests {
 		let qpack = Qpack::new(1, 1024);
 		let wire = vec![0x00, 0x00, 0xc1];
 		let out = qpack.decode_headers(&wire, STREAM_ID).unwrap();
-		assert_eq!(out.0,
+		assert_eq!(out.headers,
 			vec![Header::from_str(":path", "/")]);
-        assert_eq!(out.1, false);
+        assert_eq!(out.action, DecoderAction::None);
 	}
+    fn assert_decompression_failed(wire: Vec<u8>, reason: DecompressReason, offset: usize) {
+        let qpack = Qpack::new(1, 1024);
+        let err = qpack.decode_headers(&wire, STREAM_ID).unwrap_err();
+        let failed = err.downcast_ref::<DecompressionFailed>().unwrap();
+        assert_eq!(failed.reason, reason);
+        assert_eq!(failed.offset, offset);
+    }
+    #[test]
+    fn decompression_failed_context() {
+        // truncated Required Insert Count and Delta Base
+        assert_decompression_failed(vec![0xff], DecompressReason::Truncated, 0);
+        assert_decompression_failed(vec![0x00], DecompressReason::Truncated, 1);
+        // truncated static index
+        assert_decompression_failed(vec![0x00, 0x00, 0xff], DecompressReason::Truncated, 2);
+        // Required Insert Count beyond 2 * MaxEntries
+        assert_decompression_failed(vec![0x41, 0x00], DecompressReason::InvalidRequiredInsertCount, 0);
+        // static index 190 after a valid field line
+        assert_decompression_failed(vec![0x00, 0x00, 0xc1, 0xff, 0x7f], DecompressReason::InvalidStaticIndex, 3);
+        // dynamic index without any insertion
+        assert_decompression_failed(vec![0x00, 0x00, 0x80], DecompressReason::InvalidDynamicIndex, 2);
+        // value string is shorter than its length
+        assert_decompression_failed(vec![0x00, 0x00, 0x51, 0x0b, 0x2f, 0x69], DecompressReason::Truncated, 3);
+    }
+    #[test]
+    fn pre_base_index_out_of_range() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "custom-value"),
+                                                            Header::from_str("custom-key2", "custom-value2")]);
+        // Required Insert Count 1, S=1 and Delta Base 0 make Base 0, nothing is before it
+        let err = qpack_decoder.decode_headers(&[0x02, 0x80, 0x80], STREAM_ID).unwrap_err();
+        let failed = err.downcast_ref::<DecompressionFailed>().unwrap();
+        assert_eq!((failed.reason, failed.offset), (DecompressReason::InvalidDynamicIndex, 2));
+        // Base 2 by Delta Base 1, relative index 0 is absolute 1 at Required Insert Count
+        let err = qpack_decoder.decode_headers(&[0x02, 0x01, 0x80], STREAM_ID).unwrap_err();
+        let failed = err.downcast_ref::<DecompressionFailed>().unwrap();
+        assert_eq!((failed.reason, failed.offset), (DecompressReason::InvalidDynamicIndex, 2));
+        // same for name reference
+        let err = qpack_decoder.decode_headers(&[0x02, 0x80, 0x40, 0x00], STREAM_ID).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::InvalidDynamicIndex);
+    }
+    #[test]
+    fn required_insert_count_beyond_max_entries() {
+        let qpack = Qpack::new(1, 1024);
+        // 32 entries at most, Required Insert Count 39 without any insertion
+        let wire = [0x28, 0x00, 0x80];
+        let err = qpack.decode_headers(&wire, STREAM_ID).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::InvalidRequiredInsertCount);
+        // beyond 2 * MaxEntries
+        let err = qpack.decode_headers(&[0x41, 0x00, 0x80], STREAM_ID).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::InvalidRequiredInsertCount);
+        assert_eq!(qpack.decoder.read().unwrap().current_blocked_streams, 0);
+    }
+    #[test]
+    fn dynamic_reference_without_capacity() {
+        let qpack = Qpack::new(1, 0);
+        // Required Insert Count 1, fails without waiting for insertions
+        let err = qpack.decode_headers(&[0x02, 0x00, 0x80], STREAM_ID).unwrap_err();
+        let failed = err.downcast_ref::<DecompressionFailed>().unwrap();
+        assert_eq!((failed.reason, failed.offset), (DecompressReason::InvalidRequiredInsertCount, 0));
+        assert_eq!(qpack.decoder.read().unwrap().current_blocked_streams, 0);
+    }
+    #[test]
+    fn decompression_failed_utf8_source() {
+        let qpack = Qpack::new(1, 1024);
+        let wire = vec![0x00, 0x00, 0x51, 0x02, 0xc3, 0x28];
+        let err = qpack.decode_headers(&wire, STREAM_ID).unwrap_err();
+        let failed = err.downcast_ref::<DecompressionFailed>().unwrap();
+        assert_eq!(failed.reason, DecompressReason::InvalidUtf8);
+        assert_eq!(failed.offset, 3);
+        assert!(error::Error::source(failed).unwrap().downcast_ref::<std::str::Utf8Error>().is_some());
+    }
     #[test]
     fn encode_set_dynamic_table_capacity() {
         let qpack = Qpack::new(1, 1024);
@@ -724,9 +3295,67 @@ mod tests {
         assert_eq!(encoded, vec![0x3f, 0xbd, 0x01]);
     }
     #[test]
+    fn instruction_len() {
+        for capacity in [0, 30, 31, 220, 4096, 1 << 20] {
+            let mut encoded = vec![];
+            let _ = Encoder::encode_set_dynamic_table_capacity(&mut encoded, capacity);
+            assert_eq!(Qpack::set_capacity_instruction_len(capacity), encoded.len());
+        }
+        for idx in [0, 2, 31, 32, 200, 70000] {
+            let mut encoded = vec![];
+            let _ = Encoder::encode_duplicate(&mut encoded, idx);
+            assert_eq!(Qpack::duplicate_instruction_len(idx), encoded.len());
+        }
+    }
+    #[test]
+    fn decode_capacity_exceeds_max() {
+        let qpack_encoder = Qpack::new(1, 2048);
+        let qpack_decoder = Qpack::new(1, 1024);
+        let mut encoded = vec![];
+        let _ = qpack_encoder.encode_set_dynamic_table_capacity(&mut encoded, 1024);
+        let _ = qpack_encoder.encode_set_dynamic_table_capacity(&mut encoded, 2048);
+        let err = qpack_decoder.decode_encoder_instruction(&encoded).err().unwrap();
+        assert!(err.downcast_ref::<EncoderStreamError>().is_some());
+        assert_eq!(qpack_decoder.table.dynamic_table.read().unwrap().capacity, 0);
+    }
+    #[test]
+    fn validate_encoder_instruction() {
+        let qpack_encoder = Qpack::new(1, 1024);
+        let qpack_decoder = Qpack::new(1, 1024);
+        let mut encoded = vec![];
+        let _ = qpack_encoder.encode_set_dynamic_table_capacity(&mut encoded, 220);
+        let headers = vec![Header::from_str(":authority", "www.example.com"),
+                           Header::from_str(":path", "/sample/path")];
+        let _ = qpack_encoder.encode_insert_headers(&mut encoded, headers);
+        let _ = Encoder::encode_duplicate(&mut encoded, 1);
+
+        assert!(qpack_decoder.validate_encoder_instruction(&encoded).is_ok());
+        let err = qpack_decoder.validate_encoder_instruction(&encoded[..encoded.len() - 2]).unwrap_err();
+        assert!(err.downcast_ref::<EncoderStreamError>().is_some());
+        // duplicate of not inserted entry
+        let err = qpack_decoder.validate_encoder_instruction(&[0x02]).unwrap_err();
+        assert!(err.downcast_ref::<EncoderStreamError>().is_some());
+        assert_eq!(qpack_decoder.table.get_insert_count(), 0);
+        assert_eq!(qpack_decoder.table.dynamic_table.read().unwrap().capacity, 0);
+    }
+    #[test]
+    fn insert_refer_name_invalid_static_index() {
+        let (_, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let mut encoded = vec![];
+        let value = HeaderString::new("value".to_string(), false);
+        Encoder::encode_insert_refer_name(&mut encoded, true, 200, &value).unwrap();
+        let err = match qpack_decoder.decode_encoder_instruction(&encoded) {
+            Ok(_) => panic!("static index 200 should be rejected"),
+            Err(err) => err,
+        };
+        assert!(err.downcast_ref::<EncoderStreamError>().is_some());
+        assert!(err.downcast_ref::<DecompressionFailed>().is_none());
+        assert_eq!(qpack_decoder.table.get_insert_count(), 0);
+    }
+    #[test]
     fn blocking_multi() {
         let request_headers = get_request_headers(false);
-        let delay_func = |qpack_encoder: Arc<Qpack>, qpack_decoder: Arc<Qpack>, headers: Vec<Header>, delay: u64, insert_headers_packet: Vec<u8>, stream_id: u16| {
+        let delay_func = |qpack_encoder: Arc<Qpack>, qpack_decoder: Arc<Qpack>, headers: Vec<Header>, delay: u64, insert_headers_packet: Vec<u8>, stream_id: StreamId| {
             // header insertion arrives after starting decoding headers
             let copied_dec = Arc::clone(&qpack_decoder);
             let th = thread::spawn(move || {
@@ -759,7 +3388,7 @@ mod tests {
             let qpack_decoder = Arc::new(qpack_decoder);
             let mut ths = vec![];
             for (i, headers) in request_headers_batched.into_iter().enumerate() {
-                let f = delay_func.clone();
+                let f = delay_func;
                 let mut insert_headers_packet = vec![];
                 let commit_func = qpack_encoder.encode_insert_headers(&mut insert_headers_packet, headers.clone());
                 commit(commit_func);
@@ -768,7 +3397,7 @@ mod tests {
                 let dec_clone = Arc::clone(&qpack_decoder);
                 ths.push(thread::spawn(move || {
                     // delay is for encoder/decoder instructions arrive serially
-                    f(enc_clone, dec_clone, headers, i as u64, insert_headers_packet, i as u16 * 2);
+                    f(enc_clone, dec_clone, headers, i as u64, insert_headers_packet, StreamId::new(i as u64 * 2));
                 }));
             }
             for th in ths {
@@ -777,13 +3406,254 @@ mod tests {
         }
     }
 
+    #[test]
+    fn block_callbacks() {
+        let (qpack_encoder, mut qpack_decoder) = gen_client_server_instances(1, 1024);
+        let events = Arc::new(Mutex::new(vec![]));
+        let events_block = Arc::clone(&events);
+        qpack_decoder.set_on_block(move |stream_id, required_insert_count| {
+            events_block.lock().unwrap().push(format!("block {} {}", stream_id, required_insert_count));
+        });
+        let events_unblock = Arc::clone(&events);
+        qpack_decoder.set_on_unblock(move |stream_id| {
+            events_unblock.lock().unwrap().push(format!("unblock {}", stream_id));
+        });
+        let qpack_decoder = Arc::new(qpack_decoder);
+
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        let mut insert_headers_packet = vec![];
+        let commit_func = qpack_encoder.encode_insert_headers(&mut insert_headers_packet, headers.clone());
+        commit(commit_func);
+        let decoder = Arc::clone(&qpack_decoder);
+        let th = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            let commit_func = decoder.decode_encoder_instruction(&insert_headers_packet);
+            commit(commit_func);
+        });
+        assert!(send_headers(&qpack_encoder, &qpack_decoder, headers, STREAM_ID));
+        let _ = th.join();
+        assert_eq!(*events.lock().unwrap(), vec!["block 4 1".to_string(), "unblock 4".to_string()]);
+    }
+
+    #[test]
+    fn decoder_action() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, vec![Header::from_str(":method", "GET")], STREAM_ID));
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.action, DecoderAction::None);
+        assert!(!out.action.requires_ack());
+
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.action, DecoderAction::SendAck);
+        section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+
+        // decoded before the entry arrives
+        let headers = vec![Header::from_str("custom-key2", "custom-value2")];
+        let mut insert_headers_packet = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut insert_headers_packet, headers.clone()));
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers, StreamId::new(8)));
+        let qpack_decoder = Arc::new(qpack_decoder);
+        let decoder = Arc::clone(&qpack_decoder);
+        let th = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            commit(decoder.decode_encoder_instruction(&insert_headers_packet));
+        });
+        let out = qpack_decoder.decode_headers(&encoded, StreamId::new(8)).unwrap();
+        let _ = th.join();
+        assert_eq!(out.action, DecoderAction::AlreadyUnblocked);
+        assert!(out.action.requires_ack());
+    }
+
+    #[test]
+    fn raise_max_blocked_streams() {
+        let qpack_encoder = Qpack::new(1, 1024);
+        let qpack_decoder = Arc::new(Qpack::new(0, 1024));
+        set_table_capacity(&qpack_encoder, &qpack_decoder, 1024);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        let mut insert_headers_packet = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut insert_headers_packet, headers.clone()));
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+
+        assert_eq!(qpack_decoder.max_blocked_streams(), 0);
+        let err = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::BlockedStreamsLimitExceeded);
+
+        qpack_decoder.set_max_blocked_streams(1);
+        assert_eq!(qpack_decoder.max_blocked_streams(), 1);
+        let decoder = Arc::clone(&qpack_decoder);
+        let th = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            assert_eq!(decoder.decoder.read().unwrap().current_blocked_streams, 1);
+            commit(decoder.decode_encoder_instruction(&insert_headers_packet));
+        });
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        th.join().unwrap();
+        assert_eq!(out.headers, headers);
+    }
+
+    #[test]
+    fn blocked_streams_sequentially() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let qpack_decoder = Arc::new(qpack_decoder);
+        for i in 0..4 {
+            let stream_id = StreamId::new(4 * (i + 1));
+            let headers = vec![Header::from_string(format!("custom-key{}", i), "custom-value".to_string())];
+            let mut insert_headers_packet = vec![];
+            commit(qpack_encoder.encode_insert_headers(&mut insert_headers_packet, headers.clone()));
+            let mut encoded = vec![];
+            commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), stream_id));
+
+            let decoder = Arc::clone(&qpack_decoder);
+            let th = thread::spawn(move || {
+                thread::sleep(time::Duration::from_millis(20));
+                commit(decoder.decode_encoder_instruction(&insert_headers_packet));
+            });
+            // the limit of 1 is not used up by the streams unblocked before
+            let out = qpack_decoder.decode_headers(&encoded, stream_id).unwrap();
+            th.join().unwrap();
+            assert_eq!(out.headers, headers);
+            assert_eq!(out.action, DecoderAction::AlreadyUnblocked);
+            assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+            section_ackowledgment(&qpack_encoder, &qpack_decoder, stream_id);
+        }
+    }
+
+    #[test]
+    fn cancel_blocked_stream() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let qpack_decoder = Arc::new(qpack_decoder);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut encoded, headers.clone()));
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers, STREAM_ID));
+        assert!(!qpack_decoder.cancel_blocked_stream(STREAM_ID));
+
+        let decoder = Arc::clone(&qpack_decoder);
+        let th = thread::spawn(move || decoder.decode_headers(&encoded, STREAM_ID).map_err(|e| e.to_string()));
+        while qpack_decoder.decoder.read().unwrap().current_blocked_streams == 0 {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        assert!(!qpack_decoder.cancel_blocked_stream(StreamId::new(8)));
+        assert!(qpack_decoder.cancel_blocked_stream(STREAM_ID));
+        let err = th.join().unwrap().unwrap_err();
+        assert!(err.contains("BlockedStreamCancelled"), "{}", err);
+        assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+        assert!(!qpack_decoder.cancel_blocked_stream(STREAM_ID));
+    }
+
+    #[test]
+    fn concurrent_insert_and_blocked_decode() {
+        const STREAMS: u64 = 8;
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(STREAMS as u16, 1024);
+        let (qpack_encoder, qpack_decoder) = (Arc::new(qpack_encoder), Arc::new(qpack_decoder));
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        // reads taking several locks in a row on both sides, racing with commits and blocked decodings
+        let (encoder, decoder, reader_done) = (Arc::clone(&qpack_encoder), Arc::clone(&qpack_decoder), Arc::clone(&done));
+        let reader = thread::spawn(move || {
+            while !reader_done.load(std::sync::atomic::Ordering::Relaxed) {
+                assert!(encoder.blocking_streams() <= STREAMS as usize);
+                assert!(encoder.draining_idx() <= encoder.insert_count());
+                assert!(decoder.known_received_count() <= decoder.insert_count());
+            }
+        });
+        for round in 0..20 {
+            let mut instructions = vec![];
+            let mut decoders = vec![];
+            for i in 0..STREAMS {
+                let stream_id = StreamId::new(4 * i);
+                let headers = vec![Header::from_string(format!("key-{}-{}", round, i), "value".to_string())];
+                let mut encoded = vec![];
+                commit(qpack_encoder.encode_insert_headers(&mut encoded, headers.clone()));
+                instructions.push(encoded);
+                let mut encoded = vec![];
+                commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), stream_id));
+                let decoder = Arc::clone(&qpack_decoder);
+                decoders.push(thread::spawn(move || {
+                    let out = decoder.decode_headers(&encoded, stream_id).unwrap();
+                    assert_eq!(out.headers, headers);
+                    stream_id
+                }));
+            }
+            for instruction in instructions {
+                commit(qpack_decoder.decode_encoder_instruction(&instruction));
+            }
+            for decoder in decoders {
+                section_ackowledgment(&qpack_encoder, &qpack_decoder, decoder.join().unwrap());
+            }
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        reader.join().unwrap();
+        assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+        assert_eq!(qpack_encoder.known_received_count(), 20 * STREAMS as usize);
+        qpack_encoder.assert_synced(&qpack_decoder);
+    }
+
+    #[test]
+    fn blocked_stream_timeout() {
+        let (qpack_encoder, _) = gen_client_server_instances(1, 1024);
+        let qpack_decoder = Qpack::with_config(QpackConfig {
+            blocked_streams_limit: 1,
+            max_table_capacity: 1024,
+            blocked_stream_timeout: Some(time::Duration::from_millis(50)),
+            ..QpackConfig::default()
+        });
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut encoded, headers.clone()));
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers, STREAM_ID));
+
+        // the insertion never arrives
+        let start = std::time::Instant::now();
+        let err = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap_err();
+        assert!(time::Duration::from_millis(50) <= start.elapsed());
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::BlockedStreamTimeout);
+        assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+        assert!(qpack_decoder.pending_sections_snapshot().decoder.is_empty());
+    }
+
+    #[test]
+    fn blocked_streams_restored_on_error() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let qpack_decoder = Arc::new(qpack_decoder);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        let mut insert_headers_packet = vec![];
+        let commit_func = qpack_encoder.encode_insert_headers(&mut insert_headers_packet, headers.clone());
+        commit(commit_func);
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers, STREAM_ID);
+        commit(commit_func);
+        // static name reference whose index is cut in the middle
+        encoded.push(0x5f);
+
+        let decoder = Arc::clone(&qpack_decoder);
+        let th = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            assert_eq!(decoder.decoder.read().unwrap().current_blocked_streams, 1);
+            let commit_func = decoder.decode_encoder_instruction(&insert_headers_packet);
+            commit(commit_func);
+        });
+        let err = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap_err();
+        th.join().unwrap();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::Truncated);
+        assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+    }
+
     #[test]
     fn multi_threading() {
         let (qpack_encoder, qpack_decoder) = gen_client_server_instances(2, 1024);
         let safe_encoder = Arc::new(qpack_encoder);
         let safe_decoder = Arc::new(qpack_decoder);
 
-        let f = |headers: Vec<Header>, stream_id: u16, _expected_wire: Vec<u8>,
+        let f = |headers: Vec<Header>, stream_id: StreamId, _expected_wire: Vec<u8>,
                                                 encoder: Arc<Qpack>, decoder: Arc<Qpack>| {
             let mut encoded = vec![];
             let commit_func = encoder.encode_headers(&mut encoded, headers.clone(), stream_id);
@@ -791,15 +3661,15 @@ mod tests {
             //assert_eq!(encoded, expected_wire);
 
             if let Ok(out) = decoder.decode_headers(&encoded, stream_id) {
-                assert_eq!(out.0, headers);
-                assert_eq!(out.1, false);
+                assert_eq!(out.headers, headers);
+                assert_eq!(out.action, DecoderAction::None);
             } else {
-                assert!(false);
+                panic!("failed to decode headers");
             }
         };
 
         let mut ths = vec![];
-        let headers_set = vec![vec![Header::from_str(":path", "/"), Header::from_str("age", "0")],
+        let headers_set = [vec![Header::from_str(":path", "/"), Header::from_str("age", "0")],
                                             vec![Header::from_str("content-length", "0"), Header::from_str(":method", "CONNECT")]];
         let expected_wires: Vec<Vec<u8>> = vec![vec![], vec![]];
         for i in 0..headers_set.len() {
@@ -808,13 +3678,98 @@ mod tests {
             let headers = headers_set[i].clone();
             let expected_wire = expected_wires[i].clone();
             ths.push(thread::spawn(move || {
-                f(headers, 4 + (i as u16) * 2, expected_wire, en, de);
+                f(headers, StreamId::new(4 + (i as u64) * 2), expected_wire, en, de);
             }));
         }
         for th in ths {
             let _ = th.join();
         }
     }
+    #[test]
+    fn multi_threading_with_eviction() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(16, 400);
+        let safe_encoder = Arc::new(qpack_encoder);
+        let safe_decoder = Arc::new(qpack_decoder);
+        // encoder stream is a single ordered stream, instructions are applied to both sides in turn
+        let encoder_stream = Arc::new(Mutex::new(()));
+
+        let mut ths = vec![];
+        for t in 0..8_u64 {
+            let encoder = Arc::clone(&safe_encoder);
+            let decoder = Arc::clone(&safe_decoder);
+            let encoder_stream = Arc::clone(&encoder_stream);
+            ths.push(thread::spawn(move || {
+                for i in 0..20_u64 {
+                    let stream_id = StreamId::new((t * 20 + i) * 4);
+                    // same size for every header, so that one insertion evicts one entry at most
+                    let header = Header::from_str("x-thread", &format!("{}-{:03}", t, i));
+                    {
+                        let _lock = encoder_stream.lock().unwrap();
+                        let mut encoded = vec![];
+                        let commit_func = encoder.encode_insert_headers(&mut encoded, vec![header.clone()]).unwrap();
+                        // the entry to be evicted may be referred by a section of another thread
+                        if commit_func().is_ok() {
+                            commit(decoder.decode_encoder_instruction(&encoded));
+                        }
+                    }
+                    let headers = vec![Header::from_str(":method", "GET"), header];
+                    loop {
+                        let mut encoded = vec![];
+                        let commit_func = encoder.encode_headers(&mut encoded, headers.clone(), stream_id).unwrap();
+                        if let Err(err) = commit_func() {
+                            assert!(err.downcast_ref::<TableChanged>().is_some());
+                            continue;
+                        }
+                        let out = decoder.decode_headers(&encoded, stream_id).unwrap();
+                        assert_eq!(out.headers, headers);
+                        if out.action.requires_ack() {
+                            section_ackowledgment(&encoder, &decoder, stream_id);
+                        }
+                        break;
+                    }
+                }
+            }));
+        }
+        for th in ths {
+            th.join().unwrap();
+        }
+        assert!(safe_encoder.table.get_eviction_count() > 0);
+        safe_encoder.assert_synced(&safe_decoder);
+    }
+    #[test]
+    fn encode_headers_with_base_post_base() {
+        // RFC 9204 B.2, inserted for the request and referred post-base
+        let qpack_encoder = Qpack::new(1, 1024);
+        let qpack_decoder = Qpack::new(1, 1024);
+        let headers = vec![Header::from_str(":authority", "www.example.com"),
+                           Header::from_str(":path", "/sample/path")];
+        let mut encoder_stream = vec![];
+        commit(qpack_encoder.encode_set_dynamic_table_capacity(&mut encoder_stream, 220));
+        let base = qpack_encoder.insert_count();
+        commit(qpack_encoder.encode_insert_headers(&mut encoder_stream, headers.clone()));
+        assert_eq!(encoder_stream, vec![0x3f, 0xbd, 0x01, 0xc0, 0x0f, 0x77, 0x77,
+                                        0x77, 0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70,
+                                        0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0xc1,
+                                        0x0c, 0x2f, 0x73, 0x61, 0x6d, 0x70, 0x6c,
+                                        0x65, 0x2f, 0x70, 0x61, 0x74, 0x68]);
+
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers_with_base(&mut encoded, headers.clone(), STREAM_ID, base));
+        assert_eq!(encoded, vec![0x03, 0x81, 0x10, 0x11]);
+        assert_eq!(qpack_encoder.pending_sections_snapshot().encoder[&STREAM_ID], vec![(2, vec![0, 1])]);
+
+        commit(qpack_decoder.decode_encoder_instruction(&encoder_stream));
+        let out = qpack_decoder.decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, headers);
+        assert_eq!(out.action, DecoderAction::SendAck);
+        let mut encoded = vec![];
+        commit(qpack_decoder.encode_section_ackowledgment(&mut encoded, STREAM_ID));
+        assert_eq!(encoded, vec![0x84]);
+
+        let err = qpack_encoder.encode_headers_with_base(&mut vec![], headers, STREAM_ID, 3).err().unwrap();
+        assert!(err.downcast_ref::<InvalidBase>().is_some());
+    }
+
     #[test]
     fn encode_insert_with_name_reference() {
         let qpack_encoder = Qpack::new(1, 1024);
@@ -851,13 +3806,14 @@ mod tests {
                                           Header::from_str(":path", "/sample/path")];
             let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
             commit(commit_func);
-            assert_eq!(encoded, vec![0x03, 0x81, 0x10, 0x11]);
+            // RFC 9204 B.2 uses post-base (0x03, 0x81, 0x10, 0x11) but pre-base is as short
+            assert_eq!(encoded, vec![0x03, 0x00, 0x81, 0x80]);
 
             if let Ok(decoded) = qpack_decoder.decode_headers(&encoded, STREAM_ID) {
-                assert_eq!(decoded.0, headers);
-                assert_eq!(decoded.1, true);
+                assert_eq!(decoded.headers, headers);
+                assert!(decoded.action.requires_ack());
             } else {
-                assert!(false);
+                panic!("failed to decode headers");
             }
         }
 
@@ -930,22 +3886,22 @@ mod tests {
             let headers = vec![Header::from_str(":authority", "www.example.com"),
                                         Header::from_str(":path", "/"),
                                         Header::from_str("custom-key", "custom-value")];
-            let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), 8);
+            let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), StreamId::new(8));
             commit(commit_func);
             assert_eq!(encoded, vec![0x05, 0x00, 0x80, 0xc1, 0x81]);
 
-            if let Ok(decoded) = qpack_decoder.decode_headers(&encoded, 8) {
-                assert_eq!(decoded.0, headers);
-                assert_eq!(decoded.1, true);
+            if let Ok(decoded) = qpack_decoder.decode_headers(&encoded, StreamId::new(8)) {
+                assert_eq!(decoded.headers, headers);
+                assert!(decoded.action.requires_ack());
             } else {
-                assert!(false);
+                panic!("failed to decode headers");
             }
         }
 
         println!("Step 8");
         {   // stream cancellation
             let mut encoded = vec![];
-            let commit_func = qpack_decoder.encode_stream_cancellation(&mut encoded, 8);
+            let commit_func = qpack_decoder.encode_stream_cancellation(&mut encoded, StreamId::new(8));
             assert_eq!(encoded, vec![0x48]);
             commit(commit_func);
 
@@ -971,4 +3927,434 @@ mod tests {
             qpack_decoder.dump_dynamic_table();
         }
     }
+
+    #[test]
+    fn blocked_twice_on_same_stream() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(2, 1024);
+        let qpack_decoder = Arc::new(qpack_decoder);
+        let wait_blocked = |n| while qpack_decoder.decoder.read().unwrap().current_blocked_streams < n {
+            thread::sleep(time::Duration::from_millis(1));
+        };
+        let mut sections = vec![];
+        let mut instructions = vec![];
+        for i in 0..2 {
+            let headers = vec![Header::from_string(format!("custom-key{}", i), "custom-value".to_string())];
+            let mut encoded = vec![];
+            commit(qpack_encoder.encode_insert_headers(&mut encoded, headers.clone()));
+            instructions.push(encoded);
+            let mut encoded = vec![];
+            commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+            sections.push((encoded, headers));
+        }
+        // the later section waits longer, while the earlier one on the same stream finishes first
+        let decode = |(encoded, headers): (Vec<u8>, Vec<Header>)| {
+            let decoder = Arc::clone(&qpack_decoder);
+            thread::spawn(move || assert_eq!(decoder.decode_headers(&encoded, STREAM_ID).unwrap().headers, headers))
+        };
+        let second = decode(sections.pop().unwrap());
+        wait_blocked(1);
+        let first = decode(sections.pop().unwrap());
+        wait_blocked(2);
+        commit(qpack_decoder.decode_encoder_instruction(&instructions[0]));
+        first.join().unwrap();
+        commit(qpack_decoder.decode_encoder_instruction(&instructions[1]));
+        second.join().unwrap();
+        assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+
+        // both are woken by cancellation
+        let headers = vec![Header::from_str("custom-key2", "custom-value")];
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut encoded, headers.clone()));
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers, STREAM_ID));
+        let threads: Vec<_> = (0..2).map(|_| {
+            let (decoder, encoded) = (Arc::clone(&qpack_decoder), encoded.clone());
+            thread::spawn(move || decoder.decode_headers(&encoded, STREAM_ID).map_err(|e| e.to_string()))
+        }).collect();
+        wait_blocked(2);
+        assert!(qpack_decoder.cancel_blocked_stream(STREAM_ID));
+        for th in threads {
+            let err = th.join().unwrap().unwrap_err();
+            assert!(err.contains("BlockedStreamCancelled"), "{}", err);
+        }
+        assert!(!qpack_decoder.cancel_blocked_stream(STREAM_ID));
+    }
+
+    #[test]
+    fn encoder_stream_replay_after_eviction() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 200);
+        let headers: Vec<Header> = (0..4).map(|i| Header::from_string(format!("custom-key{}", i), "custom-value".to_string())).collect();
+        insert_send_ack(&qpack_encoder, &qpack_decoder, headers[..3].to_vec(), false);
+        // the fourth evicts the oldest
+        insert_headers(&qpack_encoder, &qpack_decoder, headers[3..].to_vec());
+        assert_eq!(qpack_encoder.table.get_eviction_count(), 1);
+        let err = qpack_encoder.encoder_stream_replay().err().unwrap();
+        assert_eq!(err.downcast_ref::<ReplayAfterEviction>().unwrap().0, 1);
+    }
+
+    #[test]
+    fn insert_count_increments_in_one_batch() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key0", "custom-value"),
+                                                            Header::from_str("custom-key1", "custom-value")]);
+        // each is within the 2 insertions, but not the sum
+        let err = qpack_encoder.decode_decoder_instruction(&[0x01, 0x02]).err().unwrap();
+        assert_eq!(QpackError::of(err.as_ref()), Some(QpackError::DecoderStreamError));
+        assert_eq!(qpack_encoder.acknowledgment_lag(), 2);
+        commit(qpack_encoder.decode_decoder_instruction(&[0x01, 0x01]));
+        assert_eq!(qpack_encoder.acknowledgment_lag(), 0);
+    }
+
+    #[test]
+    fn find_desync() {
+        let (qpack_client, qpack_server) = gen_client_server_instances(1, 4096);
+        insert_headers(&qpack_client, &qpack_server, vec![Header::from_str("custom-key", "custom-value")]);
+        assert_eq!(qpack_client.find_desync(&qpack_server), None);
+        let mut encoded = vec![];
+        commit(qpack_client.encode_insert_headers(&mut encoded, vec![Header::from_str("custom-key2", "custom-value")]));
+        // not delivered to the server
+        assert!(qpack_client.find_desync(&qpack_server).is_some());
+    }
+
+    #[test]
+    fn decode_headers_with_base_override_blocked() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let qpack_decoder = Arc::new(qpack_decoder);
+        let headers = vec![Header::from_str("custom-key", "custom-value")];
+        let mut instructions = vec![];
+        commit(qpack_encoder.encode_insert_headers(&mut instructions, headers.clone()));
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+
+        let decoder = Arc::clone(&qpack_decoder);
+        let th = thread::spawn(move || decoder.decode_headers_with_base_override(&encoded, STREAM_ID, 1).unwrap());
+        // counted as a blocked stream until the insertion arrives
+        while qpack_decoder.decoder.read().unwrap().current_blocked_streams == 0 {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        commit(qpack_decoder.decode_encoder_instruction(&instructions));
+        assert_eq!(th.join().unwrap(), headers);
+        assert_eq!(qpack_decoder.decoder.read().unwrap().current_blocked_streams, 0);
+        assert!(qpack_decoder.pending_sections_snapshot().decoder.is_empty());
+    }
+
+    #[test]
+    fn no_block_in_encoder_config() {
+        let mut qpack = Qpack::new(1, 1024);
+        qpack.set_no_block(true);
+        assert_eq!(qpack.encoder_config.max_required_insert_count_ahead, Some(0));
+        assert_eq!(EncoderConfig::conservative().max_required_insert_count_ahead, Some(0));
+        qpack.set_no_block(false);
+        assert_eq!(qpack.encoder_config, EncoderConfig::default());
+    }
+
+    #[test]
+    fn insert_headers_table_changed() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "custom-value")]);
+        // both duplicate the entry by relative index 0, against the same Insert Count
+        let mut first = vec![];
+        let first_commit = qpack_encoder.encode_insert_headers(&mut first, vec![Header::from_str("custom-key", "custom-value")]).unwrap();
+        let mut second = vec![];
+        let second_commit = qpack_encoder.encode_insert_headers(&mut second, vec![Header::from_str("custom-key", "custom-value")]).unwrap();
+        assert_eq!(first, second);
+        first_commit().unwrap();
+        let err = second_commit().unwrap_err();
+        assert!(err.downcast_ref::<TableChanged>().is_some());
+        assert_eq!(qpack_encoder.insert_count(), 2);
+        commit(qpack_decoder.decode_encoder_instruction(&first));
+        qpack_encoder.assert_synced(&qpack_decoder);
+    }
+
+    #[test]
+    fn find_headers_with_name_count() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "a"),
+                                                            Header::from_str("custom-key", "b"),
+                                                            Header::from_str("other-key", "a"),
+                                                            Header::from_str("custom-key", "c")]);
+        let out = qpack_encoder.table.find_headers_with_name_count(&[Header::from_str("custom-key", "b"),
+                                                                     Header::from_str("custom-key", "d"),
+                                                                     Header::from_str("other-key", "b"),
+                                                                     Header::from_str(":path", "/"),
+                                                                     Header::from_str("unknown", "")]);
+        assert_eq!(out, vec![((true, false, 1), 3),
+                             ((false, false, 3), 3),
+                             ((false, false, 2), 1),
+                             ((true, true, 1), 0),
+                             ((false, false, usize::MAX), 0)]);
+        let mut encoded = vec![];
+        commit(qpack_decoder.encode_insert_count_increment(&mut encoded));
+        commit(qpack_encoder.decode_decoder_instruction(&encoded));
+        set_table_capacity(&qpack_encoder, &qpack_decoder, 0);
+        assert_eq!(qpack_encoder.table.find_headers_with_name_count(&[Header::from_str("custom-key", "b")]),
+                   vec![((false, false, usize::MAX), 0)]);
+    }
+    #[test]
+    fn find_every_static_entry() {
+        let qpack = Qpack::new(1, 1024);
+        let static_table = qpack.table.static_table;
+        for (idx, header) in static_table.iter().enumerate() {
+            assert_eq!(qpack.table.find_headers(&[(*header).into()])[0], (true, true, idx));
+        }
+        // name only matches the lowest index, even if the same names are apart
+        assert_eq!(qpack.table.find_headers(&[Header::from_str(":status", "201")])[0], (false, true, 24));
+        assert_eq!(qpack.table.find_headers(&[Header::from_str(":status", "403")])[0], (true, true, 68));
+        assert_eq!(qpack.table.find_headers(&[Header::from_str("x-unknown", "")])[0], (false, false, usize::MAX));
+    }
+    #[test]
+    fn encode_late_static_entries() {
+        let qpack = Qpack::new(1, 1024);
+        // index beyond the 6 bit prefix continues to the next byte
+        for (header, expected) in [(Header::from_str("early-data", "1"), vec![0xff, 0x17]),
+                                   (Header::from_str("origin", ""), vec![0xff, 0x1b]),
+                                   (Header::from_str("purpose", "prefetch"), vec![0xff, 0x1c]),
+                                   (Header::from_str("x-frame-options", "sameorigin"), vec![0xff, 0x23])] {
+            let out = qpack.encode_single_header(&header).unwrap();
+            assert_eq!(out, (expected, Representation::Indexed { from_static: true }), "{:?}", header);
+        }
+        // name only, beyond the 4 bit prefix
+        for (header, expected) in [(Header::from_str("early-data", "0"), [0x5f, 0x47]),
+                                   (Header::from_str("origin", "https://example.com"), [0x5f, 0x4b]),
+                                   (Header::from_str("purpose", "other"), [0x5f, 0x4c]),
+                                   (Header::from_str("x-frame-options", "allow"), [0x5f, 0x52])] {
+            let out = qpack.encode_single_header(&header).unwrap();
+            assert_eq!(out.0[..2], expected, "{:?}", header);
+            assert_eq!(out.1, Representation::ReferName { from_static: true });
+            let decoded = qpack.decode_headers(&[&[0x00, 0x00], &out.0[..]].concat(), STREAM_ID).unwrap();
+            assert_eq!(decoded.headers, vec![header]);
+        }
+    }
+    #[test]
+    fn encode_refer_name_flags_with_long_index() {
+        let mut qpack = Qpack::new(1, 1024);
+        qpack.set_huffman_policy(Some(HuffmanPolicy::ValuesOnly));
+        let mut header = Header::from_str("user-agent", "mozilla/5.0 (x11; linux x86_64)");
+        header.set_sensitive(true);
+        let (encoded, representation) = qpack.encode_single_header(&header).unwrap();
+        assert_eq!(representation, Representation::ReferName { from_static: true });
+        // N and T bits on the first byte, index 95 continues to the next byte
+        assert_eq!(encoded[..2], [0x7f, 0x50]);
+        // H bit on the value length
+        assert_eq!(encoded[2] & 0x80, 0x80);
+        assert!(((encoded[2] & 0x7f) as usize) < header.get_value().value.len());
+        let decoded = qpack.decode_headers(&[&[0x00, 0x00], &encoded[..]].concat(), STREAM_ID).unwrap();
+        assert_eq!(decoded.headers, vec![header]);
+        assert!(decoded.headers[0].sensitive);
+    }
+    #[test]
+    fn shared_custom_static_table() {
+        static CUSTOM_STATIC_TABLE: [StrHeader; 3] = [
+            (":authority", ""),
+            ("x-custom", "a"),
+            ("x-custom", "b"),
+        ];
+        let instances: Vec<Qpack> = (0..100).map(|_| {
+            let mut qpack = Qpack::new(1, 1024);
+            qpack.set_static_table(&CUSTOM_STATIC_TABLE);
+            qpack
+        }).collect();
+        for qpack in instances.iter() {
+            assert!(std::ptr::eq(qpack.table.static_table, &CUSTOM_STATIC_TABLE[..]));
+            let out = qpack.encode_single_header(&Header::from_str("x-custom", "b")).unwrap();
+            assert_eq!(out, (vec![0xc2], Representation::Indexed { from_static: true }));
+            let out = qpack.encode_single_header(&Header::from_str(":path", "/")).unwrap();
+            assert_eq!(out.1, Representation::BothLiteral);
+        }
+
+        let mut encoded = vec![];
+        let commit_func = instances[0].encode_headers(&mut encoded, vec![Header::from_str("x-custom", "a")], STREAM_ID);
+        commit(commit_func);
+        let out = instances[99].decode_headers(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out.headers, vec![Header::from_str("x-custom", "a")]);
+    }
+    #[test]
+    fn encode_field_line_routing_at_base() {
+        let header = Header::from_str("custom-key", "custom-value");
+        let base = 5;
+        // entry just before Base is pre-base index 0, and the one at Base is post-base index 0
+        let mut encoded = vec![];
+        let out = Qpack::encode_field_line(&mut encoded, header.clone(), (true, false, base - 1), base + 2, base).unwrap();
+        assert_eq!((out, encoded), (Representation::Indexed { from_static: false }, vec![0x80]));
+        let mut encoded = vec![];
+        let out = Qpack::encode_field_line(&mut encoded, header.clone(), (true, false, base), base + 2, base).unwrap();
+        assert_eq!((out, encoded), (Representation::IndexedPostBase, vec![0x10]));
+        let mut encoded = vec![];
+        let out = Qpack::encode_field_line(&mut encoded, header.clone(), (false, false, base + 1), base + 2, base).unwrap();
+        assert_eq!(out, Representation::ReferNamePostBase);
+        assert_eq!(encoded[0], 0x01);
+
+        // beyond Required Insert Count
+        let mut encoded = vec![];
+        let err = Qpack::encode_field_line(&mut encoded, header, (true, false, base + 2), base + 2, base).unwrap_err();
+        let err = err.downcast_ref::<InvalidReference>().unwrap();
+        assert_eq!((err.idx, err.required_insert_count), (base + 2, base + 2));
+        assert!(encoded.is_empty());
+    }
+    #[test]
+    fn encode_single_header() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let out = qpack_encoder.encode_single_header(&Header::from_str(":path", "/")).unwrap();
+        assert_eq!(out, (vec![0xc1], Representation::Indexed { from_static: true }));
+        let out = qpack_encoder.encode_single_header(&Header::from_str("content-encoding", "zstd")).unwrap();
+        assert_eq!(out, (vec![0x5f, 0x1b, 0x04, 0x7a, 0x73, 0x74, 0x64], Representation::ReferName { from_static: true }));
+        let out = qpack_encoder.encode_single_header(&Header::from_str("custom-key", "custom-value")).unwrap();
+        assert_eq!(out.1, Representation::BothLiteral);
+
+        // same bytes as the section of the header without prefix
+        let header = Header::from_str("custom-key", "custom-value");
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![header.clone()]);
+        let out = qpack_encoder.encode_single_header(&header).unwrap();
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, vec![header], STREAM_ID);
+        commit(commit_func);
+        assert_eq!(out.0, encoded[2..].to_vec());
+        assert_eq!(out.1, Representation::Indexed { from_static: false });
+    }
+
+    #[test]
+    fn encoded_insert_count_wraps() {
+        let max_entries = 4;
+        assert_eq!(Qpack::encoded_insert_count(0, max_entries), 0);
+        assert_eq!(Qpack::encoded_insert_count(7, max_entries), 8);
+        assert_eq!(Qpack::encoded_insert_count(8, max_entries), 1);
+        for total_number_of_inserts in 0..40_usize {
+            // Required Insert Count is within max_entries of the Insert Count of the decoder either way
+            let oldest = total_number_of_inserts.saturating_sub(max_entries - 1).max(1);
+            for required_insert_count in oldest..=total_number_of_inserts + max_entries {
+                let encoded = Qpack::encoded_insert_count(required_insert_count, max_entries);
+                assert!((1..=2 * max_entries as u64).contains(&encoded));
+                assert_eq!(Qpack::decoded_insert_count(encoded, max_entries, total_number_of_inserts).unwrap(), required_insert_count);
+            }
+        }
+        assert_eq!(Qpack::decoded_insert_count(0, max_entries, 10).unwrap(), 0);
+        assert!(Qpack::decoded_insert_count(9, max_entries, 10).is_err());
+        assert!(Qpack::decoded_insert_count(1, 0, 0).is_err());
+    }
+
+    #[test]
+    fn required_insert_count_wraps_round_trip() {
+        // MaxEntries 4, Encoded Required Insert Count wraps every 8 insertions
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 128);
+        assert_eq!(qpack_encoder.table.get_max_entries(), 4);
+        let qpack_decoder = Arc::new(qpack_decoder);
+        for i in 0..50 {
+            let headers = vec![Header::from_string(format!("key{}", i), "value".to_string())];
+            let mut instructions = vec![];
+            commit(qpack_encoder.encode_insert_headers(&mut instructions, headers.clone()));
+            let mut encoded = vec![];
+            commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+            assert_eq!(encoded[0] as usize, (i + 1) % 8 + 1);
+
+            // every other section arrives before the insertion, one behind the decoder
+            let out = if i % 2 == 0 {
+                commit(qpack_decoder.decode_encoder_instruction(&instructions));
+                qpack_decoder.decode_headers_detailed(&encoded, STREAM_ID).unwrap()
+            } else {
+                let decoder = Arc::clone(&qpack_decoder);
+                let th = thread::spawn(move || decoder.decode_headers_detailed(&encoded, STREAM_ID).unwrap());
+                while qpack_decoder.decoder.read().unwrap().current_blocked_streams == 0 {
+                    thread::sleep(time::Duration::from_millis(1));
+                }
+                commit(qpack_decoder.decode_encoder_instruction(&instructions));
+                th.join().unwrap()
+            };
+            assert_eq!(out.headers, headers);
+            assert_eq!(out.required_insert_count, i + 1);
+            section_ackowledgment(&qpack_encoder, &qpack_decoder, STREAM_ID);
+        }
+        assert_eq!(qpack_encoder.known_received_count(), 50);
+        qpack_encoder.assert_synced(&qpack_decoder);
+    }
+
+    #[test]
+    fn decode_zero_byte_field_line() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "custom-value")]);
+        // Required Insert Count 1, Base 0. 0x00 is Literal Field Line with Post-Base Name Reference to
+        // relative index 0, not a terminator or an Indexed Field Line
+        let wire = vec![0x02, 0x80, 0x00, 0x03, 0x61, 0x62, 0x63];
+        let out = qpack_decoder.decode_headers(&wire, STREAM_ID).unwrap();
+        assert_eq!(out.headers, vec![Header::from_str("custom-key", "abc")]);
+        assert!(!out.headers[0].sensitive);
+        assert_eq!(out.action, DecoderAction::SendAck);
+
+        // the same representation as encoded
+        let mut encoded = vec![];
+        Encoder::encode_refer_name_post_base(&mut encoded, 0, Header::from_str("custom-key", "abc")).unwrap();
+        assert_eq!(encoded, wire[2..]);
+    }
+
+    #[test]
+    fn decode_headers_detailed() {
+        let (qpack_encoder, qpack_decoder) = gen_client_server_instances(1, 1024);
+        let headers = vec![Header::from_str("custom-key", "custom-value"), Header::from_str("custom-key2", "custom-value2")];
+        insert_headers(&qpack_encoder, &qpack_decoder, headers.clone());
+        let mut encoded = vec![];
+        commit(qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID));
+        // Required Insert Count 2 is encoded as 3
+        assert_eq!(encoded[0], 0x03);
+        let out = qpack_decoder.decode_headers_detailed(&encoded, STREAM_ID).unwrap();
+        assert_eq!(out, DecodedSection { headers, required_insert_count: 2, referenced_dynamic: true });
+
+        let out = qpack_decoder.decode_headers_detailed(&[0x00, 0x00, 0xc1], STREAM_ID).unwrap();
+        assert_eq!((out.required_insert_count, out.referenced_dynamic), (0, false));
+    }
+
+    #[test]
+    fn decode_headers_with_len() {
+        let qpack = Qpack::new(1, 1024);
+        // :path /, then bytes of the next frame
+        let wire = vec![0x00, 0x00, 0xc1, 0x00, 0x04, 0xff];
+        let (out, consumed) = qpack.decode_headers_with_len(&wire, 3, STREAM_ID).unwrap();
+        assert_eq!(out.headers, vec![Header::from_str(":path", "/")]);
+        assert_eq!(consumed, 3);
+        // the junk would be decoded as field lines without the length
+        assert!(qpack.decode_headers(&wire, STREAM_ID).is_err());
+        let err = qpack.decode_headers_with_len(&wire, 7, STREAM_ID).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::Truncated);
+    }
+
+    #[test]
+    fn decode_headers_from_push_stream() {
+        let (qpack_encoder, mut qpack_decoder) = gen_client_server_instances(1, 4096);
+        qpack_decoder.set_strict(true);
+        let headers = get_request_headers(false);
+        let mut encoded = vec![];
+        let commit_func = qpack_encoder.encode_headers(&mut encoded, headers.clone(), STREAM_ID);
+        commit(commit_func);
+        let out = qpack_decoder.decode_headers_from(&encoded, STREAM_ID, StreamType::Push).unwrap();
+        assert_eq!(out.headers, headers);
+
+        let err = qpack_decoder.decode_headers_from(&encoded, STREAM_ID, StreamType::Decoder).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::NotFieldSection);
+    }
+
+    #[test]
+    fn acknowledge_all_inserts_after_interval() {
+        let (qpack_encoder, mut qpack_decoder) = gen_client_server_instances(1, 256);
+        qpack_decoder.set_stall_ack_interval(time::Duration::from_millis(50));
+        insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", "custom-value0")]);
+        let mut encoded = vec![];
+        commit(qpack_decoder.encode_insert_count_increment(&mut encoded));
+        commit(qpack_encoder.decode_decoder_instruction(&encoded));
+        for i in 1..4 {
+            insert_headers(&qpack_encoder, &qpack_decoder, vec![Header::from_str("custom-key", &format!("custom-value{}", i))]);
+        }
+
+        // table is full, but acknowledged recently
+        assert!(!qpack_decoder.encoder_likely_stalled());
+        let mut encoded = vec![];
+        commit(qpack_decoder.acknowledge_all_inserts(&mut encoded));
+        assert!(encoded.is_empty());
+        assert_eq!(qpack_decoder.table.get_known_received_count(), 1);
+
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(qpack_decoder.encoder_likely_stalled());
+        commit(qpack_decoder.acknowledge_all_inserts(&mut encoded));
+        assert_eq!(encoded, vec![0x03]);
+        commit(qpack_encoder.decode_decoder_instruction(&encoded));
+        assert_eq!(qpack_encoder.table.get_known_received_count(), 4);
+        assert!(!qpack_decoder.encoder_likely_stalled());
+    }
 }
\ No newline at end of file
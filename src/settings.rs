@@ -0,0 +1,76 @@
+use std::{collections::HashSet, error};
+
+use crate::InvalidSettings;
+
+pub const SETTINGS_QPACK_MAX_TABLE_CAPACITY: u64 = 0x01;
+pub const SETTINGS_QPACK_BLOCKED_STREAMS: u64 = 0x07;
+
+// QPACK parameters in HTTP/3 SETTINGS. Both default to 0 when absent (# 5)
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct QpackSettings {
+    pub max_table_capacity: u64,
+    pub blocked_streams: u64,
+}
+
+// Parses the payload of a SETTINGS frame, without the frame type and length.
+// Identifiers and values are variable-length integers of QUIC (RFC 9000 16),
+// not the prefix integers of QPACK. Unknown identifiers are ignored.
+pub fn parse(buf: &[u8]) -> Result<QpackSettings, Box<dyn error::Error>> {
+    let mut settings = QpackSettings::default();
+    let mut seen = HashSet::new();
+    let mut idx = 0;
+    while idx < buf.len() {
+        let (len, identifier) = decode_varint(buf, idx)?;
+        idx += len;
+        let (len, value) = decode_varint(buf, idx)?;
+        idx += len;
+        if !seen.insert(identifier) {
+            return Err(InvalidSettings("duplicate identifier").into());
+        }
+        match identifier {
+            SETTINGS_QPACK_MAX_TABLE_CAPACITY => settings.max_table_capacity = value,
+            SETTINGS_QPACK_BLOCKED_STREAMS => settings.blocked_streams = value,
+            _ => {},
+        }
+    }
+    Ok(settings)
+}
+
+// 2 most significant bits of the first byte tell the length, 1, 2, 4 or 8 bytes
+fn decode_varint(buf: &[u8], idx: usize) -> Result<(usize, u64), Box<dyn error::Error>> {
+    let first = *buf.get(idx).ok_or(InvalidSettings("truncated"))?;
+    let len = 1 << (first >> 6);
+    let bytes = buf.get(idx..idx + len).ok_or(InvalidSettings("truncated"))?;
+    let value = bytes[1..].iter().fold((first & 0b00111111) as u64, |value, byte| (value << 8) | *byte as u64);
+    Ok((len, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{InvalidSettings, Qpack};
+    use super::{parse, QpackSettings};
+
+    #[test]
+    fn parse_settings() {
+        let buf = [
+            0x01, 0x50, 0x00,       // SETTINGS_QPACK_MAX_TABLE_CAPACITY 4096 (2 bytes)
+            0x06, 0x44, 0x00,       // SETTINGS_MAX_FIELD_SECTION_SIZE 1024, ignored
+            0x21, 0x00,             // reserved 0x1f * N + 0x21, ignored
+            0x07, 0x10,             // SETTINGS_QPACK_BLOCKED_STREAMS 16
+        ];
+        let settings = parse(&buf).unwrap();
+        assert_eq!(settings, QpackSettings { max_table_capacity: 4096, blocked_streams: 16 });
+
+        let qpack = Qpack::with_settings(&settings);
+        assert_eq!(qpack.table.get_max_entries(), 4096 / 32);
+        assert_eq!(qpack.max_blocked_streams(), 16);
+
+        assert_eq!(parse(&[]).unwrap(), QpackSettings::default());
+        // value is missing
+        assert!(parse(&buf[..9]).unwrap_err().downcast_ref::<InvalidSettings>().is_some());
+        // value ends in the middle
+        assert!(parse(&buf[..2]).is_err());
+        // duplicate identifier
+        assert!(parse(&[0x07, 0x10, 0x07, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+use std::error;
+
+use crate::Qpack;
+use crate::transformer::decoder::Decoder;
+use crate::transformer::encoder::Encoder;
+use crate::types::CommitFunc;
+
+// Stateful counterpart of Qpack::decode_encoder_instruction.
+// Encoder stream bytes can be pushed as they arrive from the transport,
+// an instruction split across chunks is kept until the rest of it arrives.
+pub struct EncoderStreamDecoder<'a> {
+    qpack: &'a Qpack,
+    buf: Vec<u8>,
+}
+
+impl<'a> EncoderStreamDecoder<'a> {
+    pub fn new(qpack: &'a Qpack) -> Self {
+        Self {
+            qpack,
+            buf: vec![],
+        }
+    }
+    // returns a commit func for each complete instruction. They must be committed in order
+    // before the next push, as following instructions may refer entries inserted by them
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<CommitFunc>, Box<dyn error::Error>> {
+        self.buf.extend_from_slice(chunk);
+        let mut commit_funcs = vec![];
+        let mut idx = 0;
+        while let Some(len) = Decoder::encoder_instruction_len(&self.buf, idx) {
+            commit_funcs.push(self.qpack.decode_encoder_instruction(&self.buf[idx..idx + len])?);
+            idx += len;
+        }
+        self.buf.drain(..idx);
+        Ok(commit_funcs)
+    }
+    // number of bytes waiting for the rest of an instruction
+    pub fn pending_len(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+// Stateful counterpart of Qpack::decode_decoder_instruction.
+pub struct DecoderStreamDecoder<'a> {
+    qpack: &'a Qpack,
+    buf: Vec<u8>,
+}
+
+impl<'a> DecoderStreamDecoder<'a> {
+    pub fn new(qpack: &'a Qpack) -> Self {
+        Self {
+            qpack,
+            buf: vec![],
+        }
+    }
+    // returns a commit func for each complete instruction. They must be committed in order
+    // before the next push, as validation of following instructions depends on them
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<CommitFunc>, Box<dyn error::Error>> {
+        self.buf.extend_from_slice(chunk);
+        let mut commit_funcs = vec![];
+        let mut idx = 0;
+        while let Some(len) = Encoder::decoder_instruction_len(&self.buf, idx) {
+            commit_funcs.push(self.qpack.decode_decoder_instruction(&self.buf[idx..idx + len])?);
+            idx += len;
+        }
+        self.buf.drain(..idx);
+        Ok(commit_funcs)
+    }
+    // number of bytes waiting for the rest of an instruction
+    pub fn pending_len(&self) -> usize {
+        self.buf.len()
+    }
+}
@@ -1,6 +1,6 @@
-use std::{collections::{HashMap, VecDeque}, error, sync::{Arc, Condvar, Mutex, RwLockWriteGuard}};
+use std::{collections::{HashMap, VecDeque}, error, io::{self, Write}, sync::{Arc, Condvar, Mutex, RwLockWriteGuard}};
 
-use crate::{DecompressionFailed, EncoderStreamError, Header, types::DynamicHeader};
+use crate::{CAPACITY_EXCEEDS_MAX, DecompressReason, DecompressionFailed, EncoderStreamError, Header, types::DynamicHeader};
 
 pub type CommitFuncWithDynamicTable = Box<dyn FnOnce(&mut RwLockWriteGuard<DynamicTable>) -> Result<(), Box<dyn error::Error>>>;
 
@@ -50,6 +50,8 @@ pub struct DynamicTable {
     pub eviction_count: usize,
     both_mapping: HashMap<(String, String), usize>,
     key_mapping: HashMap<String, usize>,
+    // number of entries of each name
+    name_counts: HashMap<String, usize>,
 }
 
 lazy_static! {
@@ -70,6 +72,7 @@ impl DynamicTable {
             eviction_count: 0,
             both_mapping: HashMap::new(),
             key_mapping: HashMap::new(),
+            name_counts: HashMap::new(),
         }
     }
     pub fn get_insert_count(&self) -> usize {
@@ -90,19 +93,20 @@ impl DynamicTable {
         ids.iter().for_each(|id| {
             let _ = self.deref_entry_at(*id);
         });
-        self.known_received_count = section;
+        // acknowledging an older section does not decrease it (# 4.4.1)
+        self.known_received_count = self.known_received_count.max(section);
     }
     pub fn cancel_section(&mut self, ids: Vec<usize>) {
         ids.iter().for_each(|id| {
             let _ = self.deref_entry_at(*id);
         });
     }
-    pub fn is_insertable(&self, headers: &Vec<Header>) -> bool {
+    pub fn is_insertable(&self, headers: &[Header]) -> bool {
         let mut size = 0;
         for header in headers {
             size += header.size();
         }
-        let upto = if self.capacity < size {0} else {self.capacity - size};
+        let upto = self.capacity.saturating_sub(size);
         self.is_evictable_upto(upto)
     }
     fn is_evictable_upto(&self, upto: usize) -> bool {
@@ -110,7 +114,7 @@ impl DynamicTable {
         let mut idx = 0;
         while idx < self.list.len() && upto < current_size {
             let entry = &self.list[idx];
-            if entry.outstanding_count > 0 || self.known_received_count < idx {
+            if entry.outstanding_count > 0 || self.known_received_count <= self.eviction_count + idx {
                 return false;
             }
             current_size -= entry.size;
@@ -122,44 +126,73 @@ impl DynamicTable {
         let mut current_size = self.current_size;
         let mut idx = 0;
         while upto < current_size {
-            if self.known_received_count < idx {
-                // trying to evict non-evictable entry
-                return Err(EncoderStreamError.into())
-            }
             let entry = &self.list[idx];
+            // the insertion has to be acknowledged as well as the references (# 2.1.1).
+            // absolute index is compared, as evicted entries are gone from the list
+            if entry.outstanding_count > 0 || self.known_received_count <= self.eviction_count + idx {
+                return Err(EncoderStreamError("evicting unacknowledged entry").into())
+            }
             current_size -= entry.size;
             idx += 1;
         }
         while idx > 0 {
             let entry = self.list.pop_front();
-            self.remove_entry_mapping(entry.unwrap());
+            self.remove_entry_mapping(&entry.unwrap());
             self.eviction_count += 1;
             idx -= 1;
         }
         self.current_size = current_size;
         Ok(())
     }
-    fn insert_entry_mapping(&mut self, entry: Box<Entry>, insert_count: usize) {
+    fn insert_entry_mapping(&mut self, entry: &Entry, insert_count: usize) {
         let header = entry.header.clone();
-        self.both_mapping.insert((*header.0.clone(), header.1), insert_count-1);
-        self.key_mapping.insert(*header.0, insert_count-1);
+        self.both_mapping.insert((header.0.clone(), header.1), insert_count-1);
+        *self.name_counts.entry(header.0.clone()).or_default() += 1;
+        self.key_mapping.insert(header.0, insert_count-1);
     }
-    fn remove_entry_mapping(&mut self, entry: Box<Entry>) {
+    // called for every evicted entry, so that the mappings never hold more keys than live entries.
+    // a key pointing to a newer entry with the same name (or name and value) is kept
+    fn remove_entry_mapping(&mut self, entry: &Entry) {
         let header = entry.header.clone();
-        let both_key = (*header.0.clone(), header.1);
-        let key_key = *header.0;
+        let both_key = (header.0.clone(), header.1);
+        let key_key = header.0;
         if let Some(abs_index) = self.both_mapping.get(&both_key) {
             if *abs_index == self.eviction_count {
                 self.both_mapping.remove(&both_key);
             }
         }
 
+        if let Some(count) = self.name_counts.get_mut(&key_key) {
+            *count -= 1;
+            if *count == 0 {
+                self.name_counts.remove(&key_key);
+            }
+        }
         if let Some(abs_index) = self.key_mapping.get(&key_key) {
             if *abs_index == self.eviction_count {
                 self.key_mapping.remove(&key_key);
             }
         }
     }
+    // copy of entries and counts detached from the live table, with its own insert count
+    pub fn snapshot(&self) -> Self {
+        Self {
+            list: self.list.clone(),
+            current_size: self.current_size,
+            capacity: self.capacity,
+            known_received_count: self.known_received_count,
+            max_capacity: self.max_capacity,
+            cv_insert_count: Arc::new((Mutex::new(self.get_insert_count()), Condvar::new())),
+            eviction_count: self.eviction_count,
+            both_mapping: self.both_mapping.clone(),
+            key_mapping: self.key_mapping.clone(),
+            name_counts: self.name_counts.clone(),
+        }
+    }
+    // entries from the oldest
+    pub fn get_headers(&self) -> Vec<Header> {
+        self.list.iter().map(|entry| Header::from((*entry.header).clone())).collect()
+    }
     pub fn dump_entries(&self) {
         // TODO: selective output target to do test table contents
         let insert_count = self.get_insert_count();
@@ -170,44 +203,102 @@ impl DynamicTable {
                 println!("v-------- acked sections --------v");
             }
             println!("\tAbs:{}, Refs:{}, ({}={})", idx, entry.outstanding_count, entry.header.0, entry.header.1);
-            if idx != 0 {
-                idx -= 1;
+            idx = idx.saturating_sub(1);
+        }
+    }
+    // same format as dump_entries, but only entries whose absolute index is in [start_abs, start_abs + count)
+    pub fn dump_range(&self, start_abs: usize, count: usize, w: &mut dyn Write) -> io::Result<()> {
+        let start = start_abs.max(self.eviction_count);
+        let end = start_abs.saturating_add(count).min(self.get_insert_count());
+        for idx in (start..end).rev() {
+            if idx + 1 == self.known_received_count {
+                writeln!(w, "v-------- acked sections --------v")?;
             }
+            let entry = &self.list[idx - self.eviction_count];
+            writeln!(w, "\tAbs:{}, Refs:{}, ({}={})", idx, entry.outstanding_count, entry.header.0, entry.header.1)?;
         }
+        Ok(())
+    }
+    // returns description of the first difference against other table, None if both are the same
+    pub fn find_desync(&self, other: &DynamicTable) -> Option<String> {
+        let insert_count = self.get_insert_count();
+        let other_insert_count = other.get_insert_count();
+        if insert_count != other_insert_count {
+            return Some(format!("Insert Count differs: {} != {}", insert_count, other_insert_count));
+        }
+        if self.list.len() != other.list.len() {
+            return Some(format!("Number of entries differs: {} != {}", self.list.len(), other.list.len()));
+        }
+        let first_abs_idx = insert_count - self.list.len();
+        for (i, (entry, other_entry)) in self.list.iter().zip(other.list.iter()).enumerate() {
+            if entry.header != other_entry.header {
+                return Some(format!("Abs:{} differs: ({}={}) != ({}={})", first_abs_idx + i,
+                                    entry.header.0, entry.header.1, other_entry.header.0, other_entry.header.1));
+            }
+        }
+        None
     }
     pub fn find_index(&self, target: &Header) -> (bool, usize) {
         if let Some(abs_index) = self.both_mapping.get(&(target.get_name().value.clone(), target.get_value().value.clone())) {
-            return (true, abs_index - self.eviction_count);
+            return (true, *abs_index);
         }
         if let Some(abs_index) = self.key_mapping.get(&target.get_name().value) {
-            return (false, abs_index - self.eviction_count);
+            return (false, *abs_index);
         }
         (false, usize::MAX)
     }
+    // entries before the returned absolute index are evicted by inserting fraction of capacity or less
+    pub fn draining_index(&self, fraction: f64) -> usize {
+        let draining_size = (self.capacity as f64 * fraction) as usize;
+        let mut room = self.capacity.saturating_sub(self.current_size);
+        let mut idx = self.eviction_count;
+        for entry in self.list.iter() {
+            room += entry.size;
+            if draining_size < room {
+                break;
+            }
+            idx += 1;
+        }
+        idx
+    }
+    pub fn get_name_count(&self, name: &str) -> usize {
+        self.name_counts.get(name).copied().unwrap_or(0)
+    }
+    // entry at idx is evicted by inserting a quarter of capacity or less
+    pub fn is_at_eviction_risk(&self, abs_idx: usize) -> bool {
+        let idx = match abs_idx.checked_sub(self.eviction_count) {
+            Some(idx) if idx < self.list.len() => idx,
+            _ => return false,
+        };
+        let free = self.capacity.saturating_sub(self.current_size);
+        let upto: usize = self.list.iter().take(idx + 1).map(|entry| entry.size).sum();
+        free + upto <= self.capacity / 4
+    }
+    // idx is absolute so that it stays valid across evictions while the section is outstanding
     pub fn ref_entry_at(&mut self, idx: usize) -> Result<(), Box<dyn error::Error>> {
-        match self.list.get_mut(idx) {
+        match idx.checked_sub(self.eviction_count).and_then(|idx| self.list.get_mut(idx)) {
             Some(entry) => entry.outstanding_count += 1,
-            None => return Err(DecompressionFailed.into())
+            None => return Err(DecompressionFailed::new(DecompressReason::InvalidDynamicIndex, 0).into())
         }
         Ok(())
     }
     pub fn deref_entry_at(&mut self, idx: usize) -> Result<(), Box<dyn error::Error>> {
-        match self.list.get_mut(idx) {
+        match idx.checked_sub(self.eviction_count).and_then(|idx| self.list.get_mut(idx)) {
             Some(entry) => entry.outstanding_count -= 1,
-            None => return Err(DecompressionFailed.into())
+            None => return Err(DecompressionFailed::new(DecompressReason::InvalidDynamicIndex, 0).into())
         }
         Ok(())
     }
     pub fn insert_table_entry(&mut self, entry: Box<Entry>) -> Result<(), Box<dyn error::Error>> {
         let size = entry.size;
         if self.capacity < size {
-            return Err(EncoderStreamError.into());
+            return Err(EncoderStreamError("entry is larger than capacity").into());
         }
         self.evict_upto(self.capacity - size)?;
         self.list.push_back(entry.clone());
 
         let insert_count = self.increment_insert_count();
-        self.insert_entry_mapping(entry, insert_count);
+        self.insert_entry_mapping(&entry, insert_count);
 
         self.current_size += size;
         Ok(())
@@ -217,20 +308,25 @@ impl DynamicTable {
         self.insert_table_entry(Box::new(Entry::new(Box::new(header.into()))))
     }
     pub fn get_entry(&self, abs_idx: usize) -> Result<Box<Entry>, Box<dyn error::Error>> {
-        match self.list.get(abs_idx) {
+        match abs_idx.checked_sub(self.eviction_count).and_then(|idx| self.list.get(idx)) {
             Some(entry) => Ok((*entry).clone()),
-            None => Err(DecompressionFailed.into())
+            None => Err(DecompressionFailed::new(DecompressReason::InvalidDynamicIndex, 0).into())
         }
     }
+    // relative index of encoder instructions (# 3.2.5)
+    pub fn get_relative_entry(&self, idx: usize) -> Option<Box<Entry>> {
+        let abs_idx = self.get_insert_count().checked_sub(idx.checked_add(1)?)?;
+        self.get_entry(abs_idx).ok()
+    }
     pub fn get(&self, abs_idx: usize) -> Result<Header, Box<dyn error::Error>> {
-        match self.list.get(abs_idx) {
+        match abs_idx.checked_sub(self.eviction_count).and_then(|idx| self.list.get(idx)) {
             Some(entry) => Ok(Header::from((*entry.header).clone())),
-            None => Err(DecompressionFailed.into())
+            None => Err(DecompressionFailed::new(DecompressReason::InvalidDynamicIndex, 0).into())
         }
     }
     pub fn set_capacity(&mut self, cap: usize) -> Result<(), Box<dyn error::Error>> {
         if self.max_capacity < cap {
-            return Err(EncoderStreamError.into());
+            return Err(EncoderStreamError(CAPACITY_EXCEEDS_MAX).into());
         }
         self.evict_upto(cap)?;
         self.capacity = cap;
@@ -311,6 +407,17 @@ mod test {
         verify_insert(&table, size, 1, 1);
     }
     #[test]
+    fn entry_contents() {
+        let header = Box::new(DynamicHeader::from_str(":path", "/index.html"));
+        assert_eq!(Header::from((*header).clone()), Header::from_str(":path", "/index.html"));
+        let entry = Entry::new(header);
+        let refer_name = Entry::refer_name(entry.clone(), "/".to_string());
+        assert_eq!(*refer_name.header, DynamicHeader::from_str(":path", "/"));
+        assert_eq!(refer_name.size, 32 + 5 + 1);
+        let duplicate = Entry::duplicate(entry.clone());
+        assert_eq!((duplicate.header, duplicate.size), (entry.header, entry.size));
+    }
+    #[test]
     fn insert_table_entry_bigger_than_cap() {
         let cap = 10;
         let mut table = gen_table();
@@ -337,6 +444,90 @@ mod test {
         assert_eq!(header.unwrap(), headers[1]);
     }
     #[test]
+    fn mapping_bounded_by_entries() {
+        let mut table = gen_table();
+        let _ = table.set_capacity(512);
+        table.known_received_count = usize::MAX;
+        for i in 0..1000 {
+            let _ = table.insert_header(Header::from_str(&format!("name-{:04}", i), "value"));
+            assert_eq!(table.both_mapping.len(), table.list.len());
+            assert_eq!(table.key_mapping.len(), table.list.len());
+            assert_eq!(table.name_counts.len(), table.list.len());
+        }
+        assert!(0 < table.eviction_count);
+        // same name with another value replaces the key mapping
+        let _ = table.insert_header(Header::from_str("name-0999", "value2"));
+        assert!(table.key_mapping.len() < table.list.len());
+        assert!(table.both_mapping.len() <= table.list.len());
+        let _ = table.set_capacity(0);
+        assert!(table.list.is_empty());
+        assert!(table.both_mapping.is_empty());
+        assert!(table.key_mapping.is_empty());
+        assert!(table.name_counts.is_empty());
+    }
+    #[test]
+    fn find_index_same_name() {
+        let mut table = gen_table();
+        let _ = table.set_capacity(512);
+        // newest entry matching both, then name, as scanning entries from the newest
+        let linear_scan = |table: &DynamicTable, target: &Header| -> (bool, usize) {
+            let entries = || table.list.iter().enumerate().rev();
+            let pair = (target.get_name().value.as_str(), target.get_value().value.as_str());
+            if let Some((idx, _)) = entries().find(|(_, entry)| (entry.header.0.as_str(), entry.header.1.as_str()) == pair) {
+                return (true, table.eviction_count + idx);
+            }
+            match entries().find(|(_, entry)| entry.header.0.as_str() == pair.0) {
+                Some((idx, _)) => (false, table.eviction_count + idx),
+                None => (false, usize::MAX),
+            }
+        };
+        let targets: Vec<Header> = (0..8).map(|i| Header::from_str("same-name", &format!("value-{}", i)))
+            .chain(vec![Header::from_str("other-name", "value-0")]).collect();
+        for i in 0..1000 {
+            // every insertion is acknowledged so that the oldest ones can be evicted
+            table.known_received_count = table.get_insert_count();
+            table.insert_header(Header::from_str("same-name", &format!("value-{}", i * 3 % 7))).unwrap();
+            for target in &targets {
+                assert_eq!(table.find_index(target), linear_scan(&table, target));
+            }
+        }
+        assert!(0 < table.eviction_count);
+    }
+    #[test]
+    fn insert_evicts_draining_entries() {
+        let mut table = gen_table();
+        let headers: Vec<Header> = (0..4).map(|i| Header::from_str(&format!("name-{}", i), "value")).collect();
+        let _ = table.set_capacity(headers[0].size() * 4);
+        for header in &headers {
+            table.insert_header(header.clone()).unwrap();
+        }
+        // full of unreferenced entries, none of them acknowledged yet
+        assert!(!table.is_insertable(&headers[..1]));
+        assert!(table.insert_header(Header::from_str("name-4", "value")).is_err());
+
+        // acknowledged ones are evicted from the oldest to make room
+        table.ack_section(4, vec![]);
+        assert!(table.is_insertable(&headers[..2]));
+        table.insert_header(Header::from_str("name-4", "value")).unwrap();
+        table.insert_header(Header::from_str("name-5", "value")).unwrap();
+        assert_eq!((table.eviction_count, table.list.len()), (2, 4));
+        assert_eq!(table.get(2).unwrap(), headers[2]);
+
+        // unacknowledged reference prevents eviction as well
+        table.ref_entry_at(2).unwrap();
+        assert!(!table.is_insertable(&headers[..1]));
+        assert!(table.insert_header(Header::from_str("name-6", "value")).is_err());
+        table.ack_section(4, vec![2]);
+        table.insert_header(Header::from_str("name-6", "value")).unwrap();
+        assert_eq!(table.eviction_count, 3);
+        // the entry inserted before is not acknowledged (absolute index 4 against Known Received Count 4)
+        table.insert_header(Header::from_str("name-7", "value")).unwrap();
+        assert!(table.insert_header(Header::from_str("name-8", "value")).is_err());
+        // acknowledging an older section keeps Known Received Count
+        table.ack_section(1, vec![]);
+        assert_eq!(table.known_received_count, 4);
+    }
+    #[test]
     fn get_not_found() {
         let table = gen_table();
         let out = table.get(128).unwrap_err();
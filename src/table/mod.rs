@@ -1,31 +1,49 @@
 mod dynamic_table;
 
+use std::collections::HashMap;
 use std::error;
+use std::io::{self, Write};
 use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
 
 use crate::transformer::encoder::Encoder;
-use crate::types::{HeaderString, StrHeader};
-use crate::{DecompressionFailed, Header};
+use crate::types::{HeaderString, StrHeader, StreamId};
+use crate::{DecompressReason, DecompressionFailed, EncoderStreamError, Header};
 
 use self::dynamic_table::{CommitFuncWithDynamicTable, DynamicTable, Entry};
 
 pub struct Table {
     pub dynamic_table: Arc<RwLock<DynamicTable>>,
+    // shared by all instances, never copied
+    pub static_table: &'static [StrHeader<'static>],
 }
 
 impl Table {
     pub fn new(max_capacity: usize, cv: Arc<(Mutex<usize>, Condvar)>) -> Self {
         Self {
             dynamic_table: Arc::new(RwLock::new(DynamicTable::new(max_capacity, cv))),
+            static_table: &STATIC_TABLE,
         }
     }
-    // TODO: return (both_matched, on_static_table, idx)
-    //       try to remove on_static_table as my HPACK did not use
-    pub fn find_header(&self, target: &Header) -> (bool, bool, usize) {
+    // lookup static table only. (false, false, usize::MAX) means not found
+    pub fn find_static_header(&self, target: &Header) -> (bool, bool, usize) {
+        if !std::ptr::eq(self.static_table, &STATIC_TABLE[..]) {
+            return self.scan_static_header(target);
+        }
+        let candidates = match STATIC_NAME_INDICES.get(target.get_name().value.as_str()) {
+            Some(candidates) => candidates,
+            None => return (false, false, usize::MAX),
+        };
+        match candidates.iter().find(|idx| STATIC_TABLE[**idx].1 == target.get_value().value) {
+            Some(idx) => (true, true, *idx),
+            None => (false, true, candidates[0]),
+        }
+    }
+    // custom static table is not indexed
+    fn scan_static_header(&self, target: &Header) -> (bool, bool, usize) {
         let not_found_val = usize::MAX;
 
         let mut static_candidate_idx: usize = not_found_val;
-        for (idx, (name, val)) in STATIC_TABLE.iter().enumerate() {
+        for (idx, (name, val)) in self.static_table.iter().enumerate() {
             if target.get_name().value.eq(*name) {
                 if target.get_value().value.eq(*val) {
                     // match both
@@ -33,36 +51,51 @@ impl Table {
                 }
                 if static_candidate_idx == not_found_val {
                     static_candidate_idx = idx;
-                } else if STATIC_TABLE[static_candidate_idx].0.ne(*name) {
+                } else if self.static_table[static_candidate_idx].0.ne(*name) {
                     // match name
                     return (false, true, static_candidate_idx);
                 }
             }
         }
-
-        let ret = self.dynamic_table.read().unwrap().find_index(target);
-        if ret.1 == not_found_val && static_candidate_idx != not_found_val {
+        if static_candidate_idx != not_found_val {
             return (false, true, static_candidate_idx);
         }
+        (false, false, not_found_val)
+    }
+    // TODO: return (both_matched, on_static_table, idx)
+    //       try to remove on_static_table as my HPACK did not use
+    fn find_header_in(&self, target: &Header, dynamic_table: &DynamicTable) -> (bool, bool, usize) {
+        let static_ret = self.find_static_header(target);
+        if static_ret.0 {
+            return static_ret;
+        }
+
+        let ret = dynamic_table.find_index(target);
+        if ret.1 == usize::MAX && static_ret.1 {
+            return static_ret;
+        }
 
         (ret.0, false, ret.1) // (false, false, usize::MAX) means not found
     }
-    pub fn find_headers(&self, headers: &Vec<Header>) -> Vec<(bool, bool, usize)> {
-        // TODO: read lock dynamic table?
-        let mut out = vec![];
-        for header in headers {
-            out.push(self.find_header(header));
-        }
-        out
+    pub fn find_headers(&self, headers: &[Header]) -> Vec<(bool, bool, usize)> {
+        self.find_headers_with_name_count(headers).into_iter().map(|(ret, _)| ret).collect()
     }
-    pub fn is_insertable(&self, headers: &Vec<Header>) -> bool {
+    // looked up under a single read lock, with the number of dynamic entries of the name of each header.
+    // several entries of a name hint Duplicate rather than another insertion referring it
+    pub fn find_headers_with_name_count(&self, headers: &[Header]) -> Vec<((bool, bool, usize), usize)> {
+        let dynamic_table = self.dynamic_table.read().unwrap();
+        headers.iter()
+            .map(|header| (self.find_header_in(header, &dynamic_table), dynamic_table.get_name_count(&header.get_name().value)))
+            .collect()
+    }
+    pub fn is_insertable(&self, headers: &[Header]) -> bool {
         self.dynamic_table.read().unwrap().is_insertable(headers)
     }
     pub fn get_header_from_static(&self, idx: usize) -> Result<Header, Box<dyn error::Error>> {
-        if STATIC_TABLE_SIZE <= idx {
-            return Err(DecompressionFailed.into());
+        match self.static_table.get(idx) {
+            Some(header) => Ok((*header).into()),
+            None => Err(DecompressionFailed::new(DecompressReason::InvalidStaticIndex, 0).into()),
         }
-        Ok(STATIC_TABLE[idx].into())
     }
     fn calc_abs_index(&self, base: usize, idx: usize, post_base: bool) -> usize {
         if post_base {
@@ -74,9 +107,6 @@ impl Table {
     pub fn get_header_from_dynamic(&self, base: usize, idx: usize, post_base: bool) -> Result<Header, Box<dyn error::Error>> {
         self.dynamic_table.read().unwrap().get(self.calc_abs_index(base, idx, post_base))
     }
-    pub fn get_entry_from_dynamic(&self, base: usize, idx: usize, post_base: bool) -> Result<Box<Entry>, Box<dyn error::Error>> {
-        self.dynamic_table.read().unwrap().get_entry(self.calc_abs_index(base, idx, post_base))
-    }
     pub fn set_dynamic_table_capacity(&self, capacity: usize)
     -> Result<CommitFuncWithDynamicTable, Box<dyn error::Error>> {
         Ok(Box::new(move |dynamic_table: &mut RwLockWriteGuard<DynamicTable>| -> Result<(), Box<dyn error::Error>> {
@@ -88,16 +118,19 @@ impl Table {
     pub fn insert_refer_name(&self, idx: usize, value: HeaderString, on_static: bool)
     -> Result<CommitFuncWithDynamicTable, Box<dyn error::Error>> {
         if on_static {
-            let mut header = self.get_header_from_static(idx)?;
+            // invalid reference on the encoder stream is a connection error, not a field section one
+            let mut header = self.get_header_from_static(idx)
+                .map_err(|_| EncoderStreamError("invalid name index"))?;
             header.set_value(value);
             return Ok(Box::new(move |dynamic_table: &mut RwLockWriteGuard<DynamicTable>| -> Result<(), Box<dyn error::Error>> {
                 dynamic_table.insert_header(header)
             }));
         }
-        let entry = self.get_entry_from_dynamic(self.get_insert_count(), idx, false)?;
-        return Ok(Box::new(move |dynamic_table: &mut RwLockWriteGuard<DynamicTable>| -> Result<(), Box<dyn error::Error>> {
+        // relative to the Insert Count on commit, which counts entries inserted by preceding instructions
+        Ok(Box::new(move |dynamic_table: &mut RwLockWriteGuard<DynamicTable>| -> Result<(), Box<dyn error::Error>> {
+            let entry = dynamic_table.get_relative_entry(idx).ok_or(EncoderStreamError("invalid name index"))?;
             dynamic_table.insert_table_entry(Box::new(Entry::refer_name(*entry, value.value)))
-        }));
+        }))
     }
     pub fn insert_both_literal(&self, header: Header)
     -> Result<CommitFuncWithDynamicTable, Box<dyn error::Error>> {
@@ -107,8 +140,8 @@ impl Table {
     }
     pub fn duplicate(&self, idx: usize)
     -> Result<CommitFuncWithDynamicTable, Box<dyn error::Error>> {
-        let entry = self.get_entry_from_dynamic(self.get_insert_count(), idx, false)?;
         Ok(Box::new(move |dynamic_table: &mut RwLockWriteGuard<DynamicTable>| -> Result<(), Box<dyn error::Error>> {
+            let entry = dynamic_table.get_relative_entry(idx).ok_or(EncoderStreamError("invalid duplicate index"))?;
             dynamic_table.insert_table_entry(Box::new(Entry::duplicate(*entry)))
         }))
     }
@@ -122,42 +155,103 @@ impl Table {
         }))
     }
     // TODO: want to lock only encoder.pending_sections
-    pub fn section_ackowledgment(&self, encoder: Arc<RwLock<Encoder>>, stream_id: u16)
+    pub fn section_ackowledgment(&self, encoder: Arc<RwLock<Encoder>>, stream_id: StreamId)
     -> Result<CommitFuncWithDynamicTable, Box<dyn error::Error>> {
         Ok(Box::new(move |dynamic_table: &mut RwLockWriteGuard<DynamicTable>| -> Result<(), Box<dyn error::Error>> {
-            let (section, ref_ids) = encoder.write().unwrap().ack_section(stream_id);
+            let (section, ref_ids) = encoder.write().unwrap().ack_section(stream_id)?;
             dynamic_table.ack_section(section, ref_ids);
             Ok(())
         }))
     }
-    pub fn stream_cancellation(&self, encoder: Arc<RwLock<Encoder>>, stream_id: u16)
+    pub fn stream_cancellation(&self, encoder: Arc<RwLock<Encoder>>, stream_id: StreamId)
     -> Result<CommitFuncWithDynamicTable, Box<dyn error::Error>> {
         Ok(Box::new(move |dynamic_table: &mut RwLockWriteGuard<DynamicTable>| -> Result<(), Box<dyn error::Error>> {
-            let indices = encoder.write().unwrap().cancel_section(stream_id);
+            let indices = encoder.write().unwrap().cancel_section(stream_id)?;
             dynamic_table.cancel_section(indices);
             Ok(())
         }))
     }
 
+    pub fn get_max_capacity(&self) -> usize {
+        self.dynamic_table.read().unwrap().max_capacity
+    }
+    // bytes which can be inserted without evicting any entry
+    pub fn get_free_capacity(&self) -> usize {
+        let dynamic_table = self.dynamic_table.read().unwrap();
+        dynamic_table.capacity - dynamic_table.current_size
+    }
     pub fn get_max_entries(&self) -> u32 {
-        (self.dynamic_table.read().unwrap().max_capacity as f64 / 32 as f64).floor() as u32
+        (self.dynamic_table.read().unwrap().max_capacity as f64 / 32_f64).floor() as u32
     }
     pub fn get_insert_count(&self) -> usize {
         self.dynamic_table.read().unwrap().get_insert_count()
     }
+    pub fn get_draining_index(&self, fraction: f64) -> usize {
+        self.dynamic_table.read().unwrap().draining_index(fraction)
+    }
+    pub fn is_at_eviction_risk(&self, idx: usize) -> bool {
+        self.dynamic_table.read().unwrap().is_at_eviction_risk(idx)
+    }
+    pub fn get_known_received_count(&self) -> usize {
+        self.dynamic_table.read().unwrap().known_received_count
+    }
     pub fn get_eviction_count(&self) -> usize {
         self.dynamic_table.read().unwrap().eviction_count
     }
     pub fn get_dynamic_table_entry_len(&self) -> usize {
         self.dynamic_table.read().unwrap().get_entry_len()
     }
+    pub fn get_dynamic_table_headers(&self) -> Vec<Header> {
+        self.dynamic_table.read().unwrap().get_headers()
+    }
+    pub fn get_capacity(&self) -> usize {
+        self.dynamic_table.read().unwrap().capacity
+    }
     pub fn dump_dynamic_table(&self) {
         self.dynamic_table.read().unwrap().dump_entries();
     }
+    pub fn dump_dynamic_table_range(&self, start_abs: usize, count: usize, w: &mut dyn Write) -> io::Result<()> {
+        self.dynamic_table.read().unwrap().dump_range(start_abs, count, w)
+    }
+    pub fn find_desync(&self, other: &Table) -> Option<String> {
+        self.dynamic_table.read().unwrap().find_desync(&other.dynamic_table.read().unwrap())
+    }
+    // insertions to the snapshot never wake decoders waiting on this table
+    pub fn snapshot(&self) -> Table {
+        Self {
+            dynamic_table: Arc::new(RwLock::new(self.dynamic_table.read().unwrap().snapshot())),
+            static_table: self.static_table,
+        }
+    }
+}
+
+// immutable copy of the dynamic table at some point, to decode captured field sections against
+pub struct DynamicTableSnapshot {
+    pub(crate) table: Table,
+}
+impl DynamicTableSnapshot {
+    pub fn get_insert_count(&self) -> usize {
+        self.table.get_insert_count()
+    }
+    // entries from the oldest
+    pub fn get_headers(&self) -> Vec<Header> {
+        self.table.get_dynamic_table_headers()
+    }
+}
+
+lazy_static! {
+    // name to indices of STATIC_TABLE in ascending order, as the same names are not always adjacent
+    static ref STATIC_NAME_INDICES: HashMap<&'static str, Vec<usize>> = {
+        let mut indices: HashMap<&'static str, Vec<usize>> = HashMap::new();
+        for (idx, (name, _)) in STATIC_TABLE.iter().enumerate() {
+            indices.entry(*name).or_default().push(idx);
+        }
+        indices
+    };
 }
 
 const STATIC_TABLE_SIZE: usize = 99;
-const STATIC_TABLE: [StrHeader; STATIC_TABLE_SIZE] = [
+static STATIC_TABLE: [StrHeader; STATIC_TABLE_SIZE] = [
     (":authority", ""),
     (":path", "/"),
     ("age", "0"),
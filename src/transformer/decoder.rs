@@ -1,7 +1,8 @@
-use std::{collections::HashMap, error};
+use std::{collections::{HashMap, VecDeque}, error};
 
-use crate::types::HeaderString;
-use crate::{DecompressionFailed, Header, table::Table};
+use crate::types::{HeaderBytes, HeaderString, StreamId};
+use crate::{DecoderStreamError, DecompressReason, DecompressionFailed, Header, table::Table};
+use crate::transformer::encoder;
 use crate::transformer::huffman::HUFFMAN_TRANSFORMER;
 use crate::transformer::qnum::Qnum;
 
@@ -12,9 +13,13 @@ impl Instruction {
     pub const _INSERT_COUNT_INCREMENT: u8 = 0b00000000;
 }
 
+// (name index, value, on static table)
+type InsertReferName = (usize, HeaderString, bool);
+
 pub struct Decoder {
     pub current_blocked_streams: u16,
-    pub pending_sections: HashMap<u16, usize>,
+    // required insert counts of sections of each stream in the order decoded
+    pub pending_sections: HashMap<StreamId, VecDeque<usize>>,
 }
 
 impl Decoder {
@@ -24,180 +29,319 @@ impl Decoder {
             pending_sections: HashMap::new(),
         }
     }
-    pub fn add_section(&mut self, stream_id: u16, required_insert_count: usize) {
-        self.pending_sections.insert(stream_id, required_insert_count);
+    pub fn add_section(&mut self, stream_id: StreamId, required_insert_count: usize) {
+        self.pending_sections.entry(stream_id).or_default().push_back(required_insert_count);
     }
-    pub fn ack_section(&mut self, stream_id: u16) -> usize {
-        // TOOD: remove unwrap
-        let section = self.pending_sections.get(&stream_id).unwrap().clone();
-        self.pending_sections.remove(&stream_id);
-        section
+    // the oldest section of the stream
+    // acknowledging the stream without section would be a Decoder Stream Error on the peer (# 4.4.1)
+    pub fn ack_section(&mut self, stream_id: StreamId) -> Result<usize, Box<dyn error::Error>> {
+        let sections = self.pending_sections.get_mut(&stream_id).ok_or(DecoderStreamError)?;
+        let section = sections.pop_front().ok_or(DecoderStreamError)?;
+        if sections.is_empty() {
+            self.pending_sections.remove(&stream_id);
+        }
+        Ok(section)
     }
-    pub fn cancel_section(&mut self, stream_id: u16) {
+    pub fn cancel_section(&mut self, stream_id: StreamId) {
         self.pending_sections.remove(&stream_id);
     }
-    fn parse_string(wire: &Vec<u8>, idx: usize, n: u8) -> Result<(usize, HeaderString), Box<dyn error::Error>> {
-        let (len, value_len) = Qnum::decode(wire, idx, n);
+    fn parse_int(wire: &[u8], idx: usize, n: u8) -> Result<(usize, u64), Box<dyn error::Error>> {
+        match Qnum::try_decode(wire, idx, n)? {
+            Some(ret) => Ok(ret),
+            None => Err(DecompressionFailed::new(DecompressReason::Truncated, idx).into()),
+        }
+    }
+    fn parse_string(wire: &[u8], idx: usize, n: u8) -> Result<(usize, HeaderString), Box<dyn error::Error>> {
+        let (len, value) = Decoder::parse_bytes(wire, idx, n)?;
+        // validated after huffman decoding, as non-ASCII bytes may be split over codes
+        let value = String::from_utf8(value)
+            .map_err(|e| DecompressionFailed::with_source(DecompressReason::InvalidUtf8, idx, e.utf8_error().into()))?;
+        Ok((len, HeaderString::new(value, wire[idx] & (1 << n) > 0)))
+    }
+    // string literal as raw bytes, huffman decoded if H bit is set
+    fn parse_bytes(wire: &[u8], idx: usize, n: u8) -> Result<(usize, Vec<u8>), Box<dyn error::Error>> {
+        let (len, value_len) = Decoder::parse_int(wire, idx, n)?;
+        if (wire.len() - idx - len) < value_len as usize {
+            return Err(DecompressionFailed::new(DecompressReason::Truncated, idx).into());
+        }
         Ok((len + value_len as usize,
         if wire[idx] & (1 << n) > 0 {
-            HeaderString::new(HUFFMAN_TRANSFORMER.decode(wire, idx + len, value_len as usize)?, true)
+            HUFFMAN_TRANSFORMER.decode_bytes(wire, idx + len, value_len as usize)
+                .map_err(|_| DecompressionFailed::new(DecompressReason::InvalidHuffman, idx))?
         } else {
-            HeaderString::new(std::str::from_utf8(
-                &wire[(idx + len)..(idx + len + value_len as usize)],
-            )?.to_string(), false)
+            wire[(idx + len)..(idx + len + value_len as usize)].to_vec()
         }))
     }
-    pub fn prefix(wire: &Vec<u8>, idx: usize, table: &Table) -> Result<(usize, u32, usize), Box<dyn error::Error>> {
-        let (len1, encoded_insert_count) = Qnum::decode(wire, idx, 8);
+    // length of the string literal at idx, None if wire ends in the middle of it
+    fn string_len(wire: &[u8], idx: usize, n: u8) -> Option<usize> {
+        let len = Qnum::try_len(wire, idx, n)?;
+        let value_len = match Qnum::decode(wire, idx, n) {
+            Ok((_, value_len)) => value_len,
+            // integer only, to let decoding the instruction report the overflow
+            Err(_) => return Some(len),
+        };
+        if (wire.len() - idx - len) < value_len as usize {
+            return None;
+        }
+        Some(len + value_len as usize)
+    }
+    // # 4.5.1.1. None if encoded_insert_count cannot be sent by any valid encoder
+    pub fn decoded_insert_count(encoded_insert_count: u64, max_entries: u64, total_number_of_inserts: u64) -> Option<u64> {
+        if encoded_insert_count == 0 {
+            return Some(0);
+        }
+        // no entry could ever be inserted, fail instead of blocking forever
+        if max_entries == 0 {
+            return None;
+        }
+        let full_range = 2 * max_entries;
+        if encoded_insert_count > full_range {
+            return None;
+        }
+        // the encoder cannot refer more than max_entries beyond the insertions received so far,
+        // larger one would block forever
+        let max_value = total_number_of_inserts + max_entries;
+        let max_wrapped = (max_value / full_range) * full_range;
+        let mut requred_insert_count = max_wrapped + encoded_insert_count - 1;
+        if requred_insert_count > max_value {
+            if requred_insert_count <= full_range {
+                return None;
+            }
+            requred_insert_count -= full_range;
+        }
+        if requred_insert_count == 0 {
+            return None;
+        }
+        Some(requred_insert_count)
+    }
+    pub fn prefix(wire: &[u8], idx: usize, table: &Table) -> Result<(usize, u32, usize), Box<dyn error::Error>> {
+        let (len1, encoded_insert_count) = Decoder::parse_int(wire, idx, 8)?;
 
-        // # 4.5.1.1
+        // the dynamic table is not locked for the sections not referring it
         let required_insert_count = if encoded_insert_count == 0 {
             0
         } else {
-            let max_entries = table.get_max_entries();
-            let total_number_of_inserts = table.get_insert_count();
-            let full_range = 2 * max_entries;
-            if encoded_insert_count > full_range {
-                return Err(DecompressionFailed.into());
-            }
-            let max_value = total_number_of_inserts as u32 + max_entries;
-            let max_wrapped = ((max_value as f64 / full_range as f64).floor() as u32) * full_range;
-            let mut requred_insert_count = max_wrapped + encoded_insert_count - 1;
-            if requred_insert_count > max_value {
-                if requred_insert_count <= full_range {
-                    return Err(DecompressionFailed.into());
-                }
-                requred_insert_count -= full_range;
-            }
-            if requred_insert_count == 0 {
-                return Err(DecompressionFailed.into());
-            }
-            requred_insert_count
+            Decoder::decoded_insert_count(encoded_insert_count, table.get_max_entries() as u64, table.get_insert_count() as u64)
+                .ok_or_else(|| DecompressionFailed::new(DecompressReason::InvalidRequiredInsertCount, idx))?
         };
 
+        let (len2, delta_base) = Decoder::parse_int(wire, idx + len1, 7)?;
         let s_flag = (wire[idx + len1] & 0b10000000) == 0b10000000;
-        let (len2, delta_base) = Qnum::decode(wire, idx + len1, 7);
         let base = if s_flag {
+            if required_insert_count <= delta_base {
+                return Err(DecompressionFailed::new(DecompressReason::InvalidBase, idx + len1).into());
+            }
             required_insert_count - delta_base - 1
         } else {
-            required_insert_count + delta_base
+            required_insert_count.checked_add(delta_base)
+                .ok_or_else(|| DecompressionFailed::new(DecompressReason::InvalidBase, idx + len1))?
         };
 
-        Ok((len1 + len2, required_insert_count, base as usize))
+        Ok((len1 + len2, required_insert_count as u32, base as usize))
     }
 
     // Encode decoder instructions
-    pub fn encode_section_ackowledgment(encoded: &mut Vec<u8>, stream_id: u16) -> Result<(), Box<dyn error::Error>> {
+    pub fn encode_section_ackowledgment(encoded: &mut Vec<u8>, stream_id: StreamId) -> Result<(), Box<dyn error::Error>> {
         // TODO: double check streamID's max length
-        let len = Qnum::encode(encoded, stream_id as u32, 7);
+        let len = Qnum::encode(encoded, stream_id.into(), 7);
         let wire_len = encoded.len();
         encoded[wire_len - len] |= Instruction::SECTION_ACKNOWLEDGMENT;
         Ok(())
     }
-    pub fn encode_stream_cancellation(encoded: &mut Vec<u8>, stream_id: u16) -> Result<(), Box<dyn error::Error>> {
+    pub fn encode_stream_cancellation(encoded: &mut Vec<u8>, stream_id: StreamId) -> Result<(), Box<dyn error::Error>> {
         // TODO: double check streamID's max length
-        let len = Qnum::encode(encoded, stream_id as u32, 6);
+        let len = Qnum::encode(encoded, stream_id.into(), 6);
         let wire_len = encoded.len();
         encoded[wire_len - len] |= Instruction::STREAM_CANCELLATION;
         Ok(())
     }
     pub fn encode_insert_count_increment(encoded: &mut Vec<u8>, increment: usize) -> Result<(), Box<dyn error::Error>> {
-        let _ = Qnum::encode(encoded, increment as u32, 6);
+        let _ = Qnum::encode(encoded, increment as u64, 6);
         Ok(())
     }
 
     // Decode encoder instructions
-    pub fn decode_dynamic_table_capacity(wire: &Vec<u8>, idx: usize) -> Result<(usize, usize), Box<dyn error::Error>> {
-        let (len1, cap) = Qnum::decode(wire, idx, 5);
+    // length of the encoder instruction at idx, None if wire ends in the middle of it
+    pub fn encoder_instruction_len(wire: &[u8], idx: usize) -> Option<usize> {
+        let first = *wire.get(idx)?;
+        if first & encoder::Instruction::INSERT_REFER_NAME == encoder::Instruction::INSERT_REFER_NAME {
+            let len1 = Qnum::try_len(wire, idx, 6)?;
+            Some(len1 + Decoder::string_len(wire, idx + len1, 7)?)
+        } else if first & encoder::Instruction::INSERT_BOTH_LITERAL == encoder::Instruction::INSERT_BOTH_LITERAL {
+            let len1 = Decoder::string_len(wire, idx, 5)?;
+            Some(len1 + Decoder::string_len(wire, idx + len1, 7)?)
+        } else { // SET_DYNAMIC_TABLE_CAPACITY and DUPLICATE have 5 bit prefix integer only
+            Some(Qnum::try_len(wire, idx, 5)?)
+        }
+    }
+    pub fn decode_dynamic_table_capacity(wire: &[u8], idx: usize) -> Result<(usize, usize), Box<dyn error::Error>> {
+        let (len1, cap) = Qnum::decode(wire, idx, 5)?;
         Ok((len1, cap as usize))
     }
-    pub fn decode_insert_refer_name(wire: &Vec<u8>, idx: usize) -> Result<(usize, (usize, HeaderString, bool)), Box<dyn error::Error>> {
+    pub fn decode_insert_refer_name(wire: &[u8], idx: usize) -> Result<(usize, InsertReferName), Box<dyn error::Error>> {
         let on_static_table = wire[idx] & 0b01000000 == 0b01000000;
-        let (len1, name_idx) = Qnum::decode(wire, idx, 6);
+        let (len1, name_idx) = Qnum::decode(wire, idx, 6)?;
         let (len2, value) = Decoder::parse_string(wire, idx + len1, 7)?;
         Ok((len1 + len2, (name_idx as usize, value, on_static_table)))
     }
-    pub fn decode_insert_both_literal(wire: &Vec<u8>, idx: usize) -> Result<(usize, Header), Box<dyn error::Error>> {
+    pub fn decode_insert_both_literal(wire: &[u8], idx: usize) -> Result<(usize, Header), Box<dyn error::Error>> {
         let (len1, name) = Decoder::parse_string(wire, idx, 5)?;
         let (len2, value) = Decoder::parse_string(wire, idx + len1, 7)?;
         Ok((len1 + len2, Header::new_with_header_string(name, value, false)))
     }
-    pub fn decode_duplicate(wire: &Vec<u8>, idx: usize) -> Result<(usize, usize), Box<dyn error::Error>> {
-        let (len, index) = Qnum::decode(wire, idx, 5);
+    pub fn decode_duplicate(wire: &[u8], idx: usize) -> Result<(usize, usize), Box<dyn error::Error>> {
+        let (len, index) = Qnum::decode(wire, idx, 5)?;
         Ok((len, index as usize))
     }
 
     // Decode received headers
-    pub fn decode_indexed(wire: &Vec<u8>, idx: &mut usize, base: usize, required_insert_count: usize, table: &Table) -> Result<(Header, bool), Box<dyn error::Error>> {
+    pub fn decode_indexed<F: FieldLine>(wire: &[u8], idx: &mut usize, base: usize, required_insert_count: usize, table: &Table) -> Result<(F, bool), Box<dyn error::Error>> {
+        let offset = *idx;
         let from_static = wire[*idx] & 0b01000000 == 0b01000000;
-        let (len, table_idx) = Qnum::decode(wire, *idx, 6);
+        let (len, table_idx) = Decoder::parse_int(wire, *idx, 6)?;
         *idx += len;
 
         let table_idx = table_idx as usize;
         Ok(
             if from_static {
-                (table.get_header_from_static(table_idx)?, false)
+                (F::from_entry(table.get_header_from_static(table_idx).map_err(|e| DecompressionFailed::at(e, offset))?), false)
             } else {
-                if required_insert_count <= table_idx {
-                    return Err(DecompressionFailed.into());
+                // relative to Base, which has to point before both Base and Required Insert Count
+                if base <= table_idx || required_insert_count < base - table_idx {
+                    return Err(DecompressionFailed::new(DecompressReason::InvalidDynamicIndex, offset).into());
                 }
-                (table.get_header_from_dynamic(base, table_idx, false)?, true)
+                (F::from_entry(table.get_header_from_dynamic(base, table_idx, false).map_err(|e| DecompressionFailed::at(e, offset))?), true)
             }
         )
     }
-    pub fn decode_refer_name(wire: &Vec<u8>, idx: &mut usize, base: usize, required_insert_count: usize, table: &Table) -> Result<(Header, bool), Box<dyn error::Error>> {
-        let (len, table_idx) = Qnum::decode(wire, *idx, 4);
+    pub fn decode_refer_name<F: FieldLine>(wire: &[u8], idx: &mut usize, base: usize, required_insert_count: usize, table: &Table) -> Result<(F, bool), Box<dyn error::Error>> {
+        let offset = *idx;
+        let (len, table_idx) = Decoder::parse_int(wire, *idx, 4)?;
         let from_static = wire[*idx] & 0b00010000 == 0b00010000;
         let is_sensitive = wire[*idx] & 0b00100000 == 0b00100000;
         *idx += len;
 
         let table_idx = table_idx as usize;
-        let mut header = if from_static {
-            table.get_header_from_static(table_idx)?
+        let header = if from_static {
+            table.get_header_from_static(table_idx).map_err(|e| DecompressionFailed::at(e, offset))?
         } else {
-            if required_insert_count <= table_idx {
-                return Err(DecompressionFailed.into());
+            if base <= table_idx || required_insert_count < base - table_idx {
+                return Err(DecompressionFailed::new(DecompressReason::InvalidDynamicIndex, offset).into());
             }
-            table.get_header_from_dynamic(base, table_idx, false)?
+            table.get_header_from_dynamic(base, table_idx, false).map_err(|e| DecompressionFailed::at(e, offset))?
         };
-        let (len, value) = Decoder::parse_string(wire, *idx, 7)?;
+        let (len, value) = F::parse_literal(wire, *idx, 7)?;
         *idx += len;
-        header.set_value(value);
-        header.set_sensitive(is_sensitive);
-        Ok((header, !from_static))
+        Ok((F::from_entry(header).with_value(value, is_sensitive), !from_static))
     }
-    pub fn decode_both_literal(wire: &Vec<u8>, idx: &mut usize) -> Result<(Header, bool), Box<dyn error::Error>> {
+    pub fn decode_both_literal<F: FieldLine>(wire: &[u8], idx: &mut usize) -> Result<(F, bool), Box<dyn error::Error>> {
         let is_sensitive = wire[*idx] & 0b00010000 == 0b00010000;
-        let (len, name) = Decoder::parse_string(wire, *idx, 3)?;
+        let (len, name) = F::parse_literal(wire, *idx, 3)?;
         *idx += len;
-        let (len, value) = Decoder::parse_string(wire, *idx, 7)?;
+        let (len, value) = F::parse_literal(wire, *idx, 7)?;
         *idx += len;
 
-        Ok((Header::new_with_header_string(name, value, is_sensitive), false))
+        Ok((F::from_literals(name, value, is_sensitive), false))
     }
-    pub fn decode_indexed_post_base(wire: &Vec<u8>, idx: &mut usize, base: usize, required_insert_count: usize, table: &Table) -> Result<(Header, bool), Box<dyn error::Error>> {
-        let (len, table_idx) = Qnum::decode(wire, *idx, 4);
+    pub fn decode_indexed_post_base<F: FieldLine>(wire: &[u8], idx: &mut usize, base: usize, required_insert_count: usize, table: &Table) -> Result<(F, bool), Box<dyn error::Error>> {
+        let offset = *idx;
+        let (len, table_idx) = Decoder::parse_int(wire, *idx, 4)?;
         let table_idx = table_idx as usize;
-        if required_insert_count <= table_idx {
-            return Err(DecompressionFailed.into());
+        if required_insert_count <= base + table_idx {
+            return Err(DecompressionFailed::new(DecompressReason::InvalidDynamicIndex, offset).into());
         }
         *idx += len;
-        let header = table.get_header_from_dynamic(base, table_idx, true)?;
-        Ok((header, true))
+        let header = table.get_header_from_dynamic(base, table_idx, true).map_err(|e| DecompressionFailed::at(e, offset))?;
+        Ok((F::from_entry(header), true))
     }
-    pub fn decode_refer_name_post_base(wire: &Vec<u8>, idx: &mut usize, base: usize, required_insert_count: usize, table: &Table) -> Result<(Header, bool), Box<dyn error::Error>> {
+    pub fn decode_refer_name_post_base<F: FieldLine>(wire: &[u8], idx: &mut usize, base: usize, required_insert_count: usize, table: &Table) -> Result<(F, bool), Box<dyn error::Error>> {
+        let offset = *idx;
         let is_sensitive = wire[*idx] & 0b00001000 == 0b00001000;
-        let (len, table_idx) = Qnum::decode(wire, *idx, 3);
+        let (len, table_idx) = Decoder::parse_int(wire, *idx, 3)?;
         let table_idx = table_idx as usize;
-        if required_insert_count <= table_idx {
-            return Err(DecompressionFailed.into());
+        if required_insert_count <= base + table_idx {
+            return Err(DecompressionFailed::new(DecompressReason::InvalidDynamicIndex, offset).into());
         }
         *idx += len;
-        let mut header = table.get_header_from_dynamic(base, table_idx, true)?;
-        let (len, value) = Decoder::parse_string(wire, *idx, 7)?;
+        let header = table.get_header_from_dynamic(base, table_idx, true).map_err(|e| DecompressionFailed::at(e, offset))?;
+        let (len, value) = F::parse_literal(wire, *idx, 7)?;
         *idx += len;
-        header.set_sensitive(is_sensitive);
-        header.set_value(value);
-        Ok((header, true))
+        Ok((F::from_entry(header).with_value(value, is_sensitive), true))
+    }
+}
+
+// decoded field line. Header, or (name, value) bytes which skips UTF-8 validation
+pub trait FieldLine: Sized {
+    type Literal;
+    fn parse_literal(wire: &[u8], idx: usize, n: u8) -> Result<(usize, Self::Literal), Box<dyn error::Error>>;
+    fn from_entry(header: Header) -> Self;
+    fn from_literals(name: Self::Literal, value: Self::Literal, sensitive: bool) -> Self;
+    fn with_value(self, value: Self::Literal, sensitive: bool) -> Self;
+    // name, value and 32 bytes of overhead (# 3.2.1)
+    fn size(&self) -> usize;
+}
+impl FieldLine for Header {
+    type Literal = HeaderString;
+    fn parse_literal(wire: &[u8], idx: usize, n: u8) -> Result<(usize, HeaderString), Box<dyn error::Error>> {
+        Decoder::parse_string(wire, idx, n)
+    }
+    fn from_entry(header: Header) -> Self {
+        header
+    }
+    fn from_literals(name: HeaderString, value: HeaderString, sensitive: bool) -> Self {
+        Header::new_with_header_string(name, value, sensitive)
+    }
+    fn with_value(mut self, value: HeaderString, sensitive: bool) -> Self {
+        self.set_value(value);
+        self.set_sensitive(sensitive);
+        self
+    }
+    fn size(&self) -> usize {
+        Header::size(self)
+    }
+}
+impl FieldLine for HeaderBytes {
+    type Literal = Vec<u8>;
+    fn parse_literal(wire: &[u8], idx: usize, n: u8) -> Result<(usize, Vec<u8>), Box<dyn error::Error>> {
+        Decoder::parse_bytes(wire, idx, n)
+    }
+    fn from_entry(header: Header) -> Self {
+        (header.get_name().value.as_bytes().to_vec(), header.get_value().value.as_bytes().to_vec())
+    }
+    fn from_literals(name: Vec<u8>, value: Vec<u8>, _sensitive: bool) -> Self {
+        (name, value)
+    }
+    fn with_value(self, value: Vec<u8>, _sensitive: bool) -> Self {
+        (self.0, value)
+    }
+    fn size(&self) -> usize {
+        self.0.len() + self.1.len() + 32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Condvar, Mutex};
+    use crate::table::Table;
+    use crate::transformer::decoder::Decoder;
+    use crate::transformer::encoder::Encoder;
+
+    fn gen_table(insert_count: usize) -> Table {
+        let cv = Arc::new((Mutex::new(insert_count), Condvar::new()));
+        Table::new(1024, cv)
+    }
+
+    #[test]
+    fn prefix_delta_base_zero() {
+        let table = gen_table(2);
+        let mut encoded = vec![];
+        // Required Insert Count 2, S=0, Base 2
+        Encoder::prefix(&mut encoded, &table, 2, false, 2);
+        assert_eq!(encoded, vec![0x03, 0x00]);
+
+        let (len, required_insert_count, base) = Decoder::prefix(&encoded, 0, &table).unwrap();
+        assert_eq!(len, 2);
+        assert_eq!(required_insert_count, 2);
+        assert_eq!(base, required_insert_count as usize);
     }
 }
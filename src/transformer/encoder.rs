@@ -1,10 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::error;
 
-use crate::types::HeaderString;
-use crate::{FieldType, table::Table, Header};
+use crate::types::{HeaderString, StreamId};
+use crate::{DecoderStreamError, FieldType, table::Table, Header};
 use crate::transformer::huffman::HUFFMAN_TRANSFORMER;
 use crate::transformer::qnum::Qnum;
+use crate::transformer::decoder;
 
 pub struct Instruction;
 impl Instruction {
@@ -15,62 +16,77 @@ impl Instruction {
 }
 
 pub struct Encoder {
-    // $2.1.1.1
-    _draining_idx: u32,
+    // $2.1.1.1. oldest entries within this fraction of capacity are not referred by field lines. 0 to disable
+    pub draining_fraction: f64,
     pub known_sending_count: usize, // TODO: requred?
-    pub pending_sections: HashMap<u16, (usize, Vec<usize>)>,
+    // sections of each stream in the order sent, as acknowledged in the same order (# 4.4.1)
+    pub pending_sections: HashMap<StreamId, VecDeque<(usize, Vec<usize>)>>,
 }
 
 impl Encoder {
     pub fn new() -> Self {
         Self {
-            _draining_idx: 0,
+            draining_fraction: 0.0,
             known_sending_count: 0,
             pending_sections: HashMap::new(),
         }
     }
-    pub fn add_section(&mut self, stream_id: u16, required_insert_count: usize, dynamic_table_indices: Vec<usize>) {
-        self.pending_sections.insert(stream_id, (required_insert_count, dynamic_table_indices));
+    // entries before this absolute index are draining
+    pub fn add_section(&mut self, stream_id: StreamId, required_insert_count: usize, dynamic_table_indices: Vec<usize>) {
+        self.pending_sections.entry(stream_id).or_default().push_back((required_insert_count, dynamic_table_indices));
     }
-    pub fn ack_section(&mut self, stream_id: u16) -> (usize, Vec<usize>) {
-        // TOOD: remove unwrap
-        let section = self.pending_sections.get(&stream_id).unwrap().clone();
-        self.pending_sections.remove(&stream_id);
-        section
+    // the oldest section of the stream. the stream without section is an error of the peer (# 4.4)
+    pub fn ack_section(&mut self, stream_id: StreamId) -> Result<(usize, Vec<usize>), Box<dyn error::Error>> {
+        let sections = self.pending_sections.get_mut(&stream_id).ok_or(DecoderStreamError)?;
+        let section = sections.pop_front().ok_or(DecoderStreamError)?;
+        if sections.is_empty() {
+            self.pending_sections.remove(&stream_id);
+        }
+        Ok(section)
+    }
+    // all the sections of the stream
+    pub fn cancel_section(&mut self, stream_id: StreamId) -> Result<Vec<usize>, Box<dyn error::Error>> {
+        let sections = self.pending_sections.remove(&stream_id).ok_or(DecoderStreamError)?;
+        Ok(sections.into_iter().flat_map(|(_, indices)| indices).collect())
     }
-    pub fn cancel_section(&mut self, stream_id: u16) -> Vec<usize> {
-        let (_, indices) = self.pending_sections.get(&stream_id).unwrap().clone();
-        self.pending_sections.remove(&stream_id);
-        indices
+    // sections not acknowledged yet refer entries the decoder may not have received
+    pub fn blocking_streams(&self, known_received_count: usize) -> usize {
+        self.pending_sections.values()
+            .filter(|sections| sections.iter().any(|(required_insert_count, _)| known_received_count < *required_insert_count))
+            .count()
     }
-    pub fn has_section(&self, stream_id: u16) -> bool {
+    pub fn has_section(&self, stream_id: StreamId) -> bool {
         self.pending_sections.contains_key(&stream_id)
     }
     fn pack_string(encoded: &mut Vec<u8>, value: &HeaderString, n: u8) -> Result<usize, Box<dyn error::Error>> {
         Ok(
-            if value.huffman {
+            if value.huffman.unwrap_or_else(|| HUFFMAN_TRANSFORMER.encoded_len(&value.value) < value.value.len()) {
                 // TODO: optimize
                 let mut encoded2 = vec![];
                 HUFFMAN_TRANSFORMER.encode(&mut encoded2, &value.value)?;
-                let len = Qnum::encode(encoded, encoded2.len() as u32, n);
+                let len = Qnum::encode(encoded, encoded2.len() as u64, n);
                 let wire_len = encoded.len();
                 encoded[wire_len - len] |= 1 << n; // H bit
                 let encoded2_len = encoded2.len();
                 encoded.append(&mut encoded2);
                 len + encoded2_len
             } else {
-                let len = Qnum::encode(encoded, value.value.len() as u32, n);
+                let len = Qnum::encode(encoded, value.value.len() as u64, n);
                 encoded.append(&mut value.value.as_bytes().to_vec());
                 len + value.value.len()
             }
         )
     }
-    pub fn prefix(encoded: &mut Vec<u8>, table: &Table, required_insert_count: u32, s_flag: bool, base: u32) {
-        let encoded_insert_count = if required_insert_count == 0 {
-            required_insert_count
+    // # 4.5.1.1
+    pub fn encoded_insert_count(required_insert_count: u64, max_entries: u64) -> u64 {
+        if required_insert_count == 0 {
+            0
         } else {
-            required_insert_count % (2 * table.get_max_entries()) + 1
-        };
+            required_insert_count % (2 * max_entries) + 1
+        }
+    }
+    pub fn prefix(encoded: &mut Vec<u8>, table: &Table, required_insert_count: u32, s_flag: bool, base: u32) {
+        let encoded_insert_count = Encoder::encoded_insert_count(required_insert_count as u64, table.get_max_entries() as u64);
         Qnum::encode(encoded, encoded_insert_count, 8);
 
         // S=1: req > base if insert/reference dynamic table
@@ -83,7 +99,7 @@ impl Encoder {
         } else {
             base - required_insert_count
         };
-        let len = Qnum::encode(encoded, delta_base, 7);
+        let len = Qnum::encode(encoded, delta_base as u64, 7);
         if s_flag {
             let wire_len = encoded.len();
             encoded[wire_len - len] |= 0b10000000; // S bit
@@ -92,13 +108,31 @@ impl Encoder {
 
     // Encode encoder instructions
     pub fn encode_set_dynamic_table_capacity(encoded: &mut Vec<u8>, cap: usize) -> Result<(), Box<dyn error::Error>> {
-        let len = Qnum::encode(encoded, cap as u32, 5);
+        let len = Qnum::encode(encoded, cap as u64, 5);
         let wire_len = encoded.len();
         encoded[wire_len - len] |= Instruction::SET_DYNAMIC_TABLE_CAPACITY;
         Ok(())
     }
+    pub fn set_dynamic_table_capacity_len(cap: usize) -> usize {
+        Qnum::encoded_len(cap as u64, 5)
+    }
+    pub fn duplicate_len(idx: usize) -> usize {
+        Qnum::encoded_len(idx as u64, 5)
+    }
+    pub fn delta_base_len(delta_base: usize) -> usize {
+        Qnum::encoded_len(delta_base as u64, 7)
+    }
+    // length of the index part of a field line referring dynamic table. indexed or refer name
+    pub fn field_line_index_len(idx: usize, indexed: bool, post_base: bool) -> usize {
+        let n = match (indexed, post_base) {
+            (true, false) => 6,
+            (true, true) | (false, false) => 4,
+            (false, true) => 3,
+        };
+        Qnum::encoded_len(idx as u64, n)
+    }
     pub fn encode_insert_refer_name(encoded: &mut Vec<u8>, on_static: bool, name_idx: usize, value: &HeaderString) -> Result<(), Box<dyn error::Error>> {
-        let len = Qnum::encode(encoded, name_idx as u32, 6);
+        let len = Qnum::encode(encoded, name_idx as u64, 6);
         let wire_len = encoded.len();
         encoded[wire_len - len] |= Instruction::INSERT_REFER_NAME |
                                     (on_static as u8) << 6; // T bit
@@ -114,35 +148,44 @@ impl Encoder {
         Ok(())
     }
     pub fn encode_duplicate(encoded: &mut Vec<u8>, idx: usize) -> Result<(), Box<dyn error::Error>> {
-        let len  = Qnum::encode(encoded, idx as u32, 5);
+        let len  = Qnum::encode(encoded, idx as u64, 5);
         let wire_len = encoded.len();
         encoded[wire_len - len] |= Instruction::DUPLICATE;
         Ok(())
     }
 
     // Decode decoder instructions
-    pub fn decode_section_ackowledgment(wire: &Vec<u8>, idx: usize) -> Result<(usize, u16), Box<dyn error::Error>> {
-        let (len, stream_id) = Qnum::decode(wire, idx, 7);
-        Ok((len, stream_id as u16))
-    }
-    pub fn decode_stream_cancellation(wire: &Vec<u8>, idx: usize) -> Result<(usize, u16), Box<dyn error::Error>> {
-        let (len, stream_id) = Qnum::decode(wire, idx, 6);
-        Ok((len, stream_id as u16))
-    }
-    pub fn decode_insert_count_increment(wire: &Vec<u8>, idx: usize) -> Result<(usize, usize), Box<dyn error::Error>> {
-        let (len, increment) = Qnum::decode(wire, idx, 6);
+    // length of the decoder instruction at idx, None if wire ends in the middle of it
+    pub fn decoder_instruction_len(wire: &[u8], idx: usize) -> Option<usize> {
+        let first = *wire.get(idx)?;
+        Some(if first & decoder::Instruction::SECTION_ACKNOWLEDGMENT == decoder::Instruction::SECTION_ACKNOWLEDGMENT {
+            Qnum::try_len(wire, idx, 7)?
+        } else { // STREAM_CANCELLATION and INSERT_COUNT_INCREMENT have 6 bit prefix
+            Qnum::try_len(wire, idx, 6)?
+        })
+    }
+    pub fn decode_section_ackowledgment(wire: &[u8], idx: usize) -> Result<(usize, StreamId), Box<dyn error::Error>> {
+        let (len, stream_id) = Qnum::decode(wire, idx, 7)?;
+        Ok((len, stream_id.into()))
+    }
+    pub fn decode_stream_cancellation(wire: &[u8], idx: usize) -> Result<(usize, StreamId), Box<dyn error::Error>> {
+        let (len, stream_id) = Qnum::decode(wire, idx, 6)?;
+        Ok((len, stream_id.into()))
+    }
+    pub fn decode_insert_count_increment(wire: &[u8], idx: usize) -> Result<(usize, usize), Box<dyn error::Error>> {
+        let (len, increment) = Qnum::decode(wire, idx, 6)?;
         Ok((len, increment as usize))
     }
 
     // Encode sending headers
     pub fn encode_indexed(encoded: &mut Vec<u8>, idx: u32, from_static: bool) {
-        let len = Qnum::encode(encoded, idx, 6);
+        let len = Qnum::encode(encoded, idx as u64, 6);
         let wire_len = encoded.len();
         encoded[wire_len - len] |= FieldType::INDEXED |
                                     (from_static as u8) << 6; // T bit
     }
     pub fn encode_indexed_post_base(encoded: &mut Vec<u8>, idx: u32) {
-        let len = Qnum::encode(encoded, idx, 4);
+        let len = Qnum::encode(encoded, idx as u64, 4);
         let wire_len = encoded.len();
         encoded[wire_len - len] |= FieldType::INDEXED_POST_BASE;
     }
@@ -152,7 +195,7 @@ impl Encoder {
         header: Header,
         from_static: bool,
     ) -> Result<usize, Box<dyn error::Error>> {
-        let len = Qnum::encode(encoded, idx, 4);
+        let len = Qnum::encode(encoded, idx as u64, 4);
         let value = header.get_value();
         let wire_len = encoded.len();
         encoded[wire_len - len] |= FieldType::REFER_NAME |
@@ -162,7 +205,7 @@ impl Encoder {
     }
     pub fn encode_refer_name_post_base(encoded: &mut Vec<u8>, idx: u32, header: Header)
         -> Result<usize, Box<dyn error::Error>> {
-        let len = Qnum::encode(encoded, idx, 3);
+        let len = Qnum::encode(encoded, idx as u64, 3);
         let value = header.get_value();
         let wire_len = encoded.len();
         encoded[wire_len - len] |= FieldType::REFER_NAME_POST_BASE |
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::error;
 use std::boxed::Box;
+use crate::{DecompressReason, DecompressionFailed};
 
 lazy_static! {
 	pub static ref HUFFMAN_TRANSFORMER: HuffmanTransformer = {
@@ -14,52 +15,125 @@ pub struct Node {
 	right: Option<Box<Node>>,
 	ascii: u16,
 }
+// Decoding step for a byte from a state, which is an internal node of the code tree.
+// A byte completes 2 symbols at most, as the shortest code is 5 bits
+#[derive(Clone, Copy, Default)]
+struct Transition {
+    next: u8,
+    symbols: [u8; 2],
+    symbols_len: u8,
+    // EOS is completed
+    fail: bool,
+}
+#[derive(Clone, Copy)]
+enum Child {
+    Node(usize),
+    Symbol(u16),
+}
 pub struct HuffmanTransformer {
 	_root: Box<Node>,
-	dict: HashMap<(u32, u8), u16>
+	_dict: HashMap<(u32, u8), u16>,
+	transitions: Vec<[Transition; 256]>,
+	// states where the string may end, the root or less than 8 bits of 1 from it
+	accepting: Vec<bool>,
 }
 impl HuffmanTransformer {
 	fn build_map() -> HashMap<(u32, u8), u16> {
 		let mut dict = HashMap::<(u32, u8), u16>::new();
-		for (ascii, (code, bitlen)) in HUFFMAN_TABLE.iter().enumerate() {
+		// EOS is not a symbol of strings
+		for (ascii, (code, bitlen)) in HUFFMAN_TABLE[..EOS].iter().enumerate() {
 			dict.insert((*code, *bitlen), ascii as u16);
 		}
 		dict
 	}
-	// TODO: fix
 	fn build_tree() -> Box<Node> {
-		let root = Box::new(Node {left: None, right: None, ascii: u16::MAX});
+		let mut root = Box::new(Node {left: None, right: None, ascii: u16::MAX});
 		for (ascii, (code, bitlen)) in HUFFMAN_TABLE.iter().enumerate() {
-			let mut p = root.clone();
-			for mask in bitlen-1..=0 {
-				if code & (1 << (mask)) > 0 {
-					if p.right.is_none() {
-						p.right = Some(Box::new(Node {left: None, right: None, ascii: u16::MAX}));
-					}
-					p = p.right.unwrap();
+			let mut p = &mut root;
+			// from the most significant bit of the code
+			for mask in (0..*bitlen).rev() {
+				let child = if code & (1 << mask) > 0 {
+					&mut p.right
 				} else {
-					if p.left.is_none() {
-						p.left = Some(Box::new(Node {left: None, right: None, ascii: u16::MAX}));
-					}
-					p = p.left.unwrap();
-				}
+					&mut p.left
+				};
+				p = child.get_or_insert_with(|| Box::new(Node {left: None, right: None, ascii: u16::MAX}));
 			}
 			p.ascii = ascii as u16;
 		}
 		root
 	}
+    fn build_transitions() -> (Vec<[Transition; 256]>, Vec<bool>) {
+        // internal nodes, and the depth from the root if the path is all 1
+        let mut nodes: Vec<[Option<Child>; 2]> = vec![[None, None]];
+        let mut ones_depth: Vec<Option<u8>> = vec![Some(0)];
+        for (symbol, (code, bitlen)) in HUFFMAN_TABLE.iter().enumerate() {
+            let mut node = 0;
+            for mask in (1..*bitlen).rev() {
+                let bit = (code >> mask & 1) as usize;
+                node = match nodes[node][bit] {
+                    Some(Child::Node(child)) => child,
+                    _ => {
+                        let depth = ones_depth[node].filter(|_| bit == 1).map(|depth| depth + 1);
+                        nodes.push([None, None]);
+                        ones_depth.push(depth);
+                        nodes[node][bit] = Some(Child::Node(nodes.len() - 1));
+                        nodes.len() - 1
+                    },
+                };
+            }
+            nodes[node][(code & 1) as usize] = Some(Child::Symbol(symbol as u16));
+        }
+
+        let transitions = (0..nodes.len()).map(|state| {
+            let mut row = [Transition::default(); 256];
+            for (byte, transition) in row.iter_mut().enumerate() {
+                let mut node = state;
+                for j in (0..8).rev() {
+                    match nodes[node][byte >> j & 1] {
+                        Some(Child::Node(child)) => node = child,
+                        Some(Child::Symbol(symbol)) if symbol as usize != EOS => {
+                            transition.symbols[transition.symbols_len as usize] = symbol as u8;
+                            transition.symbols_len += 1;
+                            node = 0;
+                        },
+                        _ => {
+                            transition.fail = true;
+                            break;
+                        },
+                    }
+                }
+                transition.next = node as u8;
+            }
+            row
+        }).collect();
+        let accepting = ones_depth.iter().map(|depth| matches!(depth, Some(depth) if *depth < 8)).collect();
+        (transitions, accepting)
+    }
 	pub fn new() -> Self {
+		let (transitions, accepting) = HuffmanTransformer::build_transitions();
 		Self {
 			_root: HuffmanTransformer::build_tree(),
-			dict: HuffmanTransformer::build_map(),
+			_dict: HuffmanTransformer::build_map(),
+			transitions,
+			accepting,
 		}
 	}
 
+    // number of bytes encode would emit
+    pub fn encoded_len(&self, value: &str) -> usize {
+        let bits: usize = value.bytes().map(|ch| HUFFMAN_TABLE[ch as usize].1 as usize).sum();
+        bits.div_ceil(8)
+    }
     pub fn encode(&self, encoded: &mut Vec<u8>, value: &str) -> Result<(), Box<dyn error::Error>> {
+        self.encode_bytes(encoded, value.as_bytes())
+    }
+    // any byte is a symbol, whether or not value is valid UTF-8
+    pub fn encode_bytes(&self, encoded: &mut Vec<u8>, value: &[u8]) -> Result<(), Box<dyn error::Error>> {
         let mut tmp = 0;
         let mut rest_bits = 8;
-        for ch in value.bytes() {
-            let mut code = HUFFMAN_TABLE[ch as usize];
+        for ch in value {
+            let mut code = HUFFMAN_TABLE[*ch as usize];
             while code.1 > 0 {
                 if code.1 < rest_bits {
                     rest_bits -= code.1;
@@ -70,7 +144,7 @@ impl HuffmanTransformer {
                     tmp |= (code.0 >> shift) as u8;
                     code.1 -= rest_bits;
                     rest_bits = 0;
-                    code.0 = code.0 & ((1 << shift) - 1);
+                    code.0 &= (1 << shift) - 1;
                 }
                 if rest_bits == 0 {
                     encoded.push(tmp);
@@ -86,52 +160,70 @@ impl HuffmanTransformer {
         Ok(())
     }
 
-    // TODO: fix
-    pub fn _decode_by_tree(&self, wire: &Vec<u8>, idx: usize, str_len: usize) -> Result<String, Box<dyn error::Error>> {
-        let mut value = String::new();
-        let mut p = self._root.clone();
-		for i in 0..str_len {
-			for j in (0..8).rev() {
-				// TODO: error if right/left is None
-				if wire[idx + i] & (1 << j) > 0 {
-					p = p.right.unwrap();
-				} else {
-					p = p.left.unwrap();
-				}
-
-				if p.ascii != u16::MAX {
-					// TODO: cast should be slow. use flag when to build tree?
-					value.push((p.ascii as u8) as char);
-					p = self._root.clone();
-				}
-			}
-		}
+    // same as decode_bytes, but walks the code tree bit by bit instead of looking up the map
+    pub fn _decode_by_tree(&self, wire: &[u8], idx: usize, str_len: usize) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let mut value = vec![];
+        let mut p = &self._root;
+        for byte in &wire[idx..idx + str_len] {
+            for j in (0..8).rev() {
+                let child = if byte & (1 << j) > 0 { &p.right } else { &p.left };
+                p = match child {
+                    Some(child) => child,
+                    None => return Err(DecompressionFailed::new(DecompressReason::InvalidHuffman, idx).into()),
+                };
+                if p.ascii != u16::MAX {
+                    if p.ascii as usize == EOS {
+                        // EOS must not appear in the string
+                        return Err(DecompressionFailed::new(DecompressReason::InvalidHuffman, idx).into());
+                    }
+                    value.push(p.ascii as u8);
+                    p = &self._root;
+                }
+            }
+        }
+        Ok(value)
+    }
+    // consumes a byte at once by the precomputed transitions
+    pub fn decode_bytes(&self, wire: &[u8], idx: usize, str_len: usize) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let invalid = || DecompressionFailed::new(DecompressReason::InvalidHuffman, idx);
+        let mut value = Vec::with_capacity(str_len * 8 / 5);
+        let mut state = 0;
+        for byte in &wire[idx..idx + str_len] {
+            let transition = &self.transitions[state][*byte as usize];
+            if transition.fail {
+                return Err(invalid().into());
+            }
+            value.extend_from_slice(&transition.symbols[..transition.symbols_len as usize]);
+            state = transition.next as usize;
+        }
+        if !self.accepting[state] {
+            return Err(invalid().into());
+        }
         Ok(value)
     }
-    pub fn decode(&self, wire: &Vec<u8>, idx: usize, str_len: usize) -> Result<String, Box<dyn error::Error>> {
-        let mut value = String::new();
-        let mut tmp: u32 = 0;
+    // same as decode_bytes, but looks up the map bit by bit
+    pub fn _decode_by_map(&self, wire: &[u8], idx: usize, str_len: usize) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let invalid = || DecompressionFailed::new(DecompressReason::InvalidHuffman, idx);
+        let mut value = vec![];
+        let mut sub: u32 = 0;
         let mut bit_len: u8 = 0;
-        for i in 0..str_len {
-            let mut sub = tmp;
+        for byte in &wire[idx..idx + str_len] {
             for j in (0..8).rev() { // 7..=0
-                sub = (sub << 1) | ((wire[idx + i] >> j & 0b1) as u32);
+                sub = (sub << 1) | ((byte >> j & 0b1) as u32);
                 bit_len += 1;
-                if self.dict.contains_key(&(sub, bit_len)) {
-                    value.push((self.dict[&(sub, bit_len)] as u8) as char);
-                    tmp = 0;
-                    bit_len = 0;
+                if let Some(ascii) = self._dict.get(&(sub, bit_len)) {
+                    value.push(*ascii as u8);
                     sub = 0;
+                    bit_len = 0;
+                } else if (sub, bit_len) == HUFFMAN_TABLE[EOS] {
+                    // EOS must not appear in the string
+                    return Err(invalid().into());
                 }
             }
-			if tmp == 0 {
-				tmp = wire[idx + i] as u32 & ((1 << (bit_len)) - 1);
-			} else {
-				tmp = (tmp << 8) + wire[idx + i] as u32;
-			}
         }
-        if bit_len != 0 || tmp != 0 {
-            // TODO: parse error
+        // padding must be shorter than 8 bits, and the most significant bits of EOS (all 1)
+        if bit_len >= 8 || sub != (1 << bit_len) - 1 {
+            return Err(invalid().into());
         }
         Ok(value)
     }
@@ -140,6 +232,7 @@ impl HuffmanTransformer {
 
 type HuffmanCode = (u32, u8);
 const HUFFMAN_TABLE_SIZE: usize = 257;
+const EOS: usize = 256;
 const HUFFMAN_TABLE: [HuffmanCode; HUFFMAN_TABLE_SIZE] = [
 	(0x1ff8, 13),
 	(0x7fffd8, 23),
@@ -283,7 +376,7 @@ const HUFFMAN_TABLE: [HuffmanCode; HUFFMAN_TABLE_SIZE] = [
 	(0x7fffdc, 23),
 	(0x7fffdd, 23),
 	(0x7fffde, 23),
-	(0xffffeb, 23),
+	(0xffffeb, 24),
 	(0x7fffdf, 23),
 	(0xffffec, 24),
 	(0xffffed, 24),
@@ -309,7 +402,7 @@ const HUFFMAN_TABLE: [HuffmanCode; HUFFMAN_TABLE_SIZE] = [
 	(0x7fffe8, 23),
 	(0x7fffe9, 23),
 	(0x1fffde, 21),
-	(0x7fffde, 23),
+	(0x7fffea, 23),
 	(0x3fffdd, 22),
 	(0x3fffde, 22),
 	(0xfffff0, 24),
@@ -355,7 +448,7 @@ const HUFFMAN_TABLE: [HuffmanCode; HUFFMAN_TABLE_SIZE] = [
 	(0x7ffffe0, 27),
 	(0x7ffffe1, 27),
 	(0x3ffffe7, 26),
-	(0x3ffffe2, 27),
+	(0x7ffffe2, 27),
 	(0xfffff2, 24),
 	(0x1fffe4, 21),
 	(0x1fffe5, 21),
@@ -403,11 +496,13 @@ const HUFFMAN_TABLE: [HuffmanCode; HUFFMAN_TABLE_SIZE] = [
 
 #[cfg(test)]
 mod tests {
-	use crate::transformer::huffman::HUFFMAN_TRANSFORMER;
+	use crate::{DecompressReason, DecompressionFailed, transformer::huffman::HUFFMAN_TRANSFORMER};
 	#[test]
 	fn encode_decode() {
 		let values = vec![
 		"www.example.com",
+		"\0",
+		"nul\0in\0between\0",
 		"text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.9",
 		"KADUSERCOOKIE=DC83629A-1466-4455-ADA8-C50384E85218; KRTBCOOKIE_218=22978-YQOWxQADPCeMBAAC&KRTB&23194-YQOWxQADPCeMBAAC&KRTB&23209-YQOWxQADPCeMBAAC&KRTB&23244-YQOWxQADPCeMBAAC; PUBMDCID=1; KRTBCOOKIE_22=14911-8779088328085767086; KRTBCOOKIE_377=6810-1ac43ec6-1bef-405c-a69f-2282e44b5b8f&KRTB&22918-1ac43ec6-1bef-405c-a69f-2282e44b5b8f&KRTB&23031-1ac43ec6-1bef-405c-a69f-2282e44b5b8f; KRTBCOOKIE_148=19421-uid:CE8832BF05E64A41AF80185CCCFA9808; chkChromeAb67Sec=2; DPSync3=1628812800%3A228_236_197_201_219_221%7C1627689600%3A174%7C1628208000%3A164; SyncRTB3=1628812800%3A54_13_55_7_165_71_3_104_22_220_21_166%7C1628208000%3A223_15_2%7C1628899200%3A35; KRTBCOOKIE_57=22776-6643794442900246332; KRTBCOOKIE_188=3189-a615a81b-65de-4a1e-8789-5cf6a15bfbdd-610396c8-5553; KRTBCOOKIE_27=16735-uid:a4886103-96c5-4400-a5cf-c0fe8d774100&KRTB&16736-uid:a4886103-96c5-4400-a5cf-c0fe8d774100&KRTB&23019-uid:a4886103-96c5-4400-a5cf-c0fe8d774100&KRTB&23114-uid:a4886103-96c5-4400-a5cf-c0fe8d774100; SPugT=1627620497; KRTBCOOKIE_279=22890-36943784-f0fc-11eb-9ec2-af01490b4c1c&KRTB&23011-36943784-f0fc-11eb-9ec2-af01490b4c1c; PugT=1627625159",
 		"h3=\":443\"; ma=2592000,h3-29=\":443\"; ma=2592000,h3-T051=\":443\"; ma=2592000,h3-Q050=\":443\"; ma=2592000,h3-Q046=\":443\"; ma=2592000,h3-Q043=\":443\"; ma=2592000,quic=\":443\"; ma=2592000; v=\"46,43\"",
@@ -417,8 +512,75 @@ mod tests {
 			let mut encoded = vec![];
 			let out = HUFFMAN_TRANSFORMER.encode(&mut encoded, value);
 			assert_eq!(out.unwrap(), ());
-			let out = HUFFMAN_TRANSFORMER.decode(&encoded, 0, encoded.len());
-			assert_eq!(&out.unwrap(), value);
+			assert_eq!(HUFFMAN_TRANSFORMER.encoded_len(value), encoded.len());
+			let out = HUFFMAN_TRANSFORMER.decode_bytes(&encoded, 0, encoded.len());
+			assert_eq!(out.unwrap(), value.as_bytes());
+			let out = HUFFMAN_TRANSFORMER._decode_by_tree(&encoded, 0, encoded.len());
+			assert_eq!(out.unwrap(), HUFFMAN_TRANSFORMER.decode_bytes(&encoded, 0, encoded.len()).unwrap());
+		}
+	}
+	#[test]
+	fn non_ascii_bytes() {
+		let value = [b'a', 0x80, 0xff, b'z'];
+		let mut encoded = vec![];
+		HUFFMAN_TRANSFORMER.encode_bytes(&mut encoded, &value).unwrap();
+		assert_eq!(HUFFMAN_TRANSFORMER.decode_bytes(&encoded, 0, encoded.len()).unwrap(), value);
+	}
+	#[test]
+	fn decode_padding() {
+		// 'a' (00011) padded by 111
+		assert_eq!(HUFFMAN_TRANSFORMER.decode_bytes(&[0x1f], 0, 1).unwrap(), b"a");
+		// padded by 11 bits
+		assert!(HUFFMAN_TRANSFORMER.decode_bytes(&[0x1f, 0xff], 0, 2).is_err());
+		// padding of 110
+		assert!(HUFFMAN_TRANSFORMER.decode_bytes(&[0x1e], 0, 1).is_err());
+	}
+	#[test]
+	fn decode_eos() {
+		// 'a' (00011), EOS (30 bits of 1) and padding of 5 bits
+		let encoded = [0x1f, 0xff, 0xff, 0xff, 0xff];
+		let err = HUFFMAN_TRANSFORMER.decode_bytes(&encoded, 0, encoded.len()).unwrap_err();
+		assert_eq!(err.downcast_ref::<DecompressionFailed>().unwrap().reason, DecompressReason::InvalidHuffman);
+	}
+	#[test]
+	fn decode_by_tree_all_symbols() {
+		let value: String = (0..128u8).map(|byte| byte as char).collect();
+		let mut encoded = vec![];
+		HUFFMAN_TRANSFORMER.encode(&mut encoded, &value).unwrap();
+		let out = HUFFMAN_TRANSFORMER._decode_by_tree(&encoded, 0, encoded.len()).unwrap();
+		assert_eq!(out, value.as_bytes());
+		assert_eq!(out, HUFFMAN_TRANSFORMER.decode_bytes(&encoded, 0, encoded.len()).unwrap());
+
+		// EOS (30 bits of 1) in the string
+		assert!(HUFFMAN_TRANSFORMER._decode_by_tree(&[0xff, 0xff, 0xff, 0xff], 0, 4).is_err());
+	}
+	#[test]
+	fn decode_by_transitions() {
+		// every symbol including non-ASCII bytes, packed from the table directly
+		let mut encoded = vec![];
+		let (mut bits, mut bit_len) = (0u64, 0);
+		for (code, len) in super::HUFFMAN_TABLE[..super::EOS].iter() {
+			bits = (bits << len) | *code as u64;
+			bit_len += len;
+			while bit_len >= 8 {
+				bit_len -= 8;
+				encoded.push((bits >> bit_len) as u8);
+			}
+		}
+		if bit_len > 0 {
+			encoded.push(((bits << (8 - bit_len)) as u8) | ((1 << (8 - bit_len)) - 1));
+		}
+		let out = HUFFMAN_TRANSFORMER.decode_bytes(&encoded, 0, encoded.len()).unwrap();
+		assert_eq!(out, (0..=255u8).collect::<Vec<u8>>());
+		assert_eq!(out, HUFFMAN_TRANSFORMER._decode_by_map(&encoded, 0, encoded.len()).unwrap());
+
+		// the same result as the map for every 2 bytes input
+		for first in 0..=255u8 {
+			for second in 0..=255u8 {
+				let wire = [first, second];
+				let out = HUFFMAN_TRANSFORMER.decode_bytes(&wire, 0, 2).ok();
+				assert_eq!(out, HUFFMAN_TRANSFORMER._decode_by_map(&wire, 0, 2).ok());
+			}
 		}
 	}
 }
\ No newline at end of file
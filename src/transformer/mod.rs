@@ -1,4 +1,4 @@
 pub mod encoder;
 pub mod decoder;
-mod huffman;
+pub mod huffman;
 mod qnum;
\ No newline at end of file
@@ -1,6 +1,10 @@
+use std::error;
+
+use crate::{DecompressReason, DecompressionFailed};
+
 pub struct Qnum;
 impl Qnum {
-    pub fn encode(encoded: &mut Vec<u8>, val: u32, n: u8) -> usize {
+    pub fn encode(encoded: &mut Vec<u8>, val: u64, n: u8) -> usize {
 		let mut val = val;
         let mut len = 1;
         let mask: u8 = if n == 8 {
@@ -8,56 +12,158 @@ impl Qnum {
         } else {
             (1 << n) - 1
         };
-        if val < mask as u32 {
+        if val < mask as u64 {
             encoded.push(val as u8);
             return len;
         }
 
         encoded.push(mask);
-        val -= mask as u32;
+        val -= mask as u64;
         while val >= 128 {
             encoded.push(((val & 0b01111111) | 0b10000000) as u8);
-            val = val >> 7;
+            val >>= 7;
             len += 1;
         }
         encoded.push(val as u8);
-        return len + 1;
+        len + 1
+    }
+    // number of bytes encode would emit
+    pub fn encoded_len(val: u64, n: u8) -> usize {
+        let mask: u64 = (1 << n) - 1;
+        if val < mask {
+            return 1;
+        }
+        let mut val = val - mask;
+        let mut len = 2;
+        while val >= 128 {
+            val >>= 7;
+            len += 1;
+        }
+        len
     }
-    pub fn decode(encoded: &Vec<u8>, idx: usize, n: u8) -> (usize, u32) {
+    // Non-minimal encodings, padded by continuation bytes of 0, are accepted and all the bytes are
+    // consumed. Senders must not produce them, but rejecting gains nothing once the value is bounded.
+    // Values not fitting in u64 are rejected, as they would be used for indices and lengths.
+    // Truncated integers fail with Truncated, see try_decode to wait for the rest instead
+    pub fn decode(encoded: &[u8], idx: usize, n: u8) -> Result<(usize, u64), Box<dyn error::Error>> {
+        let overflow = || DecompressionFailed::new(DecompressReason::IntegerOverflow, idx);
+        let byte_at = |i: usize| encoded.get(i).copied()
+            .ok_or_else(|| DecompressionFailed::new(DecompressReason::Truncated, idx));
         let mask: u16 = (1 << n) - 1;
-        let mut val: u32 = (encoded[idx] & mask as u8) as u32;
+        let mut val: u64 = (byte_at(idx)? & mask as u8) as u64;
         let mut next = val as u16 == mask;
 
         let mut len = 1;
         let mut m = 0;
         while next {
-            val += ((encoded[idx + len] & 0b01111111) as u32) << m;
-            next = encoded[idx + len] & 0b10000000 == 0b10000000;
+            let byte = byte_at(idx + len)?;
+            let bits = (byte & 0b01111111) as u64;
+            if bits != 0 {
+                if m >= u64::BITS || (bits << m) >> m != bits {
+                    return Err(overflow().into());
+                }
+                val = val.checked_add(bits << m).ok_or_else(overflow)?;
+            }
+            next = byte & 0b10000000 == 0b10000000;
             m += 7;
             len += 1;
         }
-        (len, val)
+        Ok((len, val))
+    }
+    // number of bytes of the integer at idx, None if encoded ends in the middle of it
+    pub fn try_len(encoded: &[u8], idx: usize, n: u8) -> Option<usize> {
+        let mask: u16 = (1 << n) - 1;
+        let mut len = 1;
+        if (*encoded.get(idx)? & mask as u8) as u16 == mask {
+            loop {
+                let next = *encoded.get(idx + len)? & 0b10000000 == 0b10000000;
+                len += 1;
+                if !next {
+                    break;
+                }
+            }
+        }
+        Some(len)
+    }
+    // same as decode, but returns Ok(None) if encoded ends in the middle of the integer
+    pub fn try_decode(encoded: &[u8], idx: usize, n: u8) -> Result<Option<(usize, u64)>, Box<dyn error::Error>> {
+        if Qnum::try_len(encoded, idx, n).is_none() {
+            return Ok(None);
+        }
+        Qnum::decode(encoded, idx, n).map(Some)
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::transformer::qnum::Qnum;
+    use crate::{DecompressReason, DecompressionFailed, transformer::qnum::Qnum};
     #[test]
     fn encode_decode() {
-        let mut values: Vec<u32> = (0..(u16::MAX as u32 * 2)).collect();
-        values.push(u32::MAX);
-        values.push(u32::MAX-1);
+        let mut values: Vec<u64> = (0..(u16::MAX as u64 * 2)).collect();
+        values.push(u32::MAX as u64);
+        values.push(u32::MAX as u64 - 1);
+        values.push(u64::MAX);
 
         for i in values {
             for j in 1..=8 {
                 let mut encoded = vec![];
                 let len = Qnum::encode(&mut encoded, i, j);
-                let out = Qnum::decode(&encoded, 0, j);
+                let out = Qnum::decode(&encoded, 0, j).unwrap();
                 assert_eq!(i, out.1);
                 assert_eq!(len, out.0);
+                assert_eq!(len, Qnum::encoded_len(i, j));
             }
         }
     }
+    #[test]
+    fn decode_non_minimal() {
+        // 31 + 5, padded by 2 continuation bytes
+        let encoded = [0x1f, 0x85, 0x80, 0x00, 0xff];
+        assert_eq!(Qnum::decode(&encoded, 0, 5).unwrap(), (4, 36));
+        assert_eq!(Qnum::try_decode(&encoded, 0, 5).unwrap(), Some((4, 36)));
+
+        // padding beyond 64 bits of shift
+        let mut encoded = vec![0x1f, 0x81];
+        encoded.extend_from_slice(&[0x80; 20]);
+        encoded.push(0x00);
+        assert_eq!(Qnum::decode(&encoded, 0, 5).unwrap(), (encoded.len(), 32));
+        assert_eq!(Qnum::try_decode(&encoded[..encoded.len() - 1], 0, 5).unwrap(), None);
+    }
+    #[test]
+    fn try_decode_truncated() {
+        let mut encoded = vec![];
+        let len = Qnum::encode(&mut encoded, 1337, 5);
+        for i in 0..len {
+            assert_eq!(Qnum::try_decode(&encoded[..i], 0, 5).unwrap(), None);
+        }
+        assert_eq!(Qnum::try_decode(&encoded, 0, 5).unwrap(), Some((len, 1337)));
+    }
+    #[test]
+    fn decode_truncated() {
+        for encoded in [&[][..], &[0x1f], &[0x1f, 0x85]] {
+            let err = Qnum::decode(encoded, 0, 5).unwrap_err();
+            let err = err.downcast_ref::<DecompressionFailed>().unwrap();
+            assert_eq!((err.reason, err.offset), (DecompressReason::Truncated, 0));
+        }
+    }
+    #[test]
+    fn decode_overflow() {
+        // 10 continuation bytes carrying 70 bits
+        let mut encoded = vec![0x1f];
+        encoded.extend_from_slice(&[0xff; 9]);
+        encoded.push(0x7f);
+        for _ in 0..2 {
+            let err = Qnum::decode(&encoded, 0, 5).unwrap_err();
+            let err = err.downcast_ref::<DecompressionFailed>().unwrap();
+            assert_eq!((err.reason, err.offset), (DecompressReason::IntegerOverflow, 0));
+        }
+        assert!(Qnum::try_decode(&encoded, 0, 5).is_err());
+
+        // u64::MAX + 1
+        let mut encoded = vec![];
+        Qnum::encode(&mut encoded, u64::MAX, 5);
+        encoded[1] += 1;
+        assert!(Qnum::decode(&encoded, 0, 5).is_err());
+    }
 }
@@ -1,4 +1,5 @@
-use std::{error, fmt};
+use crate::transformer::huffman::HUFFMAN_TRANSFORMER;
+use std::{collections::{hash_map::DefaultHasher, HashMap, VecDeque}, error, fmt, hash::{Hash, Hasher}, sync::Arc};
 
 // StrHeader will be implemented later once all works
 // I assume &str header's would be slow due to page fault
@@ -6,14 +7,18 @@ pub type StrHeader<'a> = (&'a str, &'a str);
 #[derive(Eq, Debug, Clone)]
 pub struct HeaderString {
     pub value: String,
-    pub huffman: bool,
+    // None to huffman encode only if it is shorter than raw
+    pub huffman: Option<bool>,
 }
 impl HeaderString {
     pub fn new(value: String, huffman: bool) -> Self {
-        Self {value, huffman}
+        Self {value, huffman: Some(huffman)}
     }
     pub fn set_huffman(&mut self, flag: bool) {
-        self.huffman = flag;
+        self.huffman = Some(flag);
+    }
+    pub fn set_huffman_auto(&mut self) {
+        self.huffman = None;
     }
 }
 
@@ -70,9 +75,18 @@ impl Header {
             sensitive: false,
         }
     }
+    // size in the dynamic table, which is the same whether huffman is used or not (# 3.2.1)
     pub fn size(&self) -> usize {
         self.name.value.len() + self.value.value.len() + 32
     }
+    // bytes of name and value string literals on the wire, without their length prefixes
+    pub fn wire_size(&self, huffman: bool) -> usize {
+        if huffman {
+            HUFFMAN_TRANSFORMER.encoded_len(&self.name.value) + HUFFMAN_TRANSFORMER.encoded_len(&self.value.value)
+        } else {
+            self.name.value.len() + self.value.value.len()
+        }
+    }
     pub fn get_name(&self) -> &HeaderString {
         &self.name
     }
@@ -82,6 +96,7 @@ impl Header {
     pub fn move_value(self) -> HeaderString {
         self.value
     }
+    // huffman flag of the value is replaced by the one of given value as well
     pub fn set_value(&mut self, value: HeaderString) {
         self.value = value;
     }
@@ -89,8 +104,22 @@ impl Header {
         self.sensitive = sensitive;
     }
     pub fn set_huffman(&mut self, huffman: (bool, bool)) {
-        self.name.huffman = huffman.0;
-        self.value.huffman = huffman.1;
+        self.name.huffman = Some(huffman.0);
+        self.value.huffman = Some(huffman.1);
+    }
+    pub fn set_huffman_auto(&mut self) {
+        self.name.set_huffman_auto();
+        self.value.set_huffman_auto();
+    }
+    // Lowercases the name and trims OWS, spaces and tabs, around the value (RFC 9110 5.5).
+    // Whitespace inside the value is kept. Values compared byte by byte by the peer,
+    // e.g. covered by a signature, may not be valid anymore once trimmed
+    pub fn normalize(&mut self) {
+        self.name.value.make_ascii_lowercase();
+        let value = self.value.value.trim_matches(|ch| ch == ' ' || ch == '\t');
+        if value.len() != self.value.value.len() {
+            self.value.value = value.to_string();
+        }
     }
 }
 
@@ -106,16 +135,16 @@ impl From<StrHeader<'_>> for Header {
 
 impl From<DynamicHeader> for Header {
     fn from(header: DynamicHeader) -> Self {
-        Header::from_string(*header.0, header.1)
+        Header::from_string(header.0, header.1)
     }
 }
 
 // TODO: trait for Header and DynamicHeader
 #[derive(PartialEq, Eq, Debug, Clone)]
-pub struct DynamicHeader(pub Box<String>, pub String);
+pub struct DynamicHeader(pub String, pub String);
 impl DynamicHeader {
     pub fn from_str(name: &str, value: &str) -> Self {
-        Self(Box::new(name.to_owned()), value.to_owned())
+        Self(name.to_owned(), value.to_owned())
     }
     pub fn size(&self) -> usize {
         self.0.len() + self.1.len() + 32
@@ -124,8 +153,116 @@ impl DynamicHeader {
 
 impl From<Header> for DynamicHeader {
     fn from(header: Header) -> Self {
-        Self(Box::new(header.name.value), header.value.value)
+        Self(header.name.value, header.value.value)
+    }
+}
+
+// (name, value) as raw bytes
+pub type HeaderBytes = (Vec<u8>, Vec<u8>);
+
+// decoded header whose name is shared with the other headers of the same name
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct InternedHeader {
+    pub name: Arc<str>,
+    pub value: String,
+    pub sensitive: bool,
+}
+
+// stream id of QUIC, kept apart from table indices and counts which are usize
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamId(u64);
+impl StreamId {
+    pub const fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+impl From<u64> for StreamId {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+impl From<StreamId> for u64 {
+    fn from(id: StreamId) -> Self {
+        id.0
+    }
+}
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
-pub type CommitFunc = Box<dyn FnOnce() -> Result<(), Box<dyn error::Error>>>;
\ No newline at end of file
+pub type CommitFunc = Box<dyn FnOnce() -> Result<(), Box<dyn error::Error>>>;
+
+// cloned view of sections waiting for acknowledgment, for diagnostics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingSections {
+    // stream id -> (required insert count, referred indices of dynamic table) of each section from the oldest
+    pub encoder: HashMap<StreamId, VecDeque<(usize, Vec<usize>)>>,
+    // stream id -> required insert count of each section from the oldest
+    pub decoder: HashMap<StreamId, VecDeque<usize>>,
+}
+
+// LRU of recently seen headers. only hashes of name and value are kept
+pub(crate) struct SeenHeaders {
+    capacity: usize,
+    hashes: VecDeque<u64>,
+}
+impl SeenHeaders {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            hashes: VecDeque::with_capacity(capacity),
+        }
+    }
+    // returns true if header is in the recent ones, and marks it as the most recent
+    pub fn see(&mut self, header: &Header) -> bool {
+        let mut hasher = DefaultHasher::new();
+        header.name.value.hash(&mut hasher);
+        header.value.value.hash(&mut hasher);
+        let hash = hasher.finish();
+        if let Some(pos) = self.hashes.iter().position(|seen| *seen == hash) {
+            self.hashes.remove(pos);
+            self.hashes.push_back(hash);
+            return true;
+        }
+        if self.hashes.len() == self.capacity {
+            self.hashes.pop_front();
+        }
+        self.hashes.push_back(hash);
+        false
+    }
+}
+
+// LRU of header names to share instead of allocating again
+pub(crate) struct NamePool {
+    capacity: usize,
+    names: VecDeque<Arc<str>>,
+    // names allocated as not in the pool
+    pub allocations: usize,
+}
+impl NamePool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            names: VecDeque::with_capacity(capacity),
+            allocations: 0,
+        }
+    }
+    // returns the pooled name and marks it as the most recent, or allocates one evicting the least recent
+    pub fn intern(&mut self, name: &str) -> Arc<str> {
+        if let Some(pos) = self.names.iter().position(|pooled| **pooled == *name) {
+            let pooled = self.names.remove(pos).unwrap();
+            self.names.push_back(Arc::clone(&pooled));
+            return pooled;
+        }
+        if self.names.len() == self.capacity {
+            self.names.pop_front();
+        }
+        let pooled: Arc<str> = Arc::from(name);
+        self.names.push_back(Arc::clone(&pooled));
+        self.allocations += 1;
+        pooled
+    }
+}